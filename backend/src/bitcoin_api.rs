@@ -2,7 +2,7 @@ use candid::Principal;
 use ic_cdk::api::call::call_with_payment;
 use ic_cdk::api::management_canister::bitcoin::{
     BitcoinNetwork, GetBalanceRequest, GetUtxosRequest,
-    GetUtxosResponse, Satoshi, SendTransactionRequest,
+    GetUtxosResponse, Satoshi, SendTransactionRequest, UtxoFilter,
 };
 
 // The fees for the various bitcoin endpoints.
@@ -31,18 +31,56 @@ pub async fn get_balance(network: BitcoinNetwork, address: String) -> u64 {
     balance_res.unwrap().0
 }
 
-/// Returns the UTXOs of the given bitcoin address.
+/// Returns the UTXOs of the given bitcoin address that have at least
+/// `min_confirmations` confirmations.
 ///
 /// NOTE: Relies on the `bitcoin_get_utxos` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_get_utxos
-pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResponse {
+pub async fn get_utxos(
+    network: BitcoinNetwork,
+    address: String,
+    min_confirmations: Option<u32>,
+) -> GetUtxosResponse {
+    let response = get_utxos_page(
+        network,
+        address.clone(),
+        min_confirmations.map(UtxoFilter::MinConfirmations),
+    )
+    .await;
+
+    get_next_pages(network, address, response).await
+}
+
+/// Fetches the pages following `response` and adds their UTXOs to it.
+pub async fn get_next_pages(
+    network: BitcoinNetwork,
+    address: String,
+    mut response: GetUtxosResponse,
+) -> GetUtxosResponse {
+    while let Some(page) = response.next_page.take() {
+        let next_response =
+            get_utxos_page(network, address.clone(), Some(UtxoFilter::Page(page))).await;
+        response.utxos.extend(next_response.utxos);
+        response.next_page = next_response.next_page;
+    }
+
+    response
+}
+
+/// Returns a single page of the UTXOs of the given bitcoin address, newest
+/// first.
+pub async fn get_utxos_page(
+    network: BitcoinNetwork,
+    address: String,
+    filter: Option<UtxoFilter>,
+) -> GetUtxosResponse {
     let utxos_res: Result<(GetUtxosResponse,), _> = call_with_payment(
         Principal::management_canister(),
         "bitcoin_get_utxos",
         (GetUtxosRequest {
             address,
             network: network.into(),
-            filter: None,
+            filter,
         },),
         GET_UTXOS_COST_CYCLES,
     )
@@ -55,7 +93,7 @@ pub async fn get_utxos(network: BitcoinNetwork, address: String) -> GetUtxosResp
 ///
 /// Relies on the `bitcoin_send_transaction` endpoint.
 /// See https://internetcomputer.org/docs/current/references/ic-interface-spec/#ic-bitcoin_send_transaction
-pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
+pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) -> Result<(), String> {
     let transaction_fee = SEND_TRANSACTION_BASE_CYCLES
         + (transaction.len() as u64) * SEND_TRANSACTION_PER_BYTE_CYCLES;
 
@@ -70,5 +108,5 @@ pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) {
     )
     .await;
 
-    res.unwrap();
-}
\ No newline at end of file
+    res.map_err(|(code, msg)| format!("Failed to send transaction: {:?} {}", code, msg))
+}
@@ -1,32 +1,99 @@
-use crate::{bitcoin_api, ecdsa_api, inscription::Inscription, schnorr_api, KEY_NAME};
+use crate::{
+    bitcoin_api, broadcasts, catalog, credits, custody, ecdsa_api,
+    guard::WalletGuard,
+    inscription::Inscription,
+    inscription_id::InscriptionId,
+    inscription_ledger, jobs, metaprotocol, ord_api, rune_ledger,
+    runes::{self, Edict, Etching, Rune, RuneId, Runestone, SpacedRune, Terms},
+    sat_point::{self, SatPoint},
+    schnorr_api,
+    types::{
+        Custody, EtchingRequest, FailedReveal, InscribedSat, InscribedUtxo, InscriptionRequest,
+        Job, JobCommit, PendingPremine, PendingReveal, PreparedInscription, PreparedReveal,
+        RefundInfo, Reveal, RevealStatus, RevealTemplate, RuneBalance, RuneUtxo,
+    },
+    utxo_cache, KEY_NAME, MAX_REBROADCAST_ATTEMPTS,
+};
 use bitcoin::{
     absolute::LockTime,
     blockdata::{opcodes, script::Builder, witness::Witness},
-    consensus::serialize,
+    consensus::{deserialize, serialize},
     hashes::Hash,
     key::{PublicKey, Secp256k1},
     script::PushBytesBuf,
-    secp256k1::{schnorr, XOnlyPublicKey},
+    secp256k1::{schnorr, Scalar, XOnlyPublicKey},
     sighash::{self, SighashCache, TapSighashType},
-    taproot::{ControlBlock, LeafVersion, Signature, TaprootBuilder},
+    taproot::{ControlBlock, LeafVersion, Signature, TapNodeHash, TaprootBuilder},
     transaction::Version,
-    Address, AddressType, Amount, EcdsaSighashType, FeeRate, Network, OutPoint, Script, Sequence,
-    TapLeafHash, Transaction, TxIn, TxOut, Txid,
+    Address, AddressType, Amount, EcdsaSighashType, FeeRate, Network, OutPoint, Script, ScriptBuf,
+    Sequence, TapLeafHash, Transaction, TxIn, TxOut, Txid,
 };
 
 use hex::ToHex;
 
+use candid::Principal;
 use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Utxo};
 use ic_cdk::print;
 
 use sha2::Digest;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 const SIG_HASH_TYPE: EcdsaSighashType = EcdsaSighashType::All;
 
+/// The value of the output holding an inscription.
+pub const POSTAGE: u64 = 10_000;
+
+/// The number of blocks after which the funder of a prepared inscription can
+/// reclaim the commit output through the refund leaf.
+pub const REFUND_CSV_BLOCKS: u16 = 144;
+
+/// How long a prepared inscription waits for its commit address to be funded
+/// in nanoseconds, roughly the time it takes to mine `REFUND_CSV_BLOCKS`
+/// blocks.
+pub const PREPARED_INSCRIPTION_TTL: u64 = REFUND_CSV_BLOCKS as u64 * 10 * 60 * 1_000_000_000;
+
+/// The number of prepared inscriptions that a principal may have waiting for
+/// their reveal transaction at a time.
+pub const MAX_PREPARED_INSCRIPTIONS: usize = 10;
+
+/// The number of prepared inscriptions that may wait for their reveal
+/// transaction at a time across all principals, bounding the cycles spent on
+/// watching their commit addresses.
+pub const MAX_WAITING_PREPARED_INSCRIPTIONS: usize = 100;
+
+/// The number of commit addresses of prepared inscriptions that are checked
+/// for a payment per round of `send_funded_reveals`.
+pub const PREPARED_INSCRIPTION_CHECKS: usize = 10;
+
+/// The BIP-341 NUMS point, `H`, whose discrete logarithm is unknown.
+pub const NUMS_POINT: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+/// The maximum number of reveal transactions spending the same commit
+/// transaction, keeping the commit within the default mempool descendant limit.
+pub const MAX_FAN_OUT: usize = 24;
+
+/// The minimum relay fee rate of Bitcoin Core's default policy, which the fee
+/// of a replacement transaction must increase by.
+pub const MIN_RELAY_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+
+/// The maximum size of the script_sig spending a P2PKH output: a DER
+/// signature with sighash type of at most 73 bytes and a compressed public
+/// key, each preceded by a push opcode.
+pub const P2PKH_SCRIPT_SIG_SIZE: usize = 1 + 73 + 1 + 33;
+
 /// The size of a schnorr signature.
 pub const SCHNORR_SIGNATURE_SIZE: usize = 64;
 
+/// Returns the derivation path of the wallet holding inscriptions in custody.
+/// The wallets of callers are derived from paths with one element and the
+/// canister's wallet from before there were per-caller wallets from the empty
+/// path, so a path with two elements collides with neither and the funds of
+/// the old wallet are never spent as custody fee inputs.
+pub fn custody_derivation_path() -> Vec<Vec<u8>> {
+    vec![b"custody".to_vec(), Vec::new()]
+}
+
 /// Returns the P2PKH address of this canister at the given derivation path.
 /// We use this to generate payment addresses
 pub async fn get_p2pkh_address(
@@ -41,275 +108,2974 @@ pub async fn get_p2pkh_address(
     public_key_to_p2pkh_address(network, &public_key)
 }
 
-fn transform_network(network: BitcoinNetwork) -> Network {
-    match network {
-        BitcoinNetwork::Mainnet => Network::Bitcoin,
-        BitcoinNetwork::Testnet => Network::Testnet,
-        BitcoinNetwork::Regtest => Network::Regtest,
-    }
+pub(crate) fn transform_network(network: BitcoinNetwork) -> Network {
+    match network {
+        BitcoinNetwork::Mainnet => Network::Bitcoin,
+        BitcoinNetwork::Testnet => Network::Testnet,
+        BitcoinNetwork::Regtest => Network::Regtest,
+    }
+}
+
+/// The canister's P2PKH wallet that funds inscriptions, together with the
+/// Schnorr key that signs the reveal transactions.
+pub struct Wallet {
+    pub key_name: String,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub public_key: Vec<u8>,
+    pub address: Address,
+    pub utxos: Vec<Utxo>,
+    /// The UTXOs carrying inscriptions, which are kept out of `utxos` so that
+    /// they aren't spent as fees or postage.
+    pub inscribed_utxos: Vec<Utxo>,
+    pub schnorr_public_key: XOnlyPublicKey,
+    guard: WalletGuard,
+}
+
+impl Wallet {
+    /// Fetches the public keys, P2PKH address, and UTXOs of the wallet at
+    /// the given derivation path.
+    ///
+    /// Fails if another operation of the wallet is in progress. The wallet
+    /// stays busy until it is dropped.
+    pub async fn fetch(
+        network: BitcoinNetwork,
+        derivation_path: Vec<Vec<u8>>,
+    ) -> Result<Self, String> {
+        let guard = WalletGuard::new(&derivation_path)?;
+        let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+
+        // Fetch our public key, P2PKH address, and UTXOs.
+        let public_key =
+            ecdsa_api::ecdsa_public_key(key_name.clone(), derivation_path.clone()).await;
+        let address = public_key_to_p2pkh_address(network, &public_key);
+
+        print("Fetching UTXOs...");
+        let mut utxos = utxo_cache::get_utxos(network, address.clone()).await;
+
+        // Outputs holding runes are only spent by rune transfers.
+        utxos.retain(|utxo| !rune_ledger::contains(to_outpoint(utxo)));
+
+        // Outputs holding inscriptions are only spent to move the inscriptions.
+        let (inscribed_utxos, utxos) = utxos
+            .into_iter()
+            .partition(|utxo| inscription_ledger::contains(to_outpoint(utxo)));
+
+        // We can be sure that the address corresponds to the correct network
+        let address = Address::from_str(&address).unwrap().assume_checked();
+
+        print("Fetching Schnorr public key...");
+        let raw_public_key =
+            schnorr_api::schnorr_public_key(key_name.clone(), derivation_path.clone()).await;
+        // Convert the raw public key (sec1 encoded) to a XOnlyPublicKey (BIP 340 encoded)
+        let schnorr_public_key = PublicKey::from_slice(&raw_public_key).unwrap().into();
+
+        Ok(Self {
+            key_name,
+            derivation_path,
+            public_key,
+            address,
+            utxos,
+            inscribed_utxos,
+            schnorr_public_key,
+            guard,
+        })
+    }
+
+    /// Reserves the UTXOs spent by a transaction of the wallet.
+    fn reserve(&self, transaction: &Transaction) {
+        self.guard.reserve(transaction);
+    }
+}
+
+// Creates an ordinal inscription
+#[allow(clippy::too_many_arguments)]
+pub async fn inscribe(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    content_type: Option<Vec<u8>>,
+    body: Option<Vec<u8>>,
+    dst_address: Option<String>,
+    fee_rate: u64,
+    metaprotocol: Option<String>,
+    pointer: Option<u64>,
+    payments: Vec<(String, u64)>,
+    sat_point: Option<String>,
+    nums_tweak: Option<String>,
+) -> Result<(String, String, u64), String> {
+    let bitcoin_network = transform_network(network);
+    let mut inscription = Inscription::new(content_type, body);
+
+    let internal_key = nums_tweak
+        .map(|nums_tweak| nums_internal_key(&nums_tweak))
+        .transpose()?;
+
+    let sat_point = sat_point
+        .map(|sat_point| SatPoint::from_str(&sat_point))
+        .transpose()?;
+    if sat_point.is_some() && pointer.is_some() {
+        return Err("A pointer would move the inscription off the given sat".to_string());
+    }
+
+    if let Some(metaprotocol) = metaprotocol {
+        metaprotocol::validate(&metaprotocol, &inscription)?;
+        inscription.metaprotocol = Some(metaprotocol.into_bytes());
+    }
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let dst_address = if let Some(dst_address) = dst_address {
+        parse_address(&dst_address, bitcoin_network)?
+    } else {
+        // Send inscription to canister's own address if none is provided
+        wallet.address.clone()
+    };
+
+    // The inscription output comes first, followed by the requested payments.
+    let mut reveal_outputs = vec![TxOut {
+        script_pubkey: dst_address.script_pubkey(),
+        value: Amount::from_sat(POSTAGE),
+    }];
+    for (address, amount) in payments {
+        let address = parse_address(&address, bitcoin_network)?;
+        let output = TxOut {
+            script_pubkey: address.script_pubkey(),
+            value: Amount::from_sat(amount),
+        };
+        if output.value < output.script_pubkey.dust_value() {
+            return Err(format!("Payment to {} is below the dust limit", address));
+        }
+        reveal_outputs.push(output);
+    }
+
+    if let Some(pointer) = pointer {
+        let total_output_value: u64 = reveal_outputs.iter().map(|o| o.value.to_sat()).sum();
+        if pointer >= total_output_value {
+            return Err(format!(
+                "Pointer {} is beyond the {} sats of the reveal outputs",
+                pointer, total_output_value
+            ));
+        }
+        inscription.pointer = Some(Inscription::pointer_value(pointer));
+    }
+
+    commit_and_reveal(
+        network,
+        &wallet,
+        owner,
+        vec![inscription],
+        vec![],
+        reveal_outputs,
+        sat_point,
+        internal_key,
+        fee_rate,
+    )
+    .await
+}
+
+// Creates several ordinal inscriptions with a single commit and reveal transaction.
+//
+// Each inscription is placed on its own postage output of the reveal
+// transaction by pointing it at the first sat of that output.
+pub async fn inscribe_batch(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    requests: Vec<InscriptionRequest>,
+    fee_rate: u64,
+) -> Result<(String, String, u64), String> {
+    if requests.is_empty() {
+        return Err("At least one inscription is required".to_string());
+    }
+
+    let bitcoin_network = transform_network(network);
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let mut inscriptions = vec![];
+    let mut reveal_outputs = vec![];
+    for (index, request) in requests.into_iter().enumerate() {
+        let (mut inscription, reveal_output) =
+            prepare_request(request, bitcoin_network, Some(&wallet.address))?;
+
+        // The first inscription lands on the first sat of the reveal outputs by default.
+        if index > 0 {
+            inscription.pointer = Some(Inscription::pointer_value(index as u64 * POSTAGE));
+        }
+
+        inscriptions.push(inscription);
+        reveal_outputs.push(reveal_output);
+    }
+
+    commit_and_reveal(
+        network,
+        &wallet,
+        owner,
+        inscriptions,
+        vec![],
+        reveal_outputs,
+        None,
+        None,
+        fee_rate,
+    )
+    .await
+}
+
+// Creates ordinal inscriptions that each get their own reveal transaction,
+// spending one of the outputs of a single commit transaction.
+//
+// The reveal transactions are sent independently of each other and their
+// status is tracked in a job, so a reveal that fails to be sent does not
+// prevent the others from being sent. It is kept and sent again later.
+pub async fn inscribe_fan_out(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    requests: Vec<InscriptionRequest>,
+    fee_rate: u64,
+) -> Result<u64, String> {
+    if requests.is_empty() {
+        return Err("At least one inscription is required".to_string());
+    }
+    if requests.len() > MAX_FAN_OUT {
+        return Err(format!(
+            "At most {} inscriptions can be revealed from a single commit",
+            MAX_FAN_OUT
+        ));
+    }
+
+    let bitcoin_network = transform_network(network);
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let reveals = requests
+        .into_iter()
+        .map(|request| {
+            let (inscription, reveal_output) =
+                prepare_request(request, bitcoin_network, Some(&wallet.address))?;
+            Ok((inscription, vec![reveal_output]))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let inscriptions: Vec<Inscription> = reveals
+        .iter()
+        .map(|(inscription, _)| inscription.clone())
+        .collect();
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let (commit_tx, reveal_txs, reveal_templates) =
+        build_fan_out_transactions(bitcoin_network, &wallet, reveals, fee_rate).await?;
+
+    print("Sending commit transaction...");
+    send_wallet_transaction(network, &wallet, owner, &commit_tx, &[]).await?;
+    print("Done");
+    credits::exclude(&commit_tx, &wallet.address);
+
+    let job_id = jobs::insert(Job {
+        commit_txid: commit_tx.txid().encode_hex(),
+        reveals: reveal_txs
+            .iter()
+            .map(|reveal_tx| Reveal {
+                txid: reveal_tx.txid().encode_hex(),
+                status: RevealStatus::Pending,
+            })
+            .collect(),
+        child_txid: None,
+    });
+    jobs::insert_commit(
+        job_id,
+        JobCommit {
+            derivation_path: wallet.derivation_path.clone(),
+            commit_tx: serialize(&commit_tx),
+            input_values: input_values(&wallet, &commit_tx),
+            reveals: reveal_templates,
+            child_tx: None,
+        },
+    );
+
+    // The reveal transactions that fail to be sent are sent again later, so
+    // their outputs are recorded right away.
+    for (index, reveal_tx) in reveal_txs.iter().enumerate() {
+        catalog::record(reveal_tx.txid(), &inscriptions[index..=index]);
+        metaprotocol::record(&inscriptions[index]);
+        credits::exclude(reveal_tx, &wallet.address);
+        record_inscriptions(
+            &wallet.address,
+            reveal_tx,
+            &[commit_tx.output[index].value.to_sat()],
+            Some((0, &inscriptions[index..=index])),
+        );
+    }
+
+    for (index, reveal_tx) in reveal_txs.iter().enumerate() {
+        print(format!("Sending reveal transaction {}...", index));
+        let status = match broadcasts::send_transaction(network, serialize(reveal_tx)).await {
+            Ok(()) => RevealStatus::Sent,
+            Err(err) => {
+                jobs::insert_failed_reveal(
+                    job_id,
+                    index,
+                    FailedReveal {
+                        reveal_tx: serialize(reveal_tx),
+                        attempts: 0,
+                    },
+                );
+                RevealStatus::Failed(err)
+            }
+        };
+        jobs::set_reveal_status(job_id, index, status);
+    }
+
+    Ok(job_id)
+}
+
+// Inscribes onto the sat that already carries the given inscription.
+//
+// The reveal transaction spends the inscribed UTXO as its first input, ahead
+// of the commit output, so the inscribed sat keeps its offset and the new
+// inscription points at it. The inscribed UTXO must be held by the canister's
+// wallet; see `take_inscribed_utxo` for how it is found.
+#[allow(clippy::too_many_arguments)]
+pub async fn reinscribe(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    ord_url: Option<String>,
+    inscription_id: String,
+    content_type: Option<Vec<u8>>,
+    body: Option<Vec<u8>>,
+    dst_address: Option<String>,
+    fee_rate: u64,
+) -> Result<(String, String, u64), String> {
+    let bitcoin_network = transform_network(network);
+    let inscription_id = InscriptionId::from_str(&inscription_id)?;
+
+    let mut wallet = Wallet::fetch(network, derivation_path).await?;
+
+    // The inscribed UTXO must not be spent to fund the commit transaction.
+    let (inscribed_utxo, offset) =
+        take_inscribed_utxo(&mut wallet, inscription_id, ord_url.as_deref()).await?;
+
+    let mut inscription = Inscription::new(content_type, body);
+    inscription.pointer = Some(Inscription::pointer_value(offset));
+
+    let dst_address = if let Some(dst_address) = dst_address {
+        parse_address(&dst_address, bitcoin_network)?
+    } else {
+        wallet.address.clone()
+    };
+
+    let inscribed_input = (
+        to_outpoint(&inscribed_utxo),
+        TxOut {
+            script_pubkey: wallet.address.script_pubkey(),
+            value: Amount::from_sat(inscribed_utxo.value),
+        },
+    );
+
+    // The reveal output carries the inscribed sats plus the postage of the
+    // commit output.
+    let reveal_output = TxOut {
+        script_pubkey: dst_address.script_pubkey(),
+        value: Amount::from_sat(inscribed_utxo.value + POSTAGE),
+    };
+
+    commit_and_reveal(
+        network,
+        &wallet,
+        owner,
+        vec![inscription],
+        vec![inscribed_input],
+        vec![reveal_output],
+        None,
+        None,
+        fee_rate,
+    )
+    .await
+}
+
+// Etches a rune and returns the ID of the job tracking the etching.
+//
+// The reveal transaction commits to the rune name in its tapscript, which
+// only counts once the commit output has `runes::COMMIT_CONFIRMATIONS`
+// confirmations. The commit transaction is therefore sent right away while
+// the signed reveal transaction is kept until `send_pending_reveals` finds
+// the commit output confirmed. The premine goes to the postage output and, if
+// that pays the wallet, is recorded by `record_premines`.
+pub async fn etch_rune(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    request: EtchingRequest,
+    dst_address: Option<String>,
+    fee_rate: u64,
+) -> Result<u64, String> {
+    let bitcoin_network = transform_network(network);
+
+    let spaced_rune = SpacedRune::from_str(&request.rune)?;
+    let premine = request.premine.unwrap_or_default();
+
+    let symbol = match request.symbol {
+        Some(symbol) => {
+            let mut chars = symbol.chars();
+            match (chars.next(), chars.next()) {
+                (Some(symbol), None) => Some(symbol),
+                _ => return Err("Symbol must be a single character".to_string()),
+            }
+        }
+        None => None,
+    };
+
+    let etching = Etching {
+        divisibility: request.divisibility,
+        premine: request.premine,
+        rune: Some(spaced_rune.rune),
+        spacers: Some(spaced_rune.spacers).filter(|spacers| *spacers != 0),
+        symbol,
+        terms: request.terms.map(|terms| Terms {
+            amount: terms.amount,
+            cap: terms.cap,
+            height: (terms.height_start, terms.height_end),
+            offset: (terms.offset_start, terms.offset_end),
+        }),
+        turbo: request.turbo.unwrap_or_default(),
+    };
+    etching.validate()?;
+
+    let runestone = Runestone {
+        etching: Some(etching),
+        ..Default::default()
+    }
+    .encipher();
+    if runestone.len() > runes::MAX_RUNESTONE_SIZE {
+        return Err(format!(
+            "Runestone of {} bytes exceeds the maximum of {} bytes",
+            runestone.len(),
+            runes::MAX_RUNESTONE_SIZE
+        ));
+    }
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    // The reveal transaction confirms later than the current block, by which
+    // time the minimum has only decreased.
+    let minimum = Rune::minimum_at_height(bitcoin_network, utxo_cache::tip_height());
+    if spaced_rune.rune < minimum {
+        return Err(format!(
+            "Rune {} is too short, the shortest rune that can be etched is {}",
+            spaced_rune, minimum
+        ));
+    }
+
+    let inscriptions = [Inscription {
+        rune: Some(spaced_rune.rune.commitment()),
+        ..Default::default()
+    }];
+
+    let dst_address = if let Some(dst_address) = dst_address {
+        parse_address(&dst_address, bitcoin_network)?
+    } else {
+        wallet.address.clone()
+    };
+
+    let reveal_outputs = vec![
+        TxOut {
+            script_pubkey: dst_address.script_pubkey(),
+            value: Amount::from_sat(POSTAGE),
+        },
+        TxOut {
+            script_pubkey: runestone,
+            value: Amount::ZERO,
+        },
+    ];
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let reveal_plan = RevealPlan::new(
+        bitcoin_network,
+        wallet.schnorr_public_key,
+        None,
+        &inscriptions,
+        vec![],
+        reveal_outputs,
+        fee_rate,
+        None,
+    );
+    let commit_address = reveal_plan.commit_address.to_string();
+    let reveal_template = reveal_plan.template();
+
+    let commit_tx =
+        build_commit_transaction(&wallet, vec![reveal_plan.commit_output()], None, fee_rate)
+            .await?;
+    let reveal_tx = reveal_plan.sign(&wallet, &commit_tx, 0).await;
+
+    print("Sending commit transaction...");
+    send_wallet_transaction(network, &wallet, owner, &commit_tx, &[]).await?;
+    print("Done");
+    credits::exclude(&commit_tx, &wallet.address);
+    credits::exclude(&reveal_tx, &wallet.address);
+    // The reveal transaction is sent later, but its outputs are recorded
+    // right away so that they are spent by nothing else once it is.
+    record_inscriptions(
+        &wallet.address,
+        &reveal_tx,
+        &[commit_tx.output[0].value.to_sat()],
+        Some((0, &inscriptions)),
+    );
+
+    let job_id = jobs::insert(Job {
+        commit_txid: commit_tx.txid().encode_hex(),
+        reveals: vec![Reveal {
+            txid: reveal_tx.txid().encode_hex(),
+            status: RevealStatus::Pending,
+        }],
+        child_txid: None,
+    });
+    jobs::insert_commit(
+        job_id,
+        JobCommit {
+            derivation_path: wallet.derivation_path.clone(),
+            commit_tx: serialize(&commit_tx),
+            input_values: input_values(&wallet, &commit_tx),
+            reveals: vec![reveal_template],
+            child_tx: None,
+        },
+    );
+
+    jobs::insert_pending_reveal(
+        job_id,
+        PendingReveal {
+            commit_address,
+            min_confirmations: runes::COMMIT_CONFIRMATIONS,
+            reveal_tx: serialize(&reveal_tx),
+        },
+    );
+
+    if premine > 0 && dst_address == wallet.address {
+        rune_ledger::insert_pending_premine(
+            job_id,
+            PendingPremine {
+                rune: spaced_rune.rune.to_string(),
+                address: wallet.address.to_string(),
+                amount: premine,
+            },
+        );
+    }
+
+    Ok(job_id)
+}
+
+// Records the premines etched to the wallets in the rune ledger once the ord
+// indexer at `ord_url` knows their runes, which it only does once the reveal
+// transactions have confirmed.
+pub async fn record_premines(ord_url: String) {
+    for (job_id, pending_premine) in rune_ledger::pending_premines() {
+        let job = jobs::get(job_id).expect("job should exist");
+        match job.reveals[0].status {
+            RevealStatus::Sent => {}
+            RevealStatus::Cancelled(_) => {
+                rune_ledger::remove_pending_premine(job_id);
+                continue;
+            }
+            RevealStatus::Pending | RevealStatus::Failed(_) => continue,
+        }
+
+        let Ok(rune) = ord_api::get_rune(&ord_url, &pending_premine.rune).await else {
+            continue;
+        };
+
+        // The rune may have been etched by another transaction that confirmed
+        // first, in which case the etching of the job is invalid.
+        let reveal_txid = job_txid(&job.reveals[0].txid);
+        if rune.etching == reveal_txid {
+            rune_ledger::insert(
+                OutPoint {
+                    txid: reveal_txid,
+                    vout: 0,
+                },
+                RuneUtxo {
+                    address: pending_premine.address,
+                    value: POSTAGE,
+                    balances: vec![RuneBalance {
+                        rune_id: rune.id.to_string(),
+                        amount: pending_premine.amount,
+                    }],
+                },
+            );
+        }
+        rune_ledger::remove_pending_premine(job_id);
+    }
+}
+
+// Sends the pending reveal transactions whose commit outputs have enough
+// confirmations.
+pub async fn send_pending_reveals(network: BitcoinNetwork) {
+    for (job_id, pending_reveal) in jobs::pending_reveals() {
+        let confirmed_utxos = bitcoin_api::get_utxos(
+            network,
+            pending_reveal.commit_address,
+            Some(pending_reveal.min_confirmations),
+        )
+        .await
+        .utxos;
+
+        if confirmed_utxos.is_empty() {
+            continue;
+        }
+
+        print(format!("Sending reveal transaction of job {}...", job_id));
+        match broadcasts::send_transaction(network, pending_reveal.reveal_tx).await {
+            Ok(()) => {
+                jobs::set_reveal_status(job_id, 0, RevealStatus::Sent);
+                jobs::remove_pending_reveal(job_id);
+            }
+            // The reveal transaction stays pending, so that it is sent again.
+            Err(err) => jobs::set_reveal_status(job_id, 0, RevealStatus::Failed(err)),
+        }
+    }
+}
+
+// Sends the reveal transactions that failed to be sent once more, each until
+// it has been retried `MAX_REBROADCAST_ATTEMPTS` times.
+pub async fn retry_failed_reveals(network: BitcoinNetwork) {
+    let max_attempts = MAX_REBROADCAST_ATTEMPTS.with(|m| m.get());
+    for ((job_id, index), mut failed_reveal) in jobs::failed_reveals() {
+        if failed_reveal.attempts >= max_attempts {
+            continue;
+        }
+        let index = index as usize;
+
+        // Count the attempt before sending, so that concurrent calls can't
+        // exceed the limit.
+        failed_reveal.attempts += 1;
+        jobs::insert_failed_reveal(job_id, index, failed_reveal.clone());
+
+        print(format!(
+            "Sending reveal transaction {} of job {} again...",
+            index, job_id
+        ));
+        let result = broadcasts::send_transaction(network, failed_reveal.reveal_tx.clone()).await;
+
+        // The reveal transaction may have been replaced or cancelled in the
+        // meantime.
+        if jobs::get_failed_reveal(job_id, index)
+            .is_none_or(|current| current.reveal_tx != failed_reveal.reveal_tx)
+        {
+            continue;
+        }
+        match result {
+            Ok(()) => {
+                jobs::remove_failed_reveal(job_id, index);
+                jobs::set_reveal_status(job_id, index, RevealStatus::Sent);
+            }
+            Err(err) => jobs::set_reveal_status(job_id, index, RevealStatus::Failed(err)),
+        }
+    }
+}
+
+// Replaces the commit transaction of a job with one paying `fee_rate` and
+// returns the ID of the new commit transaction.
+//
+// The commit transaction must not have confirmed. The replacement spends the
+// same inputs and takes the additional fee from the change output. BIP-125
+// requires it to pay at least the fees of the transactions it evicts, i.e.
+// the original, the reveal transactions sent along with it and the child
+// accelerating it (rule 3), plus the minimum relay fee for its own size
+// (rule 4). The reveal transactions are signed again against the new commit
+// transaction and sent, unless they are still waiting for the commit output to
+// confirm, in which case the waiting reveal transaction is replaced.
+pub async fn bump_commit_fee(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    job_id: u64,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let bitcoin_network = transform_network(network);
+    let job_commit = jobs::get_commit(job_id)
+        .filter(|job_commit| job_commit.derivation_path == derivation_path)
+        .ok_or_else(|| format!("Job {} not found", job_id))?;
+    let mut job = jobs::get(job_id).expect("job should exist");
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let mut commit_tx: Transaction =
+        deserialize(&job_commit.commit_tx).expect("commit transaction should be valid");
+    check_unconfirmed(job_id, &commit_tx)?;
+    let old_fee = commit_fee(&job_commit, &commit_tx);
+    let descendant_fee = descendant_fee(&job, &job_commit, &commit_tx);
+    // The child accelerating the commit transaction is evicted, so its fee is
+    // refunded.
+    let evicted_child_fee = job_commit
+        .child_tx
+        .as_ref()
+        .map_or(Amount::ZERO, |child_tx| child_fee(&commit_tx, child_tx));
+    if let Some(spender) = change_spender(&job_commit, &commit_tx) {
+        return Err(format!(
+            "The change of the commit transaction funds transaction {}, which the replacement would evict",
+            spender
+        ));
+    }
+
+    for input in commit_tx.input.iter_mut() {
+        input.script_sig = ScriptBuf::new();
+    }
+    let new_fee = estimate_p2pkh_fee(&commit_tx, FeeRate::from_sat_per_vb(fee_rate).unwrap());
+    let min_fee = old_fee + descendant_fee + estimate_p2pkh_fee(&commit_tx, MIN_RELAY_FEE_RATE);
+    if new_fee < min_fee {
+        return Err(format!(
+            "A fee rate of {} sat/vB pays {}, but the replacement must pay at least {}",
+            fee_rate, new_fee, min_fee
+        ));
+    }
+
+    let change_output = commit_tx
+        .output
+        .last_mut()
+        .filter(|output| output.script_pubkey == wallet.address.script_pubkey())
+        .ok_or("The commit transaction has no change output to pay the higher fee")?;
+    let fee_increase = new_fee - old_fee;
+    if change_output.value < fee_increase + change_output.script_pubkey.dust_value() {
+        return Err(format!(
+            "The change of {} can't pay the additional fee of {}",
+            change_output.value, fee_increase
+        ));
+    }
+    change_output.value -= fee_increase;
+
+    let commit_tx = sign_transaction_p2pkh(
+        &wallet.public_key,
+        &wallet.address,
+        commit_tx,
+        wallet.key_name.clone(),
+        wallet.derivation_path.clone(),
+        ecdsa_api::sign_with_ecdsa,
+    )
+    .await;
+
+    let mut reveal_txs = vec![];
+    for (index, reveal_template) in job_commit.reveals.iter().enumerate() {
+        let vout = commit_vout(&job_commit, index);
+        let commit_address =
+            Address::from_script(&commit_tx.output[vout].script_pubkey, bitcoin_network).unwrap();
+        let reveal_plan =
+            RevealPlan::from_template(reveal_template.clone(), commit_address, &wallet.address);
+        reveal_txs.push(reveal_plan.sign(&wallet, &commit_tx, vout).await);
+    }
+
+    print("Sending replacement commit transaction...");
+    send_paid(
+        network,
+        owner,
+        &commit_tx,
+        fee_increase.to_sat(),
+        evicted_child_fee.to_sat(),
+    )
+    .await?;
+    print("Done");
+    credits::exclude(&commit_tx, &wallet.address);
+
+    job.commit_txid = commit_tx.txid().encode_hex();
+    job.child_txid = None;
+    for (reveal, reveal_tx) in job.reveals.iter().zip(&reveal_txs) {
+        inscription_ledger::replace_transaction(job_txid(&reveal.txid), reveal_tx.txid());
+        catalog::replace_transaction(job_txid(&reveal.txid), reveal_tx.txid());
+    }
+    if let Some(mut pending_reveal) = jobs::get_pending_reveal(job_id) {
+        credits::exclude(&reveal_txs[0], &wallet.address);
+        pending_reveal.reveal_tx = serialize(&reveal_txs[0]);
+        jobs::insert_pending_reveal(job_id, pending_reveal);
+        job.reveals[0] = Reveal {
+            txid: reveal_txs[0].txid().encode_hex(),
+            status: RevealStatus::Pending,
+        };
+    } else {
+        // The failed reveal transactions spend the replaced commit
+        // transaction, so only their replacements are sent again.
+        jobs::remove_failed_reveals(job_id);
+        for (index, reveal_tx) in reveal_txs.iter().enumerate() {
+            credits::exclude(reveal_tx, &wallet.address);
+            print(format!("Sending reveal transaction {}...", index));
+            let status = match broadcasts::send_transaction(network, serialize(reveal_tx)).await {
+                Ok(()) => RevealStatus::Sent,
+                Err(err) => {
+                    jobs::insert_failed_reveal(
+                        job_id,
+                        index,
+                        FailedReveal {
+                            reveal_tx: serialize(reveal_tx),
+                            attempts: 0,
+                        },
+                    );
+                    RevealStatus::Failed(err)
+                }
+            };
+            job.reveals[index] = Reveal {
+                txid: reveal_tx.txid().encode_hex(),
+                status,
+            };
+        }
+    }
+    jobs::update(job_id, job);
+
+    jobs::insert_commit(
+        job_id,
+        JobCommit {
+            commit_tx: serialize(&commit_tx),
+            child_tx: None,
+            ..job_commit
+        },
+    );
+
+    Ok(commit_tx.txid().encode_hex())
+}
+
+// Accelerates the commit transaction of a job by spending its change output
+// back to the wallet with a fee that brings both transactions together to
+// `fee_rate`, and returns the ID of the child transaction.
+//
+// The commit transaction must not have confirmed. A child sent earlier is
+// replaced, which BIP-125 only allows if the new child pays at least its fee
+// plus the minimum relay fee for its own size. Other transactions spending
+// the change output are not replaced.
+pub async fn accelerate(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    job_id: u64,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let mut job_commit = jobs::get_commit(job_id)
+        .filter(|job_commit| job_commit.derivation_path == derivation_path)
+        .ok_or_else(|| format!("Job {} not found", job_id))?;
+    let mut job = jobs::get(job_id).expect("job should exist");
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let commit_tx: Transaction =
+        deserialize(&job_commit.commit_tx).expect("commit transaction should be valid");
+    check_unconfirmed(job_id, &commit_tx)?;
+    let commit_fee = commit_fee(&job_commit, &commit_tx);
+
+    let change_vout = commit_tx.output.len() - 1;
+    let change_output = &commit_tx.output[change_vout];
+    if change_output.script_pubkey != wallet.address.script_pubkey() {
+        return Err("The commit transaction has no change output to spend".to_string());
+    }
+    if let Some(spender) = change_spender(&job_commit, &commit_tx) {
+        return Err(format!(
+            "The change of the commit transaction funds transaction {}, which the child would replace",
+            spender
+        ));
+    }
+
+    let mut child_tx = Transaction {
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: commit_tx.txid(),
+                vout: change_vout as u32,
+            },
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+            script_sig: Script::new().into(),
+        }],
+        output: vec![TxOut {
+            script_pubkey: wallet.address.script_pubkey(),
+            value: Amount::ZERO,
+        }],
+        lock_time: LockTime::ZERO,
+        version: Version(2),
+    };
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let package_fee = fee_rate.fee_vb(commit_tx.vsize() as u64).unwrap()
+        + estimate_p2pkh_fee(&child_tx, fee_rate);
+    let replaced_child_fee = job_commit
+        .child_tx
+        .as_ref()
+        .map_or(Amount::ZERO, |old_child_tx| {
+            child_fee(&commit_tx, old_child_tx)
+        });
+    let min_child_fee = estimate_p2pkh_fee(&child_tx, MIN_RELAY_FEE_RATE) + replaced_child_fee;
+    if package_fee < commit_fee + min_child_fee {
+        return Err(format!(
+            "A fee rate of {} sat/vB is too low, the child would have to pay at least {}",
+            fee_rate.to_sat_per_vb_ceil(),
+            min_child_fee
+        ));
+    }
+
+    let child_fee = package_fee - commit_fee;
+    if change_output.value < child_fee + change_output.script_pubkey.dust_value() {
+        return Err(format!(
+            "The change of {} can't pay the child's fee of {}",
+            change_output.value, child_fee
+        ));
+    }
+    child_tx.output[0].value = change_output.value - child_fee;
+
+    let child_tx = sign_transaction_p2pkh(
+        &wallet.public_key,
+        &wallet.address,
+        child_tx,
+        wallet.key_name.clone(),
+        wallet.derivation_path.clone(),
+        ecdsa_api::sign_with_ecdsa,
+    )
+    .await;
+
+    // The owner pays the fee of the child, and gets the fee of the child it
+    // replaces back.
+    print("Sending child transaction...");
+    send_paid(
+        network,
+        owner,
+        &child_tx,
+        child_fee.to_sat(),
+        replaced_child_fee.to_sat(),
+    )
+    .await?;
+    print("Done");
+    credits::exclude(&child_tx, &wallet.address);
+
+    job.child_txid = Some(child_tx.txid().encode_hex());
+    jobs::update(job_id, job);
+    job_commit.child_tx = Some(serialize(&child_tx));
+    jobs::insert_commit(job_id, job_commit);
+
+    Ok(child_tx.txid().encode_hex())
+}
+
+// Cancels a job whose inscriptions haven't been revealed and returns the ID of
+// the transaction sending the funds back to the wallet.
+//
+// If the commit transaction hasn't confirmed, it is replaced by a transaction
+// spending its inputs back to the wallet. Otherwise the commit outputs that
+// haven't been spent by a confirmed reveal transaction are spent through the
+// key path back to the wallet, replacing the reveal transactions still in the
+// mempool. Either way the fee is at least `fee_rate` and covers the fees of
+// the evicted transactions plus the minimum relay fee, as BIP-125 requires.
+pub async fn cancel_inscription(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    job_id: u64,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let bitcoin_network = transform_network(network);
+    let job_commit = jobs::get_commit(job_id)
+        .filter(|job_commit| job_commit.derivation_path == derivation_path)
+        .ok_or_else(|| format!("Job {} not found", job_id))?;
+    let mut job = jobs::get(job_id).expect("job should exist");
+
+    // The reveal transaction of a reinscription also spends the inscribed
+    // output, whose inscriptions the ledger would lose track of.
+    if job_commit
+        .reveals
+        .iter()
+        .any(|reveal_template| reveal_template.inputs.is_some())
+    {
+        return Err(format!(
+            "Job {} reinscribes an inscription and can't be cancelled",
+            job_id
+        ));
+    }
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let commit_tx: Transaction =
+        deserialize(&job_commit.commit_tx).expect("commit transaction should be valid");
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+
+    // The bitcoin canister only returns the outputs of confirmed transactions
+    // that haven't been spent by a confirmed transaction.
+    let mut unspent_reveals = vec![];
+    for index in 0..job_commit.reveals.len() {
+        let outpoint = OutPoint {
+            txid: commit_tx.txid(),
+            vout: commit_vout(&job_commit, index) as u32,
+        };
+        let commit_address = Address::from_script(
+            &commit_tx.output[outpoint.vout as usize].script_pubkey,
+            bitcoin_network,
+        )
+        .unwrap();
+        let utxos = bitcoin_api::get_utxos(network, commit_address.to_string(), None)
+            .await
+            .utxos;
+        if utxos.iter().any(|utxo| to_outpoint(utxo) == outpoint) {
+            unspent_reveals.push(index);
+        }
+    }
+
+    let mut cancel_tx = Transaction {
+        input: vec![],
+        output: vec![TxOut {
+            script_pubkey: wallet.address.script_pubkey(),
+            value: Amount::ZERO,
+        }],
+        lock_time: LockTime::ZERO,
+        version: Version(2),
+    };
+
+    let (cancelled_reveals, evicted_value, evicted_child_fee) = if unspent_reveals.is_empty() {
+        // The commit outputs are also missing from the confirmed UTXOs once
+        // the commit transaction and all of the reveals have confirmed, so
+        // the commit transaction is only replaced if it is known not to have
+        // confirmed.
+        check_unconfirmed(job_id, &commit_tx)?;
+        if let Some(spender) = change_spender(&job_commit, &commit_tx) {
+            return Err(format!(
+                "The change of the commit transaction funds transaction {}, which the cancellation would evict",
+                spender
+            ));
+        }
+        cancel_tx.input = commit_tx
+            .input
+            .iter()
+            .map(|input| TxIn {
+                script_sig: ScriptBuf::new(),
+                ..input.clone()
+            })
+            .collect();
+
+        let input_value = Amount::from_sat(job_commit.input_values.iter().sum());
+        let min_fee = commit_fee(&job_commit, &commit_tx)
+            + descendant_fee(&job, &job_commit, &commit_tx)
+            + estimate_p2pkh_fee(&cancel_tx, MIN_RELAY_FEE_RATE);
+        let fee = estimate_p2pkh_fee(&cancel_tx, fee_rate).max(min_fee);
+        set_cancel_output(&mut cancel_tx, input_value, fee)?;
+
+        cancel_tx = sign_transaction_p2pkh(
+            &wallet.public_key,
+            &wallet.address,
+            cancel_tx,
+            wallet.key_name.clone(),
+            wallet.derivation_path.clone(),
+            ecdsa_api::sign_with_ecdsa,
+        )
+        .await;
+
+        // The outputs of the commit transaction that returned to the wallet
+        // are evicted along with the child accelerating it, whose fee is
+        // refunded.
+        let evicted_value = commit_tx
+            .output
+            .iter()
+            .filter(|output| output.script_pubkey == wallet.address.script_pubkey())
+            .map(|output| output.value)
+            .sum::<Amount>();
+        let evicted_child_fee = job_commit
+            .child_tx
+            .as_ref()
+            .map_or(Amount::ZERO, |child_tx| child_fee(&commit_tx, child_tx));
+
+        job.child_txid = None;
+        (
+            (0..job.reveals.len()).collect::<Vec<usize>>(),
+            evicted_value,
+            evicted_child_fee,
+        )
+    } else {
+        // A commit output whose internal key is a tweaked NUMS point has no
+        // key path that the wallet could sign for.
+        for index in &unspent_reveals {
+            let control_block = ControlBlock::decode(&job_commit.reveals[*index].control_block)
+                .expect("control block should be valid");
+            if control_block.internal_key != wallet.schnorr_public_key {
+                return Err(format!(
+                    "Commit output {} has no key path spendable by the wallet",
+                    commit_vout(&job_commit, *index)
+                ));
+            }
+        }
+
+        cancel_tx.input = unspent_reveals
+            .iter()
+            .map(|index| TxIn {
+                previous_output: OutPoint {
+                    txid: commit_tx.txid(),
+                    vout: commit_vout(&job_commit, *index) as u32,
+                },
+                script_sig: ScriptBuf::new(),
+                witness: Witness::new(),
+                sequence: Sequence::ZERO,
+            })
+            .collect();
+        let prevouts: Vec<TxOut> = unspent_reveals
+            .iter()
+            .map(|index| commit_tx.output[commit_vout(&job_commit, *index)].clone())
+            .collect();
+
+        // A key path spend is witnessed by a single signature.
+        let vsize = {
+            let mut cancel_tx = cancel_tx.clone();
+            for input in cancel_tx.input.iter_mut() {
+                input.witness.push([0; SCHNORR_SIGNATURE_SIZE]);
+            }
+            cancel_tx.vsize() as u64
+        };
+        let input_value = prevouts.iter().map(|o| o.value).sum::<Amount>();
+        let sent_reveal_fee = unspent_reveals
+            .iter()
+            .filter(|index| matches!(job.reveals[**index].status, RevealStatus::Sent))
+            .map(|index| Amount::from_sat(job_commit.reveals[*index].fee))
+            .sum::<Amount>();
+        let min_fee = sent_reveal_fee + MIN_RELAY_FEE_RATE.fee_vb(vsize).unwrap();
+        let fee = fee_rate.fee_vb(vsize).unwrap().max(min_fee);
+        set_cancel_output(&mut cancel_tx, input_value, fee)?;
+
+        let mut sighasher = SighashCache::new(&mut cancel_tx);
+        for (input_index, index) in unspent_reveals.iter().enumerate() {
+            let sighash = sighasher
+                .taproot_key_spend_signature_hash(
+                    input_index,
+                    &sighash::Prevouts::All(&prevouts),
+                    TapSighashType::Default,
+                )
+                .expect("failed to construct sighash");
+
+            let sig = schnorr_api::sign_with_schnorr_bip341(
+                wallet.key_name.clone(),
+                wallet.derivation_path.clone(),
+                sighash.to_byte_array().to_vec(),
+                merkle_root(&job_commit.reveals[*index])
+                    .to_byte_array()
+                    .to_vec(),
+            )
+            .await;
+
+            sighasher
+                .witness_mut(input_index)
+                .expect("getting mutable witness reference should work")
+                .push(
+                    Signature {
+                        sig: schnorr::Signature::from_slice(sig.as_slice())
+                            .expect("should parse signature"),
+                        hash_ty: TapSighashType::Default,
+                    }
+                    .to_vec(),
+                );
+        }
+
+        (unspent_reveals, Amount::ZERO, Amount::ZERO)
+    };
+
+    // The owner gets the output of the cancel transaction, but loses the
+    // outputs of the evicted reveal transactions that returned to the wallet.
+    let evicted_reveal_value: u64 = cancelled_reveals
+        .iter()
+        .map(|index| {
+            let outputs: Vec<TxOut> = deserialize(&job_commit.reveals[*index].outputs)
+                .expect("reveal outputs should be valid");
+            returned_reveal_value(
+                &wallet.address,
+                job_txid(&job.reveals[*index].txid),
+                &outputs,
+            )
+        })
+        .sum();
+
+    print("Sending cancel transaction...");
+    send_paid(
+        network,
+        owner,
+        &cancel_tx,
+        evicted_value.to_sat() + evicted_reveal_value,
+        cancel_tx.output[0].value.to_sat() + evicted_child_fee.to_sat(),
+    )
+    .await?;
+    print("Done");
+    credits::exclude(&cancel_tx, &wallet.address);
+
+    let cancel_txid: String = cancel_tx.txid().encode_hex();
+    for index in cancelled_reveals {
+        inscription_ledger::remove_transaction(job_txid(&job.reveals[index].txid));
+        catalog::remove_transaction(job_txid(&job.reveals[index].txid));
+        job.reveals[index].status = RevealStatus::Cancelled(cancel_txid.clone());
+    }
+    jobs::update(job_id, job);
+    jobs::remove_pending_reveal(job_id);
+    jobs::remove_failed_reveals(job_id);
+    jobs::remove_commit(job_id);
+
+    Ok(cancel_txid)
+}
+
+// Sets the value of the single output of a cancel transaction to what is left
+// of `input_value` after paying `fee`.
+fn set_cancel_output(
+    cancel_tx: &mut Transaction,
+    input_value: Amount,
+    fee: Amount,
+) -> Result<(), String> {
+    let output = &mut cancel_tx.output[0];
+    if input_value < fee + output.script_pubkey.dust_value() {
+        return Err(format!(
+            "The {} to reclaim can't pay the fee of {}",
+            input_value, fee
+        ));
+    }
+    output.value = input_value - fee;
+    Ok(())
+}
+
+// Returns the merkle root of the commit taproot tree of a reveal transaction.
+fn merkle_root(reveal_template: &RevealTemplate) -> TapNodeHash {
+    let control_block = ControlBlock::decode(&reveal_template.control_block)
+        .expect("control block should be valid");
+    let reveal_script = Script::from_bytes(&reveal_template.reveal_script);
+
+    control_block.merkle_branch.as_inner().iter().fold(
+        TapNodeHash::from_script(reveal_script, LeafVersion::TapScript),
+        |node, sibling| TapNodeHash::from_node_hashes(node, *sibling),
+    )
+}
+
+// Fails unless the commit transaction of a job is known not to have confirmed,
+// as of the UTXOs of its wallet that have just been fetched.
+fn check_unconfirmed(job_id: u64, commit_tx: &Transaction) -> Result<(), String> {
+    if !utxo_cache::is_pending(commit_tx) {
+        return Err(format!(
+            "The commit transaction of job {} may have confirmed",
+            job_id
+        ));
+    }
+
+    Ok(())
+}
+
+// Returns the output of the commit transaction of a job that the reveal
+// transaction at `index` spends.
+fn commit_vout(job_commit: &JobCommit, index: usize) -> usize {
+    job_commit.reveals[index]
+        .commit_vout
+        .map_or(index, |vout| vout as usize)
+}
+
+fn commit_fee(job_commit: &JobCommit, commit_tx: &Transaction) -> Amount {
+    let input_value = Amount::from_sat(job_commit.input_values.iter().sum());
+    input_value - commit_tx.output.iter().map(|o| o.value).sum::<Amount>()
+}
+
+// Returns the fees of the sent transactions spending the commit transaction of
+// a job, which are evicted together with the commit transaction.
+fn descendant_fee(job: &Job, job_commit: &JobCommit, commit_tx: &Transaction) -> Amount {
+    let sent_reveal_fee = job
+        .reveals
+        .iter()
+        .zip(&job_commit.reveals)
+        .filter(|(reveal, _)| matches!(reveal.status, RevealStatus::Sent))
+        .map(|(_, reveal_template)| Amount::from_sat(reveal_template.fee))
+        .sum::<Amount>();
+    let child_fee = job_commit
+        .child_tx
+        .as_ref()
+        .map_or(Amount::ZERO, |child_tx| child_fee(commit_tx, child_tx));
+
+    sent_reveal_fee + child_fee
+}
+
+// Returns the ID of the sent transaction spending the change output of a
+// commit transaction, unless it is the child accelerating the commit
+// transaction. Replacing the commit transaction would evict it.
+fn change_spender(job_commit: &JobCommit, commit_tx: &Transaction) -> Option<String> {
+    let change_outpoint = OutPoint {
+        txid: commit_tx.txid(),
+        vout: (commit_tx.output.len() - 1) as u32,
+    };
+    let child_txid = job_commit.child_tx.as_ref().map(|child_tx| {
+        deserialize::<Transaction>(child_tx)
+            .expect("child transaction should be valid")
+            .txid()
+            .to_string()
+    });
+    utxo_cache::spent_by(&change_outpoint).filter(|spender| Some(spender) != child_txid.as_ref())
+}
+
+// Returns the fee of a transaction spending the change output of a commit
+// transaction, which is the last output.
+fn child_fee(commit_tx: &Transaction, child_tx: &[u8]) -> Amount {
+    let child_tx: Transaction = deserialize(child_tx).expect("child transaction should be valid");
+    commit_tx.output.last().unwrap().value - child_tx.output[0].value
+}
+
+// Prepares an inscription that its owner funds from their own wallet and
+// returns the commit address together with the amount to pay.
+//
+// Each prepared inscription gets its own Schnorr key, derived from its ID
+// below the owner's derivation path, so that its commit address is unique
+// even if the same inscription is prepared twice. `send_funded_reveals` signs
+// and sends the reveal transaction once the commit address has been paid.
+//
+// If a refund key is given, the commit taproot tree gets a second leaf that
+// lets the key spend the commit output after `REFUND_CSV_BLOCKS` blocks, so
+// the funder can reclaim their sats if the reveal transaction is never sent.
+//
+// The commit address is only watched until the prepared inscription expires
+// after `PREPARED_INSCRIPTION_TTL`, and each owner may only have
+// `MAX_PREPARED_INSCRIPTIONS` prepared inscriptions waiting at a time, out of
+// `MAX_WAITING_PREPARED_INSCRIPTIONS` in total.
+pub async fn prepare_inscription(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    request: InscriptionRequest,
+    refund_public_key: Option<String>,
+    fee_rate: u64,
+) -> Result<PreparedInscription, String> {
+    let bitcoin_network = transform_network(network);
+    check_prepared_inscriptions(owner)?;
+    let (inscription, reveal_output) = prepare_request(request, bitcoin_network, None)?;
+    let refund_leaf = refund_public_key
+        .map(|public_key| {
+            Ok::<_, String>(RefundLeaf {
+                public_key: parse_x_only_public_key(&public_key)?,
+                csv_blocks: REFUND_CSV_BLOCKS,
+            })
+        })
+        .transpose()?;
+
+    let id = jobs::next_prepared_id();
+    let mut derivation_path = derivation_path;
+    derivation_path.push(id.to_be_bytes().to_vec());
+
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let raw_public_key = schnorr_api::schnorr_public_key(key_name, derivation_path.clone()).await;
+    let schnorr_public_key = PublicKey::from_slice(&raw_public_key).unwrap().into();
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let reveal_plan = RevealPlan::new(
+        bitcoin_network,
+        schnorr_public_key,
+        None,
+        &[inscription],
+        vec![],
+        vec![reveal_output],
+        fee_rate,
+        refund_leaf.as_ref(),
+    );
+
+    let refund = reveal_plan
+        .refund
+        .as_ref()
+        .map(|(refund_script, control_block)| RefundInfo {
+            commit_address: reveal_plan.commit_address.to_string(),
+            csv_blocks: REFUND_CSV_BLOCKS,
+            refund_script: refund_script.as_bytes().encode_hex(),
+            control_block: control_block.serialize().encode_hex(),
+        });
+
+    // Concurrent calls may have prepared inscriptions in the meantime.
+    check_prepared_inscriptions(owner)?;
+
+    let prepared_inscription = PreparedInscription {
+        id,
+        commit_address: reveal_plan.commit_address.to_string(),
+        amount: reveal_plan.commit_output().value.to_sat(),
+        expires_at: ic_cdk::api::time() + PREPARED_INSCRIPTION_TTL,
+        reveal: None,
+    };
+    jobs::insert_prepared_reveal(
+        id,
+        PreparedReveal {
+            inscription: prepared_inscription.clone(),
+            owner,
+            derivation_path,
+            reveal_script: reveal_plan.reveal_script.into_bytes(),
+            control_block: reveal_plan.control_block.serialize(),
+            reveal_outputs: serialize(&reveal_plan.outputs),
+            fee: reveal_plan.fee.to_sat(),
+            refund,
+        },
+    );
+
+    Ok(prepared_inscription)
+}
+
+// Fails if `owner`, or all principals together, already have the maximum
+// number of prepared inscriptions waiting for their reveal transaction.
+fn check_prepared_inscriptions(owner: Principal) -> Result<(), String> {
+    let unsent_reveals = jobs::unsent_reveals(ic_cdk::api::time());
+    if unsent_reveals.len() >= MAX_WAITING_PREPARED_INSCRIPTIONS {
+        return Err(
+            "Too many prepared inscriptions are waiting for their reveal, try again later"
+                .to_string(),
+        );
+    }
+
+    let waiting = unsent_reveals
+        .iter()
+        .filter(|(_, prepared_reveal)| prepared_reveal.owner == owner)
+        .count();
+    if waiting >= MAX_PREPARED_INSCRIPTIONS {
+        return Err(format!(
+            "At most {} prepared inscriptions may wait for their reveal at a time",
+            MAX_PREPARED_INSCRIPTIONS
+        ));
+    }
+
+    Ok(())
+}
+
+// Signs and sends the reveal transactions of the prepared inscriptions whose
+// commit address has received at least the required amount. Any sats beyond
+// the amount go to the inscription output. Reveal transactions that couldn't
+// be sent are signed and sent again until the prepared inscription expires;
+// the funder can reclaim the payment through the refund leaf after that.
+//
+// Each round only checks the next `PREPARED_INSCRIPTION_CHECKS` commit
+// addresses, so the cycles spent per round don't grow with the number of
+// prepared inscriptions.
+pub async fn send_funded_reveals(network: BitcoinNetwork) {
+    let prepared_reveals =
+        jobs::next_unsent_reveals(ic_cdk::api::time(), PREPARED_INSCRIPTION_CHECKS);
+    for (id, prepared_reveal) in prepared_reveals {
+        let inscription = prepared_reveal.inscription;
+        let funding_utxo =
+            bitcoin_api::get_utxos(network, inscription.commit_address.clone(), None)
+                .await
+                .utxos
+                .into_iter()
+                .find(|utxo| utxo.value >= inscription.amount);
+        let Some(funding_utxo) = funding_utxo else {
+            continue;
+        };
+
+        let commit_address = Address::from_str(&inscription.commit_address)
+            .unwrap()
+            .assume_checked();
+        let mut outputs: Vec<TxOut> =
+            deserialize(&prepared_reveal.reveal_outputs).expect("reveal outputs should be valid");
+        outputs[0].value += Amount::from_sat(funding_utxo.value - inscription.amount);
+
+        let reveal_plan = RevealPlan {
+            reveal_script: ScriptBuf::from_bytes(prepared_reveal.reveal_script),
+            control_block: ControlBlock::decode(&prepared_reveal.control_block)
+                .expect("control block should be valid"),
+            commit_address: commit_address.clone(),
+            refund: None,
+            inputs: vec![],
+            outputs,
+            fee: Amount::from_sat(prepared_reveal.fee),
+        };
+
+        let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+        let reveal_tx = reveal_plan
+            .sign_commit_input(
+                key_name,
+                prepared_reveal.derivation_path,
+                to_outpoint(&funding_utxo),
+                TxOut {
+                    script_pubkey: commit_address.script_pubkey(),
+                    value: Amount::from_sat(funding_utxo.value),
+                },
+            )
+            .await;
+
+        print(format!(
+            "Sending reveal transaction of prepared inscription {}...",
+            id
+        ));
+        let status = match broadcasts::send_transaction(network, serialize(&reveal_tx)).await {
+            Ok(()) => RevealStatus::Sent,
+            Err(err) => RevealStatus::Failed(err),
+        };
+        jobs::set_prepared_reveal(
+            id,
+            Reveal {
+                txid: reveal_tx.txid().encode_hex(),
+                status,
+            },
+        );
+    }
+}
+
+// Mints a rune with open mint terms and returns the ID of the transaction.
+//
+// Runes minted to the canister's wallet are recorded in the rune ledger with
+// the amount that each mint yields according to the terms of the rune, as
+// reported by the ord indexer at `ord_url`.
+pub async fn mint_rune(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    ord_url: Option<String>,
+    rune_id: String,
+    dst_address: Option<String>,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let bitcoin_network = transform_network(network);
+    let rune_id = RuneId::from_str(&rune_id)?;
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let dst_address = if let Some(dst_address) = dst_address {
+        parse_address(&dst_address, bitcoin_network)?
+    } else {
+        wallet.address.clone()
+    };
+
+    let amount = if dst_address == wallet.address {
+        let ord_url = ord_url.ok_or("No ord indexer is configured to look up the rune")?;
+        let rune = ord_api::get_rune(&ord_url, &rune_id.to_string()).await?;
+        if !rune.mintable {
+            return Err(format!("Rune {} can't be minted", rune_id));
+        }
+        Some(rune.amount.unwrap_or_default())
+    } else {
+        None
+    };
+
+    // Without a pointer or edicts the minted runes go to the first output.
+    let runestone = Runestone {
+        mint: Some(rune_id),
+        ..Default::default()
+    };
+
+    let outputs = vec![
+        TxOut {
+            script_pubkey: dst_address.script_pubkey(),
+            value: Amount::from_sat(POSTAGE),
+        },
+        TxOut {
+            script_pubkey: runestone.encipher(),
+            value: Amount::ZERO,
+        },
+    ];
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let transaction = build_wallet_transaction(&wallet, vec![], outputs, fee_rate).await?;
+    let txid = transaction.txid();
+
+    // The postage of runes minted to the wallet isn't plain bitcoin.
+    let carriers = if amount.is_some() { vec![0] } else { vec![] };
+    print("Sending mint transaction...");
+    send_wallet_transaction(network, &wallet, owner, &transaction, &carriers).await?;
+    print("Done");
+    credits::exclude(&transaction, &wallet.address);
+
+    if let Some(amount) = amount {
+        rune_ledger::insert(
+            OutPoint { txid, vout: 0 },
+            RuneUtxo {
+                address: wallet.address.to_string(),
+                value: POSTAGE,
+                balances: vec![RuneBalance {
+                    rune_id: rune_id.to_string(),
+                    amount,
+                }],
+            },
+        );
+    }
+
+    Ok(txid.encode_hex())
+}
+
+// Transfers runes held by the canister and returns the ID of the transaction.
+//
+// The runes of the spent outputs that are not transferred, including those of
+// other runes, are sent to a change output of the wallet.
+pub async fn transfer_runes(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    rune_id: String,
+    amount: u128,
+    dst_address: String,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let bitcoin_network = transform_network(network);
+    let rune_id = RuneId::from_str(&rune_id)?;
+    let dst_address = parse_address(&dst_address, bitcoin_network)?;
+
+    // An edict amount of zero transfers all remaining runes.
+    if amount == 0 {
+        return Err("Amount must be greater than zero".to_string());
+    }
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    let rune_inputs =
+        rune_ledger::select(&wallet.address.to_string(), &rune_id.to_string(), amount)?;
+
+    let mut remaining = BTreeMap::new();
+    for (_, rune_utxo) in &rune_inputs {
+        for balance in &rune_utxo.balances {
+            *remaining.entry(balance.rune_id.clone()).or_insert(0u128) += balance.amount;
+        }
+    }
+    *remaining.get_mut(&rune_id.to_string()).unwrap() -= amount;
+    let change_balances: Vec<RuneBalance> = remaining
+        .into_iter()
+        .filter(|(_, amount)| *amount > 0)
+        .map(|(rune_id, amount)| RuneBalance { rune_id, amount })
+        .collect();
+
+    let mut runestone = Runestone {
+        edicts: vec![Edict {
+            id: rune_id,
+            amount,
+            output: 0,
+        }],
+        ..Default::default()
+    };
+
+    let mut outputs = vec![TxOut {
+        script_pubkey: dst_address.script_pubkey(),
+        value: Amount::from_sat(POSTAGE),
+    }];
+    if !change_balances.is_empty() {
+        runestone.pointer = Some(1);
+        outputs.push(TxOut {
+            script_pubkey: wallet.address.script_pubkey(),
+            value: Amount::from_sat(POSTAGE),
+        });
+    }
+    outputs.push(TxOut {
+        script_pubkey: runestone.encipher(),
+        value: Amount::ZERO,
+    });
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let transaction = build_wallet_transaction(
+        &wallet,
+        rune_inputs
+            .iter()
+            .map(|(outpoint, rune_utxo)| (*outpoint, rune_utxo.value))
+            .collect(),
+        outputs,
+        fee_rate,
+    )
+    .await?;
+    let txid = transaction.txid();
+
+    // The postage of the outputs returning runes to the wallet isn't plain
+    // bitcoin.
+    let mut carriers = vec![];
+    if dst_address == wallet.address {
+        carriers.push(0);
+    }
+    if !change_balances.is_empty() {
+        carriers.push(1);
+    }
+    print("Sending transfer transaction...");
+    send_wallet_transaction(network, &wallet, owner, &transaction, &carriers).await?;
+    print("Done");
+    credits::exclude(&transaction, &wallet.address);
+
+    for (outpoint, _) in rune_inputs {
+        rune_ledger::remove(outpoint);
+    }
+    if dst_address == wallet.address {
+        rune_ledger::insert(
+            OutPoint { txid, vout: 0 },
+            RuneUtxo {
+                address: wallet.address.to_string(),
+                value: POSTAGE,
+                balances: vec![RuneBalance {
+                    rune_id: rune_id.to_string(),
+                    amount,
+                }],
+            },
+        );
+    }
+    if !change_balances.is_empty() {
+        rune_ledger::insert(
+            OutPoint { txid, vout: 1 },
+            RuneUtxo {
+                address: wallet.address.to_string(),
+                value: POSTAGE,
+                balances: change_balances,
+            },
+        );
+    }
+
+    Ok(txid.encode_hex())
+}
+
+// Moves an inscription from the wallet into custody for `owner` and returns
+// the ID of the transaction.
+pub async fn deposit_inscription(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    ord_url: Option<String>,
+    inscription_id: String,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let inscription_id = InscriptionId::from_str(&inscription_id)?;
+
+    let mut wallet = Wallet::fetch(network, derivation_path).await?;
+    let (inscribed_utxo, _) =
+        take_inscribed_utxo(&mut wallet, inscription_id, ord_url.as_deref()).await?;
+
+    // Moving the whole output into custody would also move any other
+    // inscription it carries, which nobody could then withdraw.
+    let outpoint = to_outpoint(&inscribed_utxo);
+    let inscription_count = match inscription_ledger::get(outpoint) {
+        Some(inscribed_utxo) => inscribed_utxo.inscriptions.len(),
+        None => {
+            let ord_url = ord_url.as_deref().ok_or(
+                "Inscriptions outside the ledger can only be deposited with an ord indexer",
+            )?;
+            ord_api::get_output_inscriptions(ord_url, outpoint)
+                .await?
+                .len()
+        }
+    };
+    if inscription_count > 1 {
+        return Err(format!(
+            "Output {} carries more than one inscription",
+            outpoint
+        ));
+    }
+
+    move_into_custody(
+        network,
+        &wallet,
+        owner,
+        inscription_id,
+        inscribed_utxo,
+        fee_rate,
+    )
+    .await
+}
+
+// Credits an inscription that was sent to the wallet's address to `owner` by
+// moving it into custody, and returns the ID of the transaction. The ord
+// indexer at `ord_url` must report the inscription as the only one carried by
+// the output.
+#[allow(clippy::too_many_arguments)]
+pub async fn claim_deposit(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    ord_url: String,
+    txid: String,
+    vout: u32,
+    inscription_id: String,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let inscription_id = InscriptionId::from_str(&inscription_id)?;
+    let outpoint = OutPoint {
+        txid: Txid::from_str(&txid).map_err(|e| e.to_string())?,
+        vout,
+    };
+
+    if custody::get(inscription_id).is_some() {
+        return Err(format!(
+            "Inscription {} is already held in custody",
+            inscription_id
+        ));
+    }
+
+    let mut wallet = Wallet::fetch(network, derivation_path).await?;
+    let position = wallet
+        .utxos
+        .iter()
+        .position(|utxo| to_outpoint(utxo) == outpoint)
+        .ok_or_else(|| format!("Output {} is not held by {}", outpoint, wallet.address))?;
+    let deposited_utxo = wallet.utxos.remove(position);
+
+    // Moving the whole output into custody would also move any other
+    // inscription it carries, which nobody could then claim.
+    let inscriptions = ord_api::get_output_inscriptions(&ord_url, outpoint).await?;
+    if !inscriptions.contains(&inscription_id) {
+        return Err(format!(
+            "Output {} does not carry inscription {}",
+            outpoint, inscription_id
+        ));
+    }
+    if inscriptions.len() > 1 {
+        return Err(format!(
+            "Output {} carries more than one inscription",
+            outpoint
+        ));
+    }
+
+    move_into_custody(
+        network,
+        &wallet,
+        owner,
+        inscription_id,
+        deposited_utxo,
+        fee_rate,
+    )
+    .await
+}
+
+// Sends an inscribed UTXO of the wallet to the custody address, records the
+// inscription as held for `owner` and returns the ID of the transaction.
+async fn move_into_custody(
+    network: BitcoinNetwork,
+    wallet: &Wallet,
+    owner: Principal,
+    inscription_id: InscriptionId,
+    inscribed_utxo: Utxo,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let custody_address = get_p2pkh_address(network, key_name, custody_derivation_path()).await;
+    let custody_address = Address::from_str(&custody_address)
+        .unwrap()
+        .assume_checked();
+
+    // The inscribed UTXO is the first input and therefore ends up in the
+    // custody output as a whole, keeping the offset of the inscribed sat.
+    let outputs = vec![TxOut {
+        script_pubkey: custody_address.script_pubkey(),
+        value: Amount::from_sat(inscribed_utxo.value),
+    }];
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let transaction = build_wallet_transaction(
+        wallet,
+        vec![(to_outpoint(&inscribed_utxo), inscribed_utxo.value)],
+        outputs,
+        fee_rate,
+    )
+    .await?;
+    let txid = transaction.txid();
+
+    print("Sending deposit transaction...");
+    send_wallet_transaction(network, wallet, owner, &transaction, &[]).await?;
+    print("Done");
+    credits::exclude(&transaction, &wallet.address);
+    inscription_ledger::remove(to_outpoint(&inscribed_utxo));
+
+    custody::insert(
+        inscription_id,
+        Custody {
+            owner,
+            outpoint: OutPoint { txid, vout: 0 }.to_string(),
+            value: inscribed_utxo.value,
+        },
+    );
+
+    Ok(txid.encode_hex())
+}
+
+// Sends an inscription held in custody for `owner` to `dst_address` and
+// returns the ID of the transaction. The fee is paid from the owner's wallet.
+pub async fn withdraw_inscription(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+    inscription_id: String,
+    dst_address: String,
+    fee_rate: u64,
+) -> Result<String, String> {
+    let bitcoin_network = transform_network(network);
+    let inscription_id = InscriptionId::from_str(&inscription_id)?;
+    let dst_address = parse_address(&dst_address, bitcoin_network)?;
+
+    // The inscription leaves custody before the first await, so that it can't
+    // be transferred to another principal while it is being withdrawn.
+    let withdrawal = custody::Withdrawal::start(inscription_id, owner)?;
+    let custody = withdrawal.custody().clone();
+    let outpoint = OutPoint::from_str(&custody.outpoint).expect("outpoint should be valid");
+
+    let wallet = Wallet::fetch(network, derivation_path).await?;
+
+    // Only the custody key is needed to sign the inscribed input. The
+    // custody wallet isn't fetched, as that would make concurrent withdrawals
+    // of other inscriptions fail while it is busy.
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let custody_public_key =
+        ecdsa_api::ecdsa_public_key(key_name.clone(), custody_derivation_path()).await;
+    let custody_address =
+        Address::from_str(&public_key_to_p2pkh_address(network, &custody_public_key))
+            .unwrap()
+            .assume_checked();
+
+    let outputs = vec![TxOut {
+        script_pubkey: dst_address.script_pubkey(),
+        value: Amount::from_sat(custody.value),
+    }];
+
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let mut transaction =
+        fund_transaction(&wallet, vec![(outpoint, custody.value)], outputs, fee_rate)?;
+
+    // The inscribed input belongs to the custody wallet while the inputs
+    // paying the fee belong to the owner's wallet.
+    sign_input_p2pkh_with_key(
+        key_name,
+        custody_derivation_path(),
+        &custody_public_key,
+        &custody_address,
+        &mut transaction,
+        0,
+    )
+    .await;
+    for index in 1..transaction.input.len() {
+        sign_input_p2pkh(&wallet, &mut transaction, index).await;
+    }
+    let txid = transaction.txid();
+
+    // An inscription withdrawn to the owner's own wallet doesn't return as
+    // plain bitcoin.
+    let carriers = if dst_address == wallet.address {
+        vec![0]
+    } else {
+        vec![]
+    };
+    print("Sending withdrawal transaction...");
+    send_wallet_transaction(network, &wallet, owner, &transaction, &carriers).await?;
+    print("Done");
+    credits::exclude(&transaction, &wallet.address);
+
+    withdrawal.complete();
+
+    Ok(txid.encode_hex())
+}
+
+// Removes the UTXO holding an inscription from the wallet's UTXOs, so that it
+// isn't spent to fund the transaction, and returns it along with the offset of
+// the inscribed sat within it.
+//
+// Inscriptions that the canister has moved are found in the inscription
+// ledger, any other inscription is looked up with the ord indexer at
+// `ord_url`.
+async fn take_inscribed_utxo(
+    wallet: &mut Wallet,
+    inscription_id: InscriptionId,
+    ord_url: Option<&str>,
+) -> Result<(Utxo, u64), String> {
+    let sat_point = match inscription_ledger::locate(inscription_id) {
+        Some((outpoint, inscribed_sat)) => SatPoint {
+            outpoint,
+            offset: inscribed_sat.offset,
+        },
+        None => {
+            let ord_url = ord_url.ok_or_else(|| {
+                format!(
+                    "Inscription {} is unknown and no ord indexer is configured",
+                    inscription_id
+                )
+            })?;
+            ord_api::get_inscription_sat_point(ord_url, inscription_id).await?
+        }
+    };
+
+    let not_held = format!(
+        "Inscription {} is not held by {}",
+        inscription_id, wallet.address
+    );
+    for utxos in [&mut wallet.inscribed_utxos, &mut wallet.utxos] {
+        if let Some(position) = utxos
+            .iter()
+            .position(|utxo| to_outpoint(utxo) == sat_point.outpoint)
+        {
+            return Ok((utxos.remove(position), sat_point.offset));
+        }
+    }
+
+    Err(not_held)
+}
+
+// Turns an inscription request into an inscription and the postage output
+// that receives it. Inscriptions without a recipient go to `default_address`.
+fn prepare_request(
+    request: InscriptionRequest,
+    network: Network,
+    default_address: Option<&Address>,
+) -> Result<(Inscription, TxOut), String> {
+    let mut inscription = Inscription::new(
+        Some(request.content_type.into_bytes()),
+        Some(request.body.into_bytes()),
+    );
+
+    if let Some(metaprotocol) = request.metaprotocol {
+        metaprotocol::validate(&metaprotocol, &inscription)?;
+        inscription.metaprotocol = Some(metaprotocol.into_bytes());
+    }
+
+    let dst_address = match (request.recipient, default_address) {
+        (Some(recipient), _) => parse_address(&recipient, network)?,
+        (None, Some(default_address)) => default_address.clone(),
+        (None, None) => return Err("A recipient is required".to_string()),
+    };
+
+    let reveal_output = TxOut {
+        script_pubkey: dst_address.script_pubkey(),
+        value: Amount::from_sat(POSTAGE),
+    };
+
+    Ok((inscription, reveal_output))
+}
+
+// Builds, signs and sends the commit and reveal transactions for the given
+// inscriptions and returns their transaction IDs together with the ID of the
+// job tracking them, through which the commit transaction can be replaced,
+// accelerated or cancelled.
+//
+// A reveal transaction that fails to be sent is kept and sent again later, as
+// are those of fan-out jobs.
+#[allow(clippy::too_many_arguments)]
+async fn commit_and_reveal(
+    network: BitcoinNetwork,
+    wallet: &Wallet,
+    owner: Principal,
+    inscriptions: Vec<Inscription>,
+    reveal_inputs: Vec<(OutPoint, TxOut)>,
+    reveal_outputs: Vec<TxOut>,
+    sat_point: Option<SatPoint>,
+    internal_key: Option<XOnlyPublicKey>,
+    fee_rate: u64,
+) -> Result<(String, String, u64), String> {
+    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+    let mut reveal_input_values: Vec<u64> = reveal_inputs
+        .iter()
+        .map(|(_, output)| output.value.to_sat())
+        .collect();
+    let reveal_input = reveal_input_values.len();
+
+    let (commit_tx, reveal_tx, reveal_template) = build_inscription_transactions(
+        transform_network(network),
+        wallet,
+        &inscriptions,
+        reveal_inputs,
+        reveal_outputs,
+        sat_point,
+        internal_key,
+        fee_rate,
+    )
+    .await?;
+
+    print(&format!(
+        "Signed commit transaction: {}",
+        hex::encode(serialize(&commit_tx))
+    ));
+
+    // The owner pays for everything the commit transaction takes from the
+    // wallet: the commit fee and the commit output, which covers the reveal
+    // fee and the postage.
+    print("Sending commit transaction...");
+    send_wallet_transaction(network, wallet, owner, &commit_tx, &[]).await?;
+    print("Done");
+    credits::exclude(&commit_tx, &wallet.address);
+
+    let job_id = jobs::insert(Job {
+        commit_txid: commit_tx.txid().encode_hex(),
+        reveals: vec![Reveal {
+            txid: reveal_tx.txid().encode_hex(),
+            status: RevealStatus::Pending,
+        }],
+        child_txid: None,
+    });
+    jobs::insert_commit(
+        job_id,
+        JobCommit {
+            derivation_path: wallet.derivation_path.clone(),
+            commit_tx: serialize(&commit_tx),
+            input_values: input_values(wallet, &commit_tx),
+            reveals: vec![reveal_template],
+            child_tx: None,
+        },
+    );
+
+    let reveal_tx_bytes = serialize(&reveal_tx);
+    print(&format!(
+        "Signed reveal transaction: {}",
+        hex::encode(&reveal_tx_bytes)
+    ));
+
+    let commit_vout = reveal_tx.input[reveal_input].previous_output.vout as usize;
+    let commit_value = commit_tx.output[commit_vout].value.to_sat();
+
+    // The reveal transaction is sent again later if sending it fails, so its
+    // outputs are recorded right away.
+    credits::exclude(&reveal_tx, &wallet.address);
+    catalog::record(reveal_tx.txid(), &inscriptions);
+    for inscription in &inscriptions {
+        metaprotocol::record(inscription);
+    }
+    reveal_input_values.push(commit_value);
+    record_inscriptions(
+        &wallet.address,
+        &reveal_tx,
+        &reveal_input_values,
+        Some((reveal_input, &inscriptions)),
+    );
+
+    // The reveal outputs that return plain bitcoin to the wallet are credited
+    // back.
+    credits::credit(
+        owner,
+        returned_reveal_value(&wallet.address, reveal_tx.txid(), &reveal_tx.output),
+    );
+
+    print("Sending reveal transaction...");
+    let status = match broadcasts::send_transaction(network, reveal_tx_bytes.clone()).await {
+        Ok(()) => RevealStatus::Sent,
+        Err(err) => {
+            jobs::insert_failed_reveal(
+                job_id,
+                0,
+                FailedReveal {
+                    reveal_tx: reveal_tx_bytes,
+                    attempts: 0,
+                },
+            );
+            RevealStatus::Failed(err)
+        }
+    };
+    jobs::set_reveal_status(job_id, 0, status);
+
+    Ok((
+        commit_tx.txid().encode_hex(),
+        reveal_tx.txid().encode_hex(),
+        job_id,
+    ))
+}
+
+// Returns the credits of `owner` after crediting the deposits to the wallet
+// that have enough confirmations.
+pub async fn get_credits(
+    network: BitcoinNetwork,
+    derivation_path: Vec<Vec<u8>>,
+    owner: Principal,
+) -> u64 {
+    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+    let address = get_p2pkh_address(network, key_name, derivation_path).await;
+    credit_deposits(network, address, owner).await;
+
+    credits::balance(owner)
+}
+
+// Credits the deposits to `address` that have enough confirmations to `owner`.
+async fn credit_deposits(network: BitcoinNetwork, address: String, owner: Principal) {
+    let utxos = bitcoin_api::get_utxos(network, address, Some(credits::MIN_CONFIRMATIONS))
+        .await
+        .utxos;
+    credits::credit_deposits(owner, &utxos);
+}
+
+// Sends a transaction of the wallet after charging `owner` for the plain
+// bitcoin it takes from the wallet, see `wallet_flow`. The deposits to the
+// wallet are credited first, including those that the transaction spends
+// before they have enough confirmations.
+async fn send_wallet_transaction(
+    network: BitcoinNetwork,
+    wallet: &Wallet,
+    owner: Principal,
+    transaction: &Transaction,
+    carriers: &[usize],
+) -> Result<(), String> {
+    credit_deposits(network, wallet.address.to_string(), owner).await;
+    credits::credit_spent_deposits(owner, transaction, &wallet.utxos);
+    let (spent, returned) = wallet_flow(&wallet.address, &wallet.utxos, transaction, carriers);
+
+    send_paid(network, owner, transaction, spent, returned).await
+}
+
+// Sends a transaction for which `owner` pays `charge` sats from their credits
+// and gets `refund` sats credited, netting the two. The net charge is taken
+// before the transaction is sent and given back if sending it fails, while
+// the net refund is only credited once the transaction has been sent.
+async fn send_paid(
+    network: BitcoinNetwork,
+    owner: Principal,
+    transaction: &Transaction,
+    charge: u64,
+    refund: u64,
+) -> Result<(), String> {
+    let net_charge = charge.saturating_sub(refund);
+    credits::debit(owner, net_charge)?;
+    if let Err(err) = broadcasts::send_transaction(network, serialize(transaction)).await {
+        credits::credit(owner, net_charge);
+        return Err(err);
+    }
+    credits::credit(owner, refund.saturating_sub(charge));
+
+    Ok(())
+}
+
+// Returns the plain bitcoin that a transaction takes from the wallet at
+// `address` and the plain bitcoin it returns, i.e. the value of the UTXOs
+// among `utxos` that it spends without inscriptions or runes and the value of
+// its outputs paying the wallet. The outputs at the indices in `carriers`
+// carry runes or inscriptions back to the wallet and don't count as returned.
+fn wallet_flow(
+    address: &Address,
+    utxos: &[Utxo],
+    transaction: &Transaction,
+    carriers: &[usize],
+) -> (u64, u64) {
+    let spent = transaction
+        .input
+        .iter()
+        .filter(|input| {
+            !inscription_ledger::contains(input.previous_output)
+                && !rune_ledger::contains(input.previous_output)
+        })
+        .filter_map(|input| {
+            utxos
+                .iter()
+                .find(|utxo| to_outpoint(utxo) == input.previous_output)
+        })
+        .map(|utxo| utxo.value)
+        .sum();
+    let returned = transaction
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(vout, output)| {
+            output.script_pubkey == address.script_pubkey() && !carriers.contains(vout)
+        })
+        .map(|(_, output)| output.value.to_sat())
+        .sum();
+
+    (spent, returned)
+}
+
+// Returns the value of the outputs of a reveal transaction that return plain
+// bitcoin to the wallet at `address`, i.e. that pay it without carrying an
+// inscription, like payments to the wallet's own address.
+fn returned_reveal_value(address: &Address, reveal_txid: Txid, outputs: &[TxOut]) -> u64 {
+    outputs
+        .iter()
+        .enumerate()
+        .filter(|(vout, output)| {
+            output.script_pubkey == address.script_pubkey()
+                && !inscription_ledger::contains(OutPoint {
+                    txid: reveal_txid,
+                    vout: *vout as u32,
+                })
+        })
+        .map(|(_, output)| output.value.to_sat())
+        .sum()
+}
+
+// Returns the values of the wallet's outputs spent by a transaction.
+fn input_values(wallet: &Wallet, transaction: &Transaction) -> Vec<u64> {
+    transaction
+        .input
+        .iter()
+        .filter_map(|input| {
+            wallet
+                .utxos
+                .iter()
+                .find(|utxo| to_outpoint(utxo) == input.previous_output)
+        })
+        .map(|utxo| utxo.value)
+        .collect()
 }
 
+// Records the inscriptions that a sent transaction moves to outputs paying
+// `address` and forgets the inscribed outputs it spends.
+//
+// The inscriptions of the spent outputs follow their sats through the
+// transaction, and so do the inscriptions revealed through the input at the
+// given index. A revealed inscription lands on its pointer if that points
+// into the outputs, and on the first sat of its input otherwise.
+fn record_inscriptions(
+    address: &Address,
+    transaction: &Transaction,
+    input_values: &[u64],
+    revealed: Option<(usize, &[Inscription])>,
+) {
+    let txid = transaction.txid();
+    let output_values: Vec<u64> = transaction
+        .output
+        .iter()
+        .map(|output| output.value.to_sat())
+        .collect();
 
-// Creates an ordinal inscription
-pub async fn inscribe(
-    network: BitcoinNetwork,
-    content_type: Option<Vec<u8>>,
-    body: Option<Vec<u8>>,
-    dst_address: Option<String>,
-    fee_rate: u64,
-) -> (String, String) {
-    let bitcoin_network = transform_network(network);
-    let inscription = Inscription::new(content_type, body);
+    let mut inscribed_sats = vec![];
+    let mut start = 0;
+    for (index, input) in transaction.input.iter().enumerate() {
+        if let Some(inscribed_utxo) = inscription_ledger::remove(input.previous_output) {
+            inscribed_sats.extend(
+                inscribed_utxo
+                    .inscriptions
+                    .into_iter()
+                    .map(|inscribed_sat| InscribedSat {
+                        offset: start + inscribed_sat.offset,
+                        ..inscribed_sat
+                    }),
+            );
+        }
 
-    let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
+        if let Some((_, inscriptions)) = revealed.filter(|(reveal_input, _)| *reveal_input == index)
+        {
+            for (index, inscription) in inscriptions.iter().enumerate() {
+                inscribed_sats.push(InscribedSat {
+                    inscription_id: InscriptionId {
+                        txid,
+                        index: index as u32,
+                    }
+                    .to_string(),
+                    offset: sat_point::inscription_offset(
+                        inscription.pointer(),
+                        start,
+                        &output_values,
+                    ),
+                });
+            }
+        }
 
-    let derivation_path = vec![];
+        start += input_values[index];
+    }
 
-    // Fetch our public key, P2PKH address, and UTXOs.
-    let own_public_key =
-        ecdsa_api::ecdsa_public_key(key_name.clone(), derivation_path.clone()).await;
-    let own_address = public_key_to_p2pkh_address(network, &own_public_key);
+    let mut outputs: BTreeMap<usize, Vec<InscribedSat>> = BTreeMap::new();
+    for inscribed_sat in inscribed_sats {
+        if let Some((vout, offset)) =
+            sat_point::flow(input_values, &output_values, inscribed_sat.offset)
+        {
+            outputs.entry(vout).or_default().push(InscribedSat {
+                offset,
+                ..inscribed_sat
+            });
+        }
+    }
 
-    print("Fetching UTXOs...");
-    // Note that pagination may have to be used to get all UTXOs for the given address.
-    // For the sake of simplicity, it is assumed here that the `utxo` field in the response
-    // contains all UTXOs.
-    let own_utxos = bitcoin_api::get_utxos(network, own_address.clone())
-        .await
-        .utxos;
+    for (vout, inscriptions) in outputs {
+        if transaction.output[vout].script_pubkey == address.script_pubkey() {
+            inscription_ledger::insert(
+                OutPoint {
+                    txid,
+                    vout: vout as u32,
+                },
+                InscribedUtxo {
+                    address: address.to_string(),
+                    inscriptions,
+                },
+            );
+        }
+    }
+}
 
-    // We can be sure that the address corresponds to the correct network
-    let own_address = Address::from_str(&own_address).unwrap().assume_checked();
+// Parses the ID of a transaction as stored in a job, i.e. in internal byte
+// order.
+fn job_txid(txid: &str) -> Txid {
+    hex::decode(txid)
+        .ok()
+        .and_then(|txid| Txid::from_slice(&txid).ok())
+        .expect("transaction ID should be valid")
+}
 
-    let dst_address = if let Some(dst_address) = dst_address {
-        Address::from_str(&dst_address).unwrap().assume_checked()
-    } else {
-        // Send inscription to canister's own address if none is provided
-        own_address.clone()
+#[allow(clippy::too_many_arguments)]
+async fn build_inscription_transactions(
+    network: Network,
+    wallet: &Wallet,
+    inscriptions: &[Inscription],
+    reveal_inputs: Vec<(OutPoint, TxOut)>,
+    reveal_outputs: Vec<TxOut>,
+    sat_point: Option<SatPoint>,
+    internal_key: Option<XOnlyPublicKey>,
+    fee_rate: FeeRate,
+) -> Result<(Transaction, Transaction, RevealTemplate), String> {
+    let reveal_plan = RevealPlan::new(
+        network,
+        wallet.schnorr_public_key,
+        internal_key,
+        inscriptions,
+        reveal_inputs,
+        reveal_outputs,
+        fee_rate,
+        None,
+    );
+
+    let commit_output = reveal_plan.commit_output();
+    let commit_tx =
+        build_commit_transaction(wallet, vec![commit_output.clone()], sat_point, fee_rate).await?;
+
+    let (vout, _commit_output) = commit_tx
+        .output
+        .iter()
+        .enumerate()
+        .find(|(_vout, output)| output.script_pubkey == commit_output.script_pubkey)
+        .expect("should find sat commit/inscription output");
+
+    let reveal_template = RevealTemplate {
+        commit_vout: Some(vout as u32),
+        ..reveal_plan.template()
     };
+    let reveal_tx = reveal_plan.sign(wallet, &commit_tx, vout).await;
 
-    print("Fetching Schnorr public key...");
-    let raw_public_key = schnorr_api::schnorr_public_key(key_name.clone(), vec![]).await;
-    // Convert the raw public key (sec1 encoded) to a XOnlyPublicKey (BIP 340 encoded)
-    let schnorr_public_key = PublicKey::from_slice(&raw_public_key).unwrap().into();
+    Ok((commit_tx, reveal_tx, reveal_template))
+}
 
-    let fee_rate = FeeRate::from_sat_per_vb(fee_rate).unwrap();
+// Builds a commit transaction with a taproot output per reveal transaction
+// and a reveal transaction for each of them, along with the templates of the
+// reveal transactions.
+async fn build_fan_out_transactions(
+    network: Network,
+    wallet: &Wallet,
+    reveals: Vec<(Inscription, Vec<TxOut>)>,
+    fee_rate: FeeRate,
+) -> Result<(Transaction, Vec<Transaction>, Vec<RevealTemplate>), String> {
+    let reveal_plans: Vec<RevealPlan> = reveals
+        .into_iter()
+        .map(|(inscription, reveal_outputs)| {
+            RevealPlan::new(
+                network,
+                wallet.schnorr_public_key,
+                None,
+                &[inscription],
+                vec![],
+                reveal_outputs,
+                fee_rate,
+                None,
+            )
+        })
+        .collect();
 
-    let (commit_tx, reveal_tx) = build_inscription_transactions(
-        bitcoin_network,
-        &own_utxos,
-        &dst_address,
-        schnorr_public_key,
-        inscription,
-        &own_public_key,
-        &own_address,
-        key_name,
-        derivation_path,
-        fee_rate,
-    )
-    .await
-    .expect("Should build inscription transactions");
+    let commit_outputs = reveal_plans.iter().map(RevealPlan::commit_output).collect();
+    let commit_tx = build_commit_transaction(wallet, commit_outputs, None, fee_rate).await?;
+    let reveal_templates = reveal_plans.iter().map(RevealPlan::template).collect();
 
-    let commit_tx_bytes = serialize(&commit_tx);
-    print(&format!(
-        "Signed commit transaction: {}",
-        hex::encode(&commit_tx_bytes)
-    ));
+    let mut reveal_txs = vec![];
+    for (vout, reveal_plan) in reveal_plans.into_iter().enumerate() {
+        reveal_txs.push(reveal_plan.sign(wallet, &commit_tx, vout).await);
+    }
 
-    print("Sending commit transaction...");
-    bitcoin_api::send_transaction(network, commit_tx_bytes).await;
-    print("Done");
+    Ok((commit_tx, reveal_txs, reveal_templates))
+}
 
-    let reveal_tx_bytes = serialize(&reveal_tx);
-    print(&format!(
-        "Signed reveal transaction: {}",
-        hex::encode(&reveal_tx_bytes)
-    ));
+// A leaf of the commit taproot tree that lets `public_key` spend the commit
+// output once it is `csv_blocks` blocks old.
+struct RefundLeaf {
+    public_key: XOnlyPublicKey,
+    csv_blocks: u16,
+}
 
-    print("Sending reveal transaction...");
-    bitcoin_api::send_transaction(network, reveal_tx_bytes).await;
-    print("Done");
+impl RefundLeaf {
+    // Returns `<csv_blocks> OP_CSV OP_DROP <public_key> OP_CHECKSIG`.
+    fn script(&self) -> ScriptBuf {
+        Builder::new()
+            .push_int(self.csv_blocks.into())
+            .push_opcode(opcodes::all::OP_CSV)
+            .push_opcode(opcodes::all::OP_DROP)
+            .push_x_only_key(&self.public_key)
+            .push_opcode(opcodes::all::OP_CHECKSIG)
+            .into_script()
+    }
+}
 
-    (commit_tx.txid().encode_hex(), reveal_tx.txid().encode_hex())
+/// A reveal transaction that spends a commit output through the reveal script.
+///
+/// The commit output may be preceded by wallet outputs, e.g. an inscribed
+/// UTXO whose first sat receives the inscriptions.
+struct RevealPlan {
+    reveal_script: ScriptBuf,
+    control_block: ControlBlock,
+    // The refund leaf script and its control block, if any.
+    refund: Option<(ScriptBuf, ControlBlock)>,
+    commit_address: Address,
+    inputs: Vec<(OutPoint, TxOut)>,
+    outputs: Vec<TxOut>,
+    fee: Amount,
 }
 
-async fn build_inscription_transactions(
-    network: Network,
-    own_utxos: &[Utxo],
-    dst_address: &Address,
-    schnorr_public_key: XOnlyPublicKey,
-    inscription: Inscription,
-    own_public_key: &[u8],
-    own_address: &Address,
-    key_name: String,
-    derivation_path: Vec<Vec<u8>>,
-    fee_rate: FeeRate,
-) -> Result<(Transaction, Transaction), String> {
-    let mut builder = Builder::new();
+impl RevealPlan {
+    // Plans the reveal of `inscriptions` through a leaf of the commit taproot
+    // tree that is signed by `schnorr_public_key`. The tree's internal key is
+    // `schnorr_public_key` as well unless another one is given.
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        network: Network,
+        schnorr_public_key: XOnlyPublicKey,
+        internal_key: Option<XOnlyPublicKey>,
+        inscriptions: &[Inscription],
+        inputs: Vec<(OutPoint, TxOut)>,
+        outputs: Vec<TxOut>,
+        fee_rate: FeeRate,
+        refund_leaf: Option<&RefundLeaf>,
+    ) -> Self {
+        let mut builder = Builder::new();
+
+        for inscription in inscriptions {
+            builder = inscription.append_reveal_script_to_builder(builder);
+        }
 
-    builder = inscription.append_reveal_script_to_builder(builder);
+        let secp256k1 = Secp256k1::new();
+
+        builder = builder
+            .push_slice(schnorr_public_key.serialize())
+            .push_opcode(opcodes::all::OP_CHECKSIG);
+
+        let reveal_script = builder.into_script();
+
+        print(&format!("Reveal script: {}", &reveal_script));
+
+        let refund_script = refund_leaf.map(RefundLeaf::script);
+
+        let taproot_builder = match &refund_script {
+            Some(refund_script) => TaprootBuilder::new()
+                .add_leaf(1, reveal_script.clone())
+                .expect("adding leaf should work")
+                .add_leaf(1, refund_script.clone())
+                .expect("adding leaf should work"),
+            None => TaprootBuilder::new()
+                .add_leaf(0, reveal_script.clone())
+                .expect("adding leaf should work"),
+        };
+        let taproot_spend_info = taproot_builder
+            .finalize(&secp256k1, internal_key.unwrap_or(schnorr_public_key))
+            .expect("finalizing taproot builder should work");
+
+        let control_block = taproot_spend_info
+            .control_block(&(reveal_script.clone(), LeafVersion::TapScript))
+            .expect("should compute control block");
+
+        let refund = refund_script.map(|refund_script| {
+            let control_block = taproot_spend_info
+                .control_block(&(refund_script.clone(), LeafVersion::TapScript))
+                .expect("should compute control block");
+            (refund_script, control_block)
+        });
+
+        let commit_address = Address::p2tr_tweaked(taproot_spend_info.output_key(), network);
+
+        let mut reveal_inputs: Vec<OutPoint> =
+            inputs.iter().map(|(outpoint, _)| *outpoint).collect();
+        reveal_inputs.push(OutPoint::null());
+
+        let (_, fee) = build_reveal_transaction(
+            &control_block,
+            fee_rate,
+            reveal_inputs,
+            inputs.len(),
+            outputs.clone(),
+            &reveal_script,
+        );
+
+        Self {
+            reveal_script,
+            control_block,
+            refund,
+            commit_address,
+            inputs,
+            outputs,
+            fee,
+        }
+    }
 
-    print(&format!(
-        "Reveal script: {}",
-        &builder.clone().into_script()
-    ));
+    // Restores the plan of a reveal transaction that spends an output paying
+    // to `commit_address`, preceded by the outputs of the wallet at
+    // `wallet_address` that the template lists.
+    fn from_template(
+        template: RevealTemplate,
+        commit_address: Address,
+        wallet_address: &Address,
+    ) -> Self {
+        let inputs = template
+            .inputs
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(outpoint, value)| {
+                (
+                    OutPoint::from_str(&outpoint).expect("outpoint should be valid"),
+                    TxOut {
+                        script_pubkey: wallet_address.script_pubkey(),
+                        value: Amount::from_sat(value),
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            reveal_script: ScriptBuf::from_bytes(template.reveal_script),
+            control_block: ControlBlock::decode(&template.control_block)
+                .expect("control block should be valid"),
+            refund: None,
+            commit_address,
+            inputs,
+            outputs: deserialize(&template.outputs).expect("reveal outputs should be valid"),
+            fee: Amount::from_sat(template.fee),
+        }
+    }
 
-    let secp256k1 = Secp256k1::new();
+    // Returns the template of the reveal transaction. The preceding inputs
+    // have to be outputs of the wallet.
+    fn template(&self) -> RevealTemplate {
+        let inputs: Vec<(String, u64)> = self
+            .inputs
+            .iter()
+            .map(|(outpoint, output)| (outpoint.to_string(), output.value.to_sat()))
+            .collect();
+
+        RevealTemplate {
+            reveal_script: self.reveal_script.to_bytes(),
+            control_block: self.control_block.serialize(),
+            outputs: serialize(&self.outputs),
+            fee: self.fee.to_sat(),
+            inputs: Some(inputs).filter(|inputs| !inputs.is_empty()),
+            commit_vout: None,
+        }
+    }
 
-    builder = builder
-        .push_slice(&schnorr_public_key.serialize())
-        .push_opcode(opcodes::all::OP_CHECKSIG);
+    // The commit output has to cover the reveal outputs and the reveal fee,
+    // except for the part covered by the preceding inputs.
+    fn commit_output(&self) -> TxOut {
+        let input_value = self.inputs.iter().map(|(_, o)| o.value).sum::<Amount>();
 
-    let reveal_script = builder.into_script();
+        TxOut {
+            script_pubkey: self.commit_address.script_pubkey(),
+            value: self.outputs.iter().map(|o| o.value).sum::<Amount>() + self.fee - input_value,
+        }
+    }
 
-    print(&format!("Reveal script: {}", &reveal_script));
+    // Signs the reveal transaction spending output `vout` of the commit transaction.
+    async fn sign(self, wallet: &Wallet, commit_tx: &Transaction, vout: usize) -> Transaction {
+        let commit_input_index = self.inputs.len();
+        let commit_outpoint = OutPoint {
+            txid: commit_tx.txid(),
+            vout: vout.try_into().unwrap(),
+        };
+
+        let mut reveal_tx = self
+            .sign_commit_input(
+                wallet.key_name.clone(),
+                wallet.derivation_path.clone(),
+                commit_outpoint,
+                commit_tx.output[vout].clone(),
+            )
+            .await;
+
+        // The preceding inputs are legacy P2PKH inputs of the wallet, whose
+        // signatures don't commit to the witness of the commit input.
+        for index in 0..commit_input_index {
+            sign_input_p2pkh(wallet, &mut reveal_tx, index).await;
+        }
 
-    let taproot_spend_info = TaprootBuilder::new()
-        .add_leaf(0, reveal_script.clone())
-        .expect("adding leaf should work")
-        .finalize(&secp256k1, schnorr_public_key)
-        .expect("finalizing taproot builder should work");
+        reveal_tx
+    }
 
-    let control_block = taproot_spend_info
-        .control_block(&(reveal_script.clone(), LeafVersion::TapScript))
-        .expect("should compute control block");
+    // Builds the reveal transaction and signs its input spending the commit
+    // output with the Schnorr key at the given derivation path.
+    async fn sign_commit_input(
+        self,
+        key_name: String,
+        derivation_path: Vec<Vec<u8>>,
+        commit_outpoint: OutPoint,
+        commit_output: TxOut,
+    ) -> Transaction {
+        let commit_input_index = self.inputs.len();
+
+        let mut prevouts: Vec<TxOut> = self.inputs.iter().map(|(_, o)| o.clone()).collect();
+        prevouts.push(commit_output);
+
+        let mut reveal_inputs: Vec<OutPoint> =
+            self.inputs.iter().map(|(outpoint, _)| *outpoint).collect();
+        reveal_inputs.push(commit_outpoint);
+
+        let mut reveal_tx = Transaction {
+            input: reveal_inputs
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Builder::new().into_script(),
+                    witness: Witness::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                })
+                .collect(),
+            output: self.outputs,
+            lock_time: LockTime::ZERO,
+            version: Version(2),
+        };
+
+        let mut sighasher = SighashCache::new(&mut reveal_tx);
+        let sighash = sighasher
+            .taproot_script_spend_signature_hash(
+                commit_input_index,
+                &sighash::Prevouts::All(&prevouts),
+                TapLeafHash::from_script(&self.reveal_script, LeafVersion::TapScript),
+                TapSighashType::Default,
+            )
+            .expect("failed to construct sighash");
+
+        let sig = schnorr_api::sign_with_schnorr(
+            key_name,
+            derivation_path,
+            sighash.to_byte_array().to_vec(),
+        )
+        .await;
 
-    let commit_tx_address = Address::p2tr_tweaked(taproot_spend_info.output_key(), network);
+        let witness = sighasher
+            .witness_mut(commit_input_index)
+            .expect("getting mutable witness reference should work");
 
-    let mut reveal_inputs = vec![OutPoint::null()];
+        witness.push(
+            Signature {
+                sig: schnorr::Signature::from_slice(sig.as_slice())
+                    .expect("should parse signature"),
+                hash_ty: TapSighashType::Default,
+            }
+            .to_vec(),
+        );
 
-    // Amount should be value of the UTXO minus the fees for commit and reveal transactions
-    let mut reveal_outputs = vec![TxOut {
-        script_pubkey: dst_address.script_pubkey(),
-        value: Amount::from_sat(0),
-    }];
+        witness.push(self.reveal_script);
+        witness.push(self.control_block.serialize());
 
-    let commit_input_index = 0;
+        reveal_tx
+    }
+}
 
-    let (_, reveal_fee) = build_reveal_transaction(
-        &control_block,
-        fee_rate,
-        reveal_inputs.clone(),
-        commit_input_index,
-        reveal_outputs.clone(),
-        &reveal_script,
-    );
+// Builds and signs a transaction that funds the given commit outputs from
+// the wallet and sends the change back to the wallet.
+//
+// If a sat point is given, its UTXO is spent first and the sats preceding
+// the sat are sent back to the wallet, so that the sat becomes the first sat
+// of the first commit output.
+async fn build_commit_transaction(
+    wallet: &Wallet,
+    commit_outputs: Vec<TxOut>,
+    sat_point: Option<SatPoint>,
+    fee_rate: FeeRate,
+) -> Result<Transaction, String> {
+    let commit_value = commit_outputs.iter().map(|o| o.value).sum::<Amount>();
+    let reveal_count = commit_outputs.len();
+
+    let mut unsigned_commit_tx = Transaction {
+        input: vec![],
+        output: commit_outputs,
+        lock_time: LockTime::ZERO,
+        version: Version(2),
+    };
+    unsigned_commit_tx.output.push(TxOut {
+        script_pubkey: wallet.address.script_pubkey(),
+        value: Amount::ZERO,
+    });
 
     // Select which UTXOs to spend. We naively spend the oldest available UTXOs,
-    // even if they were previously spent in a transaction. This isn't a
-    // problem as long as at most one transaction is created per block and
-    // we're using min_confirmations of 1.
-    let mut utxos_to_spend = vec![];
-    let mut total_spent = 0;
-    for utxo in own_utxos.iter().rev() {
-        total_spent += utxo.value;
-        utxos_to_spend.push(utxo);
-    }
+    // and the unconfirmed change of the canister's transactions last. The
+    // UTXOs reserved for transactions that haven't confirmed yet aren't among
+    // the wallet's UTXOs.
+    let mut utxos: Vec<&Utxo> = wallet.utxos.iter().rev().collect();
+
+    let mut padding = Amount::ZERO;
+    if let Some(sat_point) = sat_point {
+        let position = utxos
+            .iter()
+            .position(|utxo| {
+                utxo.outpoint.txid == sat_point.outpoint.txid.as_byte_array()
+                    && utxo.outpoint.vout == sat_point.outpoint.vout
+            })
+            .ok_or_else(|| format!("Output {} is not held by the canister", sat_point.outpoint))?;
+        let utxo = utxos.remove(position);
+
+        if sat_point.offset >= utxo.value {
+            return Err(format!(
+                "Offset {} is beyond the {} sats of output {}",
+                sat_point.offset, utxo.value, sat_point.outpoint
+            ));
+        }
+
+        padding = Amount::from_sat(sat_point.offset);
+        if padding > Amount::ZERO {
+            let padding_output = TxOut {
+                script_pubkey: wallet.address.script_pubkey(),
+                value: padding,
+            };
+            if padding < padding_output.script_pubkey.dust_value() {
+                return Err(format!(
+                    "Offset {} is too small to send the preceding sats back to the wallet",
+                    sat_point.offset
+                ));
+            }
+            unsigned_commit_tx.output.insert(0, padding_output);
+        }
 
-    let total_spent = Amount::from_sat(total_spent);
+        utxos.insert(0, utxo);
+    }
 
-    let inputs: Vec<TxIn> = utxos_to_spend
-        .into_iter()
-        .map(|utxo| TxIn {
-            previous_output: OutPoint {
-                txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
-                vout: utxo.outpoint.vout,
-            },
+    let mut input_values = vec![];
+    let mut total_spent = Amount::ZERO;
+    let mut commit_fee = Amount::ZERO;
+    let mut skipped = Amount::ZERO;
+    for utxo in utxos {
+        if total_spent >= padding + commit_value + commit_fee {
+            break;
+        }
+        // The reveals must be able to join the chain of unconfirmed
+        // transactions in the mempool as well.
+        if !fits_chain_limits(&unsigned_commit_tx, utxo, reveal_count) {
+            skipped += Amount::from_sat(utxo.value);
+            continue;
+        }
+        total_spent += Amount::from_sat(utxo.value);
+        input_values.push(utxo.value);
+        unsigned_commit_tx.input.push(TxIn {
+            previous_output: to_outpoint(utxo),
             sequence: Sequence::ZERO,
             witness: Witness::new(),
             script_sig: Script::new().into(),
-        })
-        .collect();
+        });
+        commit_fee = estimate_p2pkh_fee(&unsigned_commit_tx, fee_rate);
+    }
 
-    let mut unsigned_commit_tx = Transaction {
-        input: inputs,
-        output: vec![TxOut {
-            script_pubkey: commit_tx_address.script_pubkey(),
-            value: total_spent,
-        }],
-        lock_time: LockTime::ZERO,
-        version: Version(2),
-    };
+    if total_spent < padding + commit_value + commit_fee {
+        return Err(funding_error(
+            total_spent,
+            skipped,
+            padding + commit_value + commit_fee,
+        ));
+    }
 
-    // We assume that we spend a single P2PKH output
-    let sig_vbytes = 73;
+    // Send the change back to our own address unless it would be dust, in
+    // which case it is left to the miners.
+    let change = total_spent - padding - commit_value - commit_fee;
+    let change_output = unsigned_commit_tx.output.last_mut().unwrap();
+    if change < change_output.script_pubkey.dust_value() {
+        unsigned_commit_tx.output.pop();
+    } else {
+        change_output.value = change;
+    }
 
-    let commit_fee = fee_rate
-        .fee_vb(unsigned_commit_tx.vsize() as u64 + sig_vbytes)
-        .unwrap();
+    if let Some(sat_point) = sat_point {
+        let output_values: Vec<u64> = unsigned_commit_tx
+            .output
+            .iter()
+            .map(|o| o.value.to_sat())
+            .collect();
+        let commit_vout = if padding > Amount::ZERO { 1 } else { 0 };
+
+        if sat_point::flow(&input_values, &output_values, sat_point.offset)
+            != Some((commit_vout, 0))
+        {
+            return Err(format!(
+                "Sat {} would not be the first sat of the commit output",
+                sat_point
+            ));
+        }
+    }
 
-    unsigned_commit_tx.output[0].value = total_spent - commit_fee;
+    wallet.reserve(&unsigned_commit_tx);
 
-    let commit_tx = sign_transaction_p2pkh(
-        own_public_key,
-        own_address,
+    Ok(sign_transaction_p2pkh(
+        &wallet.public_key,
+        &wallet.address,
         unsigned_commit_tx,
-        key_name.clone(),
-        derivation_path.clone(),
+        wallet.key_name.clone(),
+        wallet.derivation_path.clone(),
         ecdsa_api::sign_with_ecdsa,
     )
-    .await;
-
-    let (vout, _commit_output) = commit_tx
-        .output
-        .iter()
-        .enumerate()
-        .find(|(_vout, output)| output.script_pubkey == commit_tx_address.script_pubkey())
-        .expect("should find sat commit/inscription output");
+    .await)
+}
 
-    reveal_inputs[commit_input_index] = OutPoint {
-        txid: commit_tx.txid(),
-        vout: vout.try_into().unwrap(),
-    };
+// Builds and signs a transaction that spends the given outputs of the
+// wallet, funds the given outputs from the wallet's other outputs, and sends
+// the change back to the wallet.
+async fn build_wallet_transaction(
+    wallet: &Wallet,
+    inputs: Vec<(OutPoint, u64)>,
+    outputs: Vec<TxOut>,
+    fee_rate: FeeRate,
+) -> Result<Transaction, String> {
+    let unsigned_tx = fund_transaction(wallet, inputs, outputs, fee_rate)?;
+
+    Ok(sign_transaction_p2pkh(
+        &wallet.public_key,
+        &wallet.address,
+        unsigned_tx,
+        wallet.key_name.clone(),
+        wallet.derivation_path.clone(),
+        ecdsa_api::sign_with_ecdsa,
+    )
+    .await)
+}
 
-    reveal_outputs = vec![TxOut {
-        script_pubkey: dst_address.script_pubkey(),
-        value: total_spent - commit_fee - reveal_fee,
-    }];
+// Builds an unsigned transaction that spends the given P2PKH outputs, funds
+// the given outputs from the wallet's outputs, and sends the change back to
+// the wallet.
+fn fund_transaction(
+    wallet: &Wallet,
+    inputs: Vec<(OutPoint, u64)>,
+    outputs: Vec<TxOut>,
+    fee_rate: FeeRate,
+) -> Result<Transaction, String> {
+    let output_value = outputs.iter().map(|o| o.value).sum::<Amount>();
 
-    let mut reveal_tx = Transaction {
-        input: reveal_inputs
-            .iter()
-            .map(|outpoint| TxIn {
-                previous_output: *outpoint,
-                script_sig: Builder::new().into_script(),
-                witness: Witness::new(),
-                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-            })
-            .collect(),
-        output: reveal_outputs,
+    let mut total_spent = Amount::ZERO;
+    let mut unsigned_tx = Transaction {
+        input: vec![],
+        output: outputs,
         lock_time: LockTime::ZERO,
         version: Version(2),
     };
+    for (outpoint, value) in inputs {
+        total_spent += Amount::from_sat(value);
+        unsigned_tx.input.push(TxIn {
+            previous_output: outpoint,
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+            script_sig: Script::new().into(),
+        });
+    }
+    unsigned_tx.output.push(TxOut {
+        script_pubkey: wallet.address.script_pubkey(),
+        value: Amount::ZERO,
+    });
+
+    let mut fee = estimate_p2pkh_fee(&unsigned_tx, fee_rate);
+    let mut skipped = Amount::ZERO;
+    for utxo in wallet.utxos.iter().rev() {
+        if total_spent >= output_value + fee {
+            break;
+        }
+        if !fits_chain_limits(&unsigned_tx, utxo, 0) {
+            skipped += Amount::from_sat(utxo.value);
+            continue;
+        }
+        total_spent += Amount::from_sat(utxo.value);
+        unsigned_tx.input.push(TxIn {
+            previous_output: to_outpoint(utxo),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+            script_sig: Script::new().into(),
+        });
+        fee = estimate_p2pkh_fee(&unsigned_tx, fee_rate);
+    }
 
-    let mut sighasher = SighashCache::new(&mut reveal_tx);
-    let sighash = sighasher
-        .taproot_script_spend_signature_hash(
-            commit_input_index,
-            &sighash::Prevouts::All(commit_tx.output.as_slice()),
-            TapLeafHash::from_script(&reveal_script, LeafVersion::TapScript),
-            TapSighashType::Default,
-        )
-        .expect("failed to construct sighash");
+    if total_spent < output_value + fee {
+        return Err(funding_error(total_spent, skipped, output_value + fee));
+    }
 
-    let sig = schnorr_api::sign_with_schnorr(key_name, derivation_path, sighash.to_byte_array().to_vec()).await;
+    // Send the change back to our own address unless it would be dust, in
+    // which case it is left to the miners.
+    let change = total_spent - output_value - fee;
+    let change_output = unsigned_tx.output.last_mut().unwrap();
+    if change < change_output.script_pubkey.dust_value() {
+        unsigned_tx.output.pop();
+    } else {
+        change_output.value = change;
+    }
 
-    let witness = sighasher
-        .witness_mut(commit_input_index)
-        .expect("getting mutable witness reference should work");
+    wallet.reserve(&unsigned_tx);
 
-    witness.push(
-        Signature {
-            sig: schnorr::Signature::from_slice(sig.as_slice()).expect("should parse signature"),
-            hash_ty: TapSighashType::Default,
-        }
-        .to_vec(),
-    );
+    Ok(unsigned_tx)
+}
 
-    witness.push(reveal_script);
-    witness.push(&control_block.serialize());
+// Returns whether `transaction` stays within the mempool chain limits when it
+// also spends `utxo`, together with `children` transactions spending its
+// outputs.
+fn fits_chain_limits(transaction: &Transaction, utxo: &Utxo, children: usize) -> bool {
+    let mut outpoints: Vec<OutPoint> = transaction
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .collect();
+    outpoints.push(to_outpoint(utxo));
+    utxo_cache::fits_chain_limits(&outpoints, children)
+}
 
-    Ok((commit_tx, reveal_tx))
+// Returns the error for a transaction that can't be funded. If the UTXOs
+// skipped because of the mempool chain limits would have funded it, the error
+// tells that waiting for confirmations helps rather than reporting
+// insufficient funds.
+fn funding_error(available: Amount, skipped: Amount, required: Amount) -> String {
+    if available + skipped >= required {
+        format!(
+            "Chain limit reached: {} available, {} required; {} of unconfirmed change can't be spent until more of the canister's transactions confirm",
+            available, required, skipped
+        )
+    } else {
+        format!(
+            "Insufficient funds: {} available, {} required",
+            available, required
+        )
+    }
 }
 
 fn build_reveal_transaction(
@@ -349,7 +3115,8 @@ fn build_reveal_transaction(
                 txin.witness.push(script);
                 txin.witness.push(&control_block.serialize());
             } else {
-                txin.witness = Witness::from_slice(&[&[0; SCHNORR_SIGNATURE_SIZE]]);
+                // add dummy signature for the wallet's P2PKH inputs
+                txin.script_sig = ScriptBuf::from_bytes(vec![0; P2PKH_SCRIPT_SIG_SIZE]);
             }
         }
 
@@ -361,6 +3128,97 @@ fn build_reveal_transaction(
     (reveal_tx, fee)
 }
 
+pub(crate) fn to_outpoint(utxo: &Utxo) -> OutPoint {
+    OutPoint {
+        txid: Txid::from_raw_hash(Hash::from_slice(&utxo.outpoint.txid).unwrap()),
+        vout: utxo.outpoint.vout,
+    }
+}
+
+// Returns the NUMS point tweaked by `tweak`, i.e. `H + tweak * G`. Nobody can
+// spend a taproot output with this internal key by key path, which anyone
+// who knows the tweak can verify.
+fn nums_internal_key(tweak: &str) -> Result<XOnlyPublicKey, String> {
+    let invalid_tweak = || format!("Invalid tweak {}", tweak);
+    let tweak: [u8; 32] = hex::decode(tweak)
+        .ok()
+        .and_then(|tweak| tweak.try_into().ok())
+        .ok_or_else(invalid_tweak)?;
+    let tweak = Scalar::from_be_bytes(tweak).map_err(|_| invalid_tweak())?;
+
+    let nums_point = XOnlyPublicKey::from_str(NUMS_POINT).unwrap();
+    let (internal_key, _parity) = nums_point
+        .add_tweak(&Secp256k1::verification_only(), &tweak)
+        .map_err(|_| invalid_tweak())?;
+
+    Ok(internal_key)
+}
+
+// Parses a hex-encoded x-only or compressed public key.
+fn parse_x_only_public_key(public_key: &str) -> Result<XOnlyPublicKey, String> {
+    XOnlyPublicKey::from_str(public_key)
+        .or_else(|_| PublicKey::from_str(public_key).map(XOnlyPublicKey::from))
+        .map_err(|_| format!("Invalid public key {}", public_key))
+}
+
+// Parses an address and checks that it belongs to the given network.
+fn parse_address(address: &str, network: Network) -> Result<Address, String> {
+    Address::from_str(address)
+        .map_err(|e| format!("Invalid address {}: {}", address, e))?
+        .require_network(network)
+        .map_err(|e| format!("Invalid address {}: {}", address, e))
+}
+
+// Estimates the fee of a transaction whose inputs all spend P2PKH outputs
+// and are not signed yet.
+fn estimate_p2pkh_fee(transaction: &Transaction, fee_rate: FeeRate) -> Amount {
+    let script_sig_vbytes = transaction.input.len() * P2PKH_SCRIPT_SIG_SIZE;
+
+    fee_rate
+        .fee_vb((transaction.vsize() + script_sig_vbytes) as u64)
+        .unwrap()
+}
+
+// Signs the input at `index`, which spends an output of the wallet's P2PKH address.
+async fn sign_input_p2pkh(wallet: &Wallet, transaction: &mut Transaction, index: usize) {
+    sign_input_p2pkh_with_key(
+        wallet.key_name.clone(),
+        wallet.derivation_path.clone(),
+        &wallet.public_key,
+        &wallet.address,
+        transaction,
+        index,
+    )
+    .await;
+}
+
+// Signs the input at `index`, which spends an output of the P2PKH `address`
+// of the key at the given derivation path.
+async fn sign_input_p2pkh_with_key(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    public_key: &[u8],
+    address: &Address,
+    transaction: &mut Transaction,
+    index: usize,
+) {
+    let sighash = SighashCache::new(&*transaction)
+        .legacy_signature_hash(index, &address.script_pubkey(), SIG_HASH_TYPE.to_u32())
+        .unwrap();
+
+    let signature =
+        ecdsa_api::sign_with_ecdsa(key_name, derivation_path, sighash.as_byte_array().to_vec())
+            .await;
+
+    let mut sig_with_hashtype = sec1_to_der(signature);
+    sig_with_hashtype.push(SIG_HASH_TYPE.to_u32() as u8);
+
+    transaction.input[index].script_sig = Builder::new()
+        .push_slice(PushBytesBuf::try_from(sig_with_hashtype).unwrap())
+        .push_slice(PushBytesBuf::try_from(public_key.to_vec()).unwrap())
+        .into_script();
+}
+
 // Sign a P2PKH bitcoin transaction.
 //
 // IMPORTANT: This method is for demonstration purposes only and it only
@@ -482,3 +3340,155 @@ fn sec1_to_der(sec1_signature: Vec<u8>) -> Vec<u8> {
     .flatten()
     .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::sha256;
+    use bitcoin::secp256k1::constants::{GENERATOR_X, GENERATOR_Y};
+    use ic_cdk::api::management_canister::bitcoin::Outpoint;
+
+    #[test]
+    fn nums_point_is_the_bip341_point() {
+        // BIP-341 takes the x coordinate of the NUMS point from the SHA-256
+        // hash of the uncompressed encoding of the generator.
+        let generator = [&[0x04][..], &GENERATOR_X, &GENERATOR_Y].concat();
+
+        assert_eq!(
+            NUMS_POINT,
+            sha256::Hash::hash(&generator)
+                .to_byte_array()
+                .encode_hex::<String>()
+        );
+        assert!(XOnlyPublicKey::from_str(NUMS_POINT).is_ok());
+    }
+
+    #[test]
+    fn nums_internal_key_tweaks_the_nums_point() {
+        assert_eq!(
+            nums_internal_key(&"00".repeat(32)).unwrap().to_string(),
+            NUMS_POINT
+        );
+        assert_ne!(
+            nums_internal_key(&format!("{}01", "00".repeat(31)))
+                .unwrap()
+                .to_string(),
+            NUMS_POINT
+        );
+        assert_eq!(nums_internal_key("00"), Err("Invalid tweak 00".to_string()));
+        assert_eq!(
+            nums_internal_key(&"ff".repeat(32)),
+            Err(format!("Invalid tweak {}", "ff".repeat(32)))
+        );
+    }
+
+    fn utxo(txid: Txid, vout: u32, value: u64) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: txid.to_byte_array().to_vec(),
+                vout,
+            },
+            value,
+            height: 0,
+        }
+    }
+
+    fn transaction(inputs: &[&Utxo], outputs: Vec<(&Address, u64)>) -> Transaction {
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: to_outpoint(utxo),
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|(address, value)| TxOut {
+                    script_pubkey: address.script_pubkey(),
+                    value: Amount::from_sat(value),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn wallet_flow_counts_plain_bitcoin() {
+        let wallet = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")
+            .unwrap()
+            .assume_checked();
+        let other = Address::from_str("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")
+            .unwrap()
+            .assume_checked();
+        let plain = utxo(Txid::from_byte_array([1; 32]), 0, 10_000);
+        let inscribed = utxo(Txid::from_byte_array([1; 32]), 1, 546);
+        let foreign = utxo(Txid::from_byte_array([1; 32]), 2, 5_000);
+        inscription_ledger::insert(
+            to_outpoint(&inscribed),
+            InscribedUtxo {
+                address: wallet.to_string(),
+                inscriptions: vec![],
+            },
+        );
+
+        let transaction = transaction(
+            &[&inscribed, &plain, &foreign],
+            vec![(&wallet, 546), (&other, 3_000), (&wallet, 6_000)],
+        );
+
+        // The inscribed input and the output carrying it are neither spent
+        // nor returned, and inputs outside the wallet's UTXOs aren't spent.
+        assert_eq!(
+            wallet_flow(
+                &wallet,
+                &[plain.clone(), inscribed.clone()],
+                &transaction,
+                &[0]
+            ),
+            (10_000, 6_000)
+        );
+        assert_eq!(
+            wallet_flow(&wallet, &[plain, inscribed], &transaction, &[]),
+            (10_000, 6_546)
+        );
+    }
+
+    #[test]
+    fn returned_reveal_value_skips_inscribed_outputs() {
+        let wallet = Address::from_str("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2")
+            .unwrap()
+            .assume_checked();
+        let other = Address::from_str("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")
+            .unwrap()
+            .assume_checked();
+        let reveal_tx = transaction(&[], vec![(&wallet, 546), (&other, 1_000), (&wallet, 2_000)]);
+        inscription_ledger::insert(
+            OutPoint {
+                txid: reveal_tx.txid(),
+                vout: 0,
+            },
+            InscribedUtxo {
+                address: wallet.to_string(),
+                inscriptions: vec![],
+            },
+        );
+
+        assert_eq!(
+            returned_reveal_value(&wallet, reveal_tx.txid(), &reveal_tx.output),
+            2_000
+        );
+    }
+
+    #[test]
+    fn chain_limits_are_reported_apart_from_insufficient_funds() {
+        let sat = Amount::from_sat;
+
+        assert!(funding_error(sat(1_000), sat(0), sat(2_000)).starts_with("Insufficient funds"));
+        assert!(funding_error(sat(1_000), sat(500), sat(2_000)).starts_with("Insufficient funds"));
+        assert!(
+            funding_error(sat(1_000), sat(1_000), sat(2_000)).starts_with("Chain limit reached")
+        );
+    }
+}
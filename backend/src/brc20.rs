@@ -0,0 +1,418 @@
+// Builds BRC-20 payloads following https://layer1.gitbook.io/layer1-foundation/protocols/brc-20/documentation
+
+use crate::metaprotocol::Metaprotocol;
+use crate::types::Brc20Deployment;
+use crate::{Memory, BRC20_DEPLOYMENTS_MEMORY_ID, MEMORY_MANAGER};
+use ic_stable_structures::StableBTreeMap;
+use serde::Deserialize;
+
+use std::cell::RefCell;
+
+/// The content type of BRC-20 inscriptions.
+pub const CONTENT_TYPE: &str = "text/plain;charset=utf-8";
+
+/// The length of a ticker in bytes.
+const TICKER_LENGTH: usize = 4;
+/// The maximum number of decimals of a token.
+const MAX_DECIMALS: u8 = 18;
+
+/// The name of the BRC-20 metaprotocol.
+pub const METAPROTOCOL: &str = "brc-20";
+
+// A BRC-20 operation as found in the body of an inscription. All numbers are
+// given as strings.
+#[derive(Deserialize)]
+struct Operation {
+    p: String,
+    op: String,
+    tick: String,
+    max: Option<String>,
+    lim: Option<String>,
+    dec: Option<String>,
+    amt: Option<String>,
+}
+
+thread_local! {
+    // The tokens deployed through this canister, keyed by lowercase ticker.
+    static DEPLOYMENTS: RefCell<StableBTreeMap<String, Brc20Deployment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BRC20_DEPLOYMENTS_MEMORY_ID)),
+        ));
+}
+
+/// The BRC-20 metaprotocol, which checks that an inscription holds a
+/// well-formed deploy, mint or transfer operation.
+pub struct Brc20;
+
+impl Metaprotocol for Brc20 {
+    fn name(&self) -> &str {
+        METAPROTOCOL
+    }
+
+    fn validate(
+        &self,
+        content_type: Option<&[u8]>,
+        body: Option<&[u8]>,
+        _metadata: Option<&[u8]>,
+    ) -> Result<(), String> {
+        let content_type = content_type
+            .and_then(|content_type| std::str::from_utf8(content_type).ok())
+            .unwrap_or_default();
+        if !content_type.starts_with("text/plain") && !content_type.starts_with("application/json")
+        {
+            return Err(format!(
+                "BRC-20 inscriptions must be text, not {}",
+                content_type
+            ));
+        }
+
+        let operation = parse_operation(body)?;
+
+        let required = |field: Option<String>, name: &str| {
+            field.ok_or_else(|| format!("BRC-20 {} operation lacks {}", operation.op, name))
+        };
+        match operation.op.as_str() {
+            "deploy" => {
+                let dec = operation
+                    .dec
+                    .map(|dec| {
+                        dec.parse()
+                            .map_err(|_| format!("Invalid decimals: {}", dec))
+                    })
+                    .transpose()?;
+                let max = required(operation.max, "max")?;
+                deploy(&operation.tick, &max, operation.lim.as_deref(), dec)?;
+            }
+            "mint" => {
+                let amt = required(operation.amt, "amt")?;
+                validate_mint(&operation.tick, &amt)?;
+            }
+            "transfer" => {
+                let amt = required(operation.amt, "amt")?;
+                transfer(&operation.tick, &amt)?;
+            }
+            op => return Err(format!("Unknown BRC-20 operation {}", op)),
+        }
+
+        Ok(())
+    }
+
+    fn record(&self, _content_type: Option<&[u8]>, body: Option<&[u8]>, _metadata: Option<&[u8]>) {
+        let Ok(operation) = parse_operation(body) else {
+            return;
+        };
+        if operation.op != "deploy" || validate_ticker(&operation.tick).is_err() {
+            return;
+        }
+        let Some(max) = operation.max else {
+            return;
+        };
+        let Ok(dec) = operation.dec.map(|dec| dec.parse()).transpose() else {
+            return;
+        };
+
+        record_deployment(&operation.tick, &max, operation.lim.as_deref(), dec);
+    }
+}
+
+// Parses the BRC-20 operation in the body of an inscription.
+fn parse_operation(body: Option<&[u8]>) -> Result<Operation, String> {
+    let operation: Operation = serde_json::from_slice(body.unwrap_or_default())
+        .map_err(|e| format!("Invalid BRC-20 operation: {}", e))?;
+    if operation.p != METAPROTOCOL {
+        return Err(format!("Unknown protocol {}", operation.p));
+    }
+
+    Ok(operation)
+}
+
+/// Returns the payload of a deploy operation.
+pub fn deploy(tick: &str, max: &str, lim: Option<&str>, dec: Option<u8>) -> Result<String, String> {
+    validate_ticker(tick)?;
+
+    // Indexers only honor the first deployment of a ticker.
+    if is_deployed(tick) {
+        return Err(format!("Ticker {} has already been deployed", tick));
+    }
+
+    let decimals = dec.unwrap_or(MAX_DECIMALS);
+    if decimals > MAX_DECIMALS {
+        return Err(format!("Decimals must not exceed {}", MAX_DECIMALS));
+    }
+
+    let max_value = parse_amount(max, decimals)?;
+    if let Some(lim) = lim {
+        if parse_amount(lim, decimals)? > max_value {
+            return Err("Mint limit must not exceed the maximum supply".to_string());
+        }
+    }
+
+    let mut payload = format!(
+        r#"{{"p":"brc-20","op":"deploy","tick":"{}","max":"{}""#,
+        tick, max
+    );
+    if let Some(lim) = lim {
+        payload.push_str(&format!(r#","lim":"{}""#, lim));
+    }
+    if let Some(dec) = dec {
+        payload.push_str(&format!(r#","dec":"{}""#, dec));
+    }
+    payload.push('}');
+
+    Ok(payload)
+}
+
+/// Remembers a deployed token so that later mints can be checked against it.
+/// A deployment that was recorded earlier, e.g. by a concurrent deploy of the
+/// same ticker, is kept.
+pub fn record_deployment(tick: &str, max: &str, lim: Option<&str>, dec: Option<u8>) {
+    let dec = dec.unwrap_or(MAX_DECIMALS);
+    // The amounts have been validated when building the deploy payload.
+    let lim = parse_amount(lim.unwrap_or(max), dec).expect("amount should be valid");
+
+    DEPLOYMENTS.with(|d| {
+        let mut deployments = d.borrow_mut();
+        if !deployments.contains_key(&tick.to_lowercase()) {
+            deployments.insert(tick.to_lowercase(), Brc20Deployment { lim, dec });
+        }
+    });
+}
+
+/// Returns the payload of a mint operation.
+///
+/// Only tokens deployed through this canister can be minted, as the mint
+/// limit is needed to check the amount.
+pub fn mint(tick: &str, amt: &str) -> Result<String, String> {
+    validate_ticker(tick)?;
+    if !is_deployed(tick) {
+        return Err(format!(
+            "Ticker {} was not deployed through this canister",
+            tick
+        ));
+    }
+    validate_mint(tick, amt)?;
+
+    Ok(format!(
+        r#"{{"p":"brc-20","op":"mint","tick":"{}","amt":"{}"}}"#,
+        tick, amt
+    ))
+}
+
+/// Returns the payload of a transfer operation.
+pub fn transfer(tick: &str, amt: &str) -> Result<String, String> {
+    validate_ticker(tick)?;
+
+    let decimals = DEPLOYMENTS
+        .with(|d| d.borrow().get(&tick.to_lowercase()).map(|d| d.dec))
+        .unwrap_or(MAX_DECIMALS);
+    parse_amount(amt, decimals)?;
+
+    Ok(format!(
+        r#"{{"p":"brc-20","op":"transfer","tick":"{}","amt":"{}"}}"#,
+        tick, amt
+    ))
+}
+
+// Checks the amount of a mint operation. The amount of a token deployed
+// through this canister must not exceed its mint limit, while only the syntax
+// of the amount can be checked for other tokens.
+fn validate_mint(tick: &str, amt: &str) -> Result<(), String> {
+    validate_ticker(tick)?;
+
+    match DEPLOYMENTS.with(|d| d.borrow().get(&tick.to_lowercase())) {
+        Some(deployment) => {
+            if parse_amount(amt, deployment.dec)? > deployment.lim {
+                return Err("Amount must not exceed the mint limit".to_string());
+            }
+        }
+        None => {
+            parse_amount(amt, MAX_DECIMALS)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Returns whether a token was deployed through this canister.
+fn is_deployed(tick: &str) -> bool {
+    DEPLOYMENTS.with(|d| d.borrow().contains_key(&tick.to_lowercase()))
+}
+
+fn validate_ticker(tick: &str) -> Result<(), String> {
+    if tick.len() != TICKER_LENGTH {
+        return Err(format!("Ticker must be {} bytes long", TICKER_LENGTH));
+    }
+
+    // Rejecting characters that would need escaping keeps the payload canonical.
+    if tick
+        .chars()
+        .any(|c| c == '"' || c == '\\' || c.is_control())
+    {
+        return Err(
+            "Ticker must not contain quotes, backslashes or control characters".to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+// Parses a positive decimal amount into a fixed point number with 18 decimals.
+fn parse_amount(amount: &str, decimals: u8) -> Result<u128, String> {
+    let invalid = || format!("Invalid amount: {}", amount);
+
+    let (integer, fraction) = match amount.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (amount, ""),
+    };
+
+    if integer.is_empty()
+        || !integer.bytes().all(|b| b.is_ascii_digit())
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+        || (amount.contains('.') && fraction.is_empty())
+    {
+        return Err(invalid());
+    }
+
+    if fraction.len() > decimals as usize {
+        return Err(format!(
+            "Amount {} has more than {} decimals",
+            amount, decimals
+        ));
+    }
+
+    let integer: u64 = integer.parse().map_err(|_| invalid())?;
+    let fraction: u128 = format!("{:0<width$}", fraction, width = MAX_DECIMALS as usize)
+        .parse()
+        .map_err(|_| invalid())?;
+
+    let value = integer as u128 * 10u128.pow(MAX_DECIMALS as u32) + fraction;
+    if value == 0 {
+        return Err("Amount must be greater than zero".to_string());
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONE: u128 = 10u128.pow(MAX_DECIMALS as u32);
+
+    fn validate(body: &str) -> Result<(), String> {
+        Brc20.validate(Some(CONTENT_TYPE.as_bytes()), Some(body.as_bytes()), None)
+    }
+
+    #[test]
+    fn tickers_must_be_four_bytes() {
+        assert_eq!(validate_ticker("ordi"), Ok(()));
+        assert_eq!(validate_ticker("🚀"), Ok(()));
+        assert!(validate_ticker("ord").is_err());
+        assert!(validate_ticker("ordis").is_err());
+        assert!(validate_ticker("").is_err());
+    }
+
+    #[test]
+    fn tickers_must_not_need_escaping() {
+        assert!(validate_ticker("or\"d").is_err());
+        assert!(validate_ticker("or\\d").is_err());
+        assert!(validate_ticker("or\nd").is_err());
+    }
+
+    #[test]
+    fn amounts_are_fixed_point_numbers() {
+        assert_eq!(parse_amount("1", MAX_DECIMALS), Ok(ONE));
+        assert_eq!(parse_amount("1.5", MAX_DECIMALS), Ok(ONE + ONE / 2));
+        assert_eq!(parse_amount("0.000000000000000001", MAX_DECIMALS), Ok(1));
+        assert_eq!(parse_amount("21000000", 0), Ok(21_000_000 * ONE));
+    }
+
+    #[test]
+    fn malformed_amounts_are_rejected() {
+        for amount in ["", ".5", "1.", "-1", "+1", "1e3", "1.2.3", " 1", "0x10"] {
+            assert!(parse_amount(amount, MAX_DECIMALS).is_err(), "{}", amount);
+        }
+    }
+
+    #[test]
+    fn zero_amounts_are_rejected() {
+        assert!(parse_amount("0", MAX_DECIMALS).is_err());
+        assert!(parse_amount("0.000", MAX_DECIMALS).is_err());
+    }
+
+    #[test]
+    fn amounts_must_not_have_more_decimals_than_the_token() {
+        assert_eq!(parse_amount("1.25", 2), Ok(ONE + ONE / 4));
+        assert!(parse_amount("1.125", 2).is_err());
+        assert!(parse_amount("1.5", 0).is_err());
+        assert!(parse_amount("0.0000000000000000001", MAX_DECIMALS).is_err());
+    }
+
+    #[test]
+    fn deploy_validates_decimals_and_limit() {
+        assert!(deploy("abcd", "1000", None, Some(MAX_DECIMALS + 1)).is_err());
+        assert!(deploy("abcd", "1000", Some("1001"), None).is_err());
+        assert!(deploy("abcd", "1000", None, Some(0)).is_ok());
+        assert!(deploy("abcd", "1000.5", None, Some(0)).is_err());
+        assert_eq!(
+            deploy("abcd", "1000", Some("10"), Some(2)),
+            Ok(
+                r#"{"p":"brc-20","op":"deploy","tick":"abcd","max":"1000","lim":"10","dec":"2"}"#
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn mints_are_checked_against_the_limit_of_deployed_tokens() {
+        record_deployment("mint", "1000", Some("10"), Some(1));
+
+        assert!(mint("MINT", "10").is_ok());
+        assert!(mint("mint", "10.1").is_err());
+        assert!(mint("mint", "9.95").is_err());
+        assert!(mint("none", "1").is_err());
+    }
+
+    #[test]
+    fn mints_of_other_tokens_are_checked_for_syntax() {
+        assert_eq!(
+            validate(r#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#),
+            Ok(())
+        );
+        assert!(validate(r#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1,000"}"#).is_err());
+        assert!(validate(r#"{"p":"brc-20","op":"mint","tick":"ordi"}"#).is_err());
+    }
+
+    #[test]
+    fn validated_mints_respect_the_limit_of_deployed_tokens() {
+        record_deployment("lim5", "100", Some("5"), None);
+
+        assert_eq!(
+            validate(r#"{"p":"brc-20","op":"mint","tick":"lim5","amt":"5"}"#),
+            Ok(())
+        );
+        assert!(validate(r#"{"p":"brc-20","op":"mint","tick":"lim5","amt":"6"}"#).is_err());
+    }
+
+    #[test]
+    fn recorded_deploys_are_remembered() {
+        let body = r#"{"p":"brc-20","op":"deploy","tick":"dply","max":"100","lim":"1","dec":"0"}"#;
+        assert_eq!(validate(body), Ok(()));
+
+        Brc20.record(Some(CONTENT_TYPE.as_bytes()), Some(body.as_bytes()), None);
+
+        assert!(is_deployed("DPLY"));
+        assert!(validate(body).is_err());
+        assert!(mint("dply", "1").is_ok());
+        assert!(mint("dply", "2").is_err());
+    }
+
+    #[test]
+    fn other_operations_are_not_recorded() {
+        let body = r#"{"p":"brc-20","op":"transfer","tick":"xfer","amt":"1"}"#;
+
+        Brc20.record(Some(CONTENT_TYPE.as_bytes()), Some(body.as_bytes()), None);
+
+        assert!(!is_deployed("xfer"));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::types::Broadcast;
+use crate::{
+    bitcoin_api, utxo_cache, Memory, BROADCASTS_MEMORY_ID, MAX_REBROADCAST_ATTEMPTS, MEMORY_MANAGER,
+};
+use bitcoin::{consensus::deserialize, Transaction};
+use candid::Principal;
+use hex::ToHex;
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_cdk::print;
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+
+thread_local! {
+    // The transactions sent by the canister, keyed by transaction ID.
+    static BROADCASTS: RefCell<StableBTreeMap<String, Broadcast, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BROADCASTS_MEMORY_ID)),
+        ));
+}
+
+/// Sends a signed transaction and keeps it, so that it can be sent again if
+/// the bitcoin adapter drops it. The UTXOs it spends stay reserved until it
+/// confirms.
+///
+/// The transaction is kept before it is sent, so that it isn't lost if the
+/// bitcoin canister accepts it but the call doesn't return, and forgotten
+/// again if sending it fails.
+pub async fn send_transaction(network: BitcoinNetwork, transaction: Vec<u8>) -> Result<(), String> {
+    let decoded_transaction: Transaction =
+        deserialize(&transaction).expect("transaction should be valid");
+    let txid: String = decoded_transaction.txid().encode_hex();
+    let previous = BROADCASTS.with(|b| {
+        b.borrow_mut().insert(
+            txid.clone(),
+            Broadcast {
+                transaction: transaction.clone(),
+                sender: ic_cdk::caller(),
+                attempts: 0,
+                confirmed: false,
+            },
+        )
+    });
+
+    if let Err(err) = bitcoin_api::send_transaction(network, transaction).await {
+        BROADCASTS.with(|b| match previous {
+            Some(previous) => b.borrow_mut().insert(txid, previous),
+            None => b.borrow_mut().remove(&txid),
+        });
+        return Err(err);
+    }
+
+    utxo_cache::mark_sent(&decoded_transaction);
+    Ok(())
+}
+
+/// Sends a transaction sent before once more on behalf of `caller`, who must
+/// have sent the transaction or be a controller of the canister, so that
+/// nobody else can use up its rebroadcast attempts.
+pub async fn rebroadcast(
+    network: BitcoinNetwork,
+    caller: Principal,
+    txid: String,
+) -> Result<(), String> {
+    let broadcast = BROADCASTS
+        .with(|b| b.borrow().get(&txid))
+        .ok_or_else(|| format!("Transaction {} not found", txid))?;
+    if broadcast.sender != caller && !ic_cdk::api::is_controller(&caller) {
+        return Err(format!(
+            "Only the sender of transaction {} or a controller may rebroadcast it",
+            txid
+        ));
+    }
+
+    send_again(network, txid).await
+}
+
+// Sends a transaction sent before once more, unless it has been sent again
+// `MAX_REBROADCAST_ATTEMPTS` times already.
+async fn send_again(network: BitcoinNetwork, txid: String) -> Result<(), String> {
+    let mut broadcast = BROADCASTS
+        .with(|b| b.borrow().get(&txid))
+        .ok_or_else(|| format!("Transaction {} not found", txid))?;
+    let max_attempts = MAX_REBROADCAST_ATTEMPTS.with(|m| m.get());
+    if broadcast.attempts >= max_attempts {
+        return Err(format!(
+            "Transaction {} has been rebroadcast {} times already",
+            txid, broadcast.attempts
+        ));
+    }
+
+    // Count the attempt before sending, so that concurrent calls can't exceed
+    // the limit.
+    broadcast.attempts += 1;
+    BROADCASTS.with(|b| b.borrow_mut().insert(txid, broadcast.clone()));
+
+    bitcoin_api::send_transaction(network, broadcast.transaction).await
+}
+
+/// Rebroadcasts the transactions that haven't been seen confirmed yet.
+///
+/// Rather than fetching the UTXOs of every output, a transaction counts as
+/// confirmed once the UTXO cache no longer tracks it as unconfirmed, which
+/// also stops the rebroadcasts of replaced transactions. Transactions whose
+/// confirmation the cache hasn't picked up yet are rebroadcast until they
+/// reach the maximum number of attempts, which is harmless.
+pub async fn rebroadcast_unconfirmed(network: BitcoinNetwork) {
+    let max_attempts = MAX_REBROADCAST_ATTEMPTS.with(|m| m.get());
+    let unconfirmed: Vec<(String, Broadcast)> = BROADCASTS.with(|b| {
+        b.borrow()
+            .iter()
+            .filter(|(_, broadcast)| !broadcast.confirmed && broadcast.attempts < max_attempts)
+            .collect()
+    });
+
+    for (txid, mut broadcast) in unconfirmed {
+        let transaction: Transaction =
+            deserialize(&broadcast.transaction).expect("transaction should be valid");
+        if !utxo_cache::is_unconfirmed(&transaction.txid()) {
+            broadcast.confirmed = true;
+            BROADCASTS.with(|b| b.borrow_mut().insert(txid, broadcast));
+            continue;
+        }
+
+        print(format!("Rebroadcasting transaction {}...", txid));
+        if let Err(err) = send_again(network, txid).await {
+            print(err);
+        }
+    }
+}
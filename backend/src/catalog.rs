@@ -0,0 +1,133 @@
+use crate::inscription::Inscription;
+use crate::inscription_id::InscriptionId;
+use crate::types::InscriptionInfo;
+use crate::{Memory, CATALOG_MEMORY_ID, MEMORY_MANAGER};
+use bitcoin::Txid;
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+use std::str::FromStr;
+
+thread_local! {
+    // The inscriptions revealed by the canister, keyed by inscription ID.
+    static CATALOG: RefCell<StableBTreeMap<String, InscriptionInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CATALOG_MEMORY_ID)),
+        ));
+}
+
+/// Records the inscriptions of a reveal transaction that has been sent.
+pub fn record(reveal_txid: Txid, inscriptions: &[Inscription]) {
+    CATALOG.with(|c| {
+        let mut catalog = c.borrow_mut();
+        for (index, inscription) in inscriptions.iter().enumerate() {
+            let inscription_id = InscriptionId {
+                txid: reveal_txid,
+                index: index as u32,
+            };
+            catalog.insert(
+                inscription_id.to_string(),
+                InscriptionInfo {
+                    content_type: inscription
+                        .content_type
+                        .as_ref()
+                        .map(|content_type| String::from_utf8_lossy(content_type).into_owned()),
+                    metadata: inscription.metadata.clone(),
+                },
+            );
+        }
+    });
+}
+
+/// Moves the inscriptions revealed by a transaction to the transaction
+/// replacing it.
+pub fn replace_transaction(old_txid: Txid, new_txid: Txid) {
+    CATALOG.with(|c| {
+        let mut catalog = c.borrow_mut();
+        for (inscription_id, inscription_info) in revealed_by(&catalog, old_txid) {
+            catalog.remove(&inscription_id);
+            let mut inscription_id =
+                InscriptionId::from_str(&inscription_id).expect("inscription ID should be valid");
+            inscription_id.txid = new_txid;
+            catalog.insert(inscription_id.to_string(), inscription_info);
+        }
+    });
+}
+
+/// Forgets the inscriptions revealed by a transaction that has been cancelled.
+pub fn remove_transaction(txid: Txid) {
+    CATALOG.with(|c| {
+        let mut catalog = c.borrow_mut();
+        for (inscription_id, _) in revealed_by(&catalog, txid) {
+            catalog.remove(&inscription_id);
+        }
+    });
+}
+
+// Returns the entries of the inscriptions revealed by a transaction.
+fn revealed_by(
+    catalog: &StableBTreeMap<String, InscriptionInfo, Memory>,
+    txid: Txid,
+) -> Vec<(String, InscriptionInfo)> {
+    let prefix = format!("{}i", txid);
+    catalog
+        .range(prefix.clone()..)
+        .take_while(|(inscription_id, _)| inscription_id.starts_with(&prefix))
+        .collect()
+}
+
+pub fn get(inscription_id: InscriptionId) -> Option<InscriptionInfo> {
+    CATALOG.with(|c| c.borrow().get(&inscription_id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn replaced_reveals_move_their_inscriptions() {
+        let old_txid = Txid::from_byte_array([1; 32]);
+        let new_txid = Txid::from_byte_array([2; 32]);
+        let other_txid = Txid::from_byte_array([3; 32]);
+        let inscriptions = [
+            Inscription::new(Some(b"text/plain".to_vec()), Some(b"a".to_vec())),
+            Inscription::new(Some(b"image/png".to_vec()), Some(b"b".to_vec())),
+        ];
+        record(old_txid, &inscriptions);
+        record(other_txid, &inscriptions[..1]);
+
+        replace_transaction(old_txid, new_txid);
+
+        let id = |txid, index| InscriptionId { txid, index };
+        assert!(get(id(old_txid, 0)).is_none());
+        assert!(get(id(old_txid, 1)).is_none());
+        assert_eq!(
+            get(id(new_txid, 0)).unwrap().content_type.as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(
+            get(id(new_txid, 1)).unwrap().content_type.as_deref(),
+            Some("image/png")
+        );
+        assert!(get(id(other_txid, 0)).is_some());
+    }
+
+    #[test]
+    fn cancelled_reveals_lose_their_inscriptions() {
+        let txid = Txid::from_byte_array([1; 32]);
+        let other_txid = Txid::from_byte_array([2; 32]);
+        let inscriptions = [Inscription::new(None, Some(b"a".to_vec()))];
+        record(txid, &inscriptions);
+        record(other_txid, &inscriptions);
+
+        remove_transaction(txid);
+
+        assert!(get(InscriptionId { txid, index: 0 }).is_none());
+        assert!(get(InscriptionId {
+            txid: other_txid,
+            index: 0
+        })
+        .is_some());
+    }
+}
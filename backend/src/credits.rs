@@ -0,0 +1,270 @@
+use crate::bitcoin_wallet::to_outpoint;
+use crate::{inscription_ledger, rune_ledger};
+use crate::{Memory, CREDITS_MEMORY_ID, MEMORY_MANAGER, SEEN_OUTPOINTS_MEMORY_ID};
+use bitcoin::{Address, OutPoint, Transaction};
+use candid::Principal;
+use ic_cdk::api::management_canister::bitcoin::Utxo;
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+
+/// The number of confirmations a deposit needs before it is credited.
+pub const MIN_CONFIRMATIONS: u32 = 6;
+
+thread_local! {
+    // The sats each principal can spend on inscriptions.
+    static CREDITS: RefCell<StableBTreeMap<Principal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CREDITS_MEMORY_ID)),
+        ));
+
+    // The outputs at the principals' addresses that have been credited or
+    // that were created by the canister itself, keyed by outpoint.
+    static SEEN_OUTPOINTS: RefCell<StableBTreeMap<String, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(SEEN_OUTPOINTS_MEMORY_ID)),
+        ));
+}
+
+/// Credits the UTXOs at the address of `owner` that haven't been seen before.
+/// UTXOs carrying inscriptions or runes aren't plain bitcoin and are only
+/// marked as seen.
+pub fn credit_deposits(owner: Principal, utxos: &[Utxo]) {
+    for utxo in utxos {
+        let outpoint = to_outpoint(utxo);
+        let seen = SEEN_OUTPOINTS.with(|s| s.borrow_mut().insert(outpoint.to_string(), ()));
+        if seen.is_none()
+            && !inscription_ledger::contains(outpoint)
+            && !rune_ledger::contains(outpoint)
+        {
+            credit(owner, utxo.value);
+        }
+    }
+}
+
+/// Credits the UTXOs of `owner` that a transaction spends and that haven't
+/// been seen before. The wallet spends deposits before they have enough
+/// confirmations to be credited, so they are credited once they are spent.
+pub fn credit_spent_deposits(owner: Principal, transaction: &Transaction, utxos: &[Utxo]) {
+    let spent_utxos: Vec<Utxo> = utxos
+        .iter()
+        .filter(|utxo| {
+            transaction
+                .input
+                .iter()
+                .any(|input| input.previous_output == to_outpoint(utxo))
+        })
+        .cloned()
+        .collect();
+    credit_deposits(owner, &spent_utxos);
+}
+
+/// Marks the outputs of a transaction sent by the canister that pay to
+/// `address`, so that change and postage aren't credited as deposits.
+pub fn exclude(transaction: &Transaction, address: &Address) {
+    let txid = transaction.txid();
+    SEEN_OUTPOINTS.with(|s| {
+        let mut seen_outpoints = s.borrow_mut();
+        for (vout, output) in transaction.output.iter().enumerate() {
+            if output.script_pubkey == address.script_pubkey() {
+                let outpoint = OutPoint {
+                    txid,
+                    vout: vout as u32,
+                };
+                seen_outpoints.insert(outpoint.to_string(), ());
+            }
+        }
+    });
+}
+
+pub fn balance(owner: Principal) -> u64 {
+    CREDITS.with(|c| c.borrow().get(&owner).unwrap_or(0))
+}
+
+/// Takes `amount` sats from the credits of `owner`.
+pub fn debit(owner: Principal, amount: u64) -> Result<(), String> {
+    let balance = balance(owner);
+    if balance < amount {
+        return Err(format!(
+            "Insufficient credits: {} sat available, {} sat required",
+            balance, amount
+        ));
+    }
+
+    CREDITS.with(|c| c.borrow_mut().insert(owner, balance - amount));
+    Ok(())
+}
+
+/// Adds `amount` sats to the credits of `owner`.
+pub fn credit(owner: Principal, amount: u64) {
+    let balance = balance(owner);
+    CREDITS.with(|c| c.borrow_mut().insert(owner, balance + amount));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{InscribedUtxo, RuneUtxo};
+    use bitcoin::{
+        absolute::LockTime, hashes::Hash, transaction::Version, Amount, TxIn, TxOut, Txid,
+    };
+    use ic_cdk::api::management_canister::bitcoin::Outpoint;
+    use std::str::FromStr;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn address(address: &str) -> Address {
+        Address::from_str(address).unwrap().assume_checked()
+    }
+
+    fn utxo(txid: Txid, vout: u32, value: u64) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: txid.to_byte_array().to_vec(),
+                vout,
+            },
+            value,
+            height: 0,
+        }
+    }
+
+    fn transaction(inputs: &[&Utxo], outputs: Vec<(&Address, u64)>) -> Transaction {
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: to_outpoint(utxo),
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs
+                .into_iter()
+                .map(|(address, value)| TxOut {
+                    script_pubkey: address.script_pubkey(),
+                    value: Amount::from_sat(value),
+                })
+                .collect(),
+        }
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    #[test]
+    fn debit_takes_credits() {
+        let owner = principal(1);
+        credit(owner, 1_000);
+
+        assert_eq!(debit(owner, 400), Ok(()));
+        assert_eq!(balance(owner), 600);
+        assert_eq!(debit(owner, 600), Ok(()));
+        assert_eq!(balance(owner), 0);
+    }
+
+    #[test]
+    fn debit_fails_without_enough_credits() {
+        let owner = principal(2);
+        credit(owner, 100);
+
+        assert!(debit(owner, 101).is_err());
+        assert_eq!(balance(owner), 100);
+    }
+
+    #[test]
+    fn credits_are_kept_per_principal() {
+        credit(principal(3), 100);
+        credit(principal(4), 200);
+
+        assert_eq!(balance(principal(3)), 100);
+        assert_eq!(balance(principal(4)), 200);
+        assert_eq!(balance(principal(5)), 0);
+    }
+
+    #[test]
+    fn deposits_are_credited_once() {
+        let owner = principal(6);
+        let utxos = [utxo(txid(1), 0, 1_000), utxo(txid(1), 1, 2_000)];
+
+        credit_deposits(owner, &utxos);
+        credit_deposits(owner, &utxos);
+
+        assert_eq!(balance(owner), 3_000);
+    }
+
+    #[test]
+    fn excluded_outputs_are_not_credited() {
+        let owner = principal(7);
+        let wallet = address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2");
+        let other = address("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy");
+        let deposit = utxo(txid(2), 0, 10_000);
+        let transaction = transaction(&[&deposit], vec![(&other, 3_000), (&wallet, 6_000)]);
+        exclude(&transaction, &wallet);
+
+        let change = utxo(transaction.txid(), 1, 6_000);
+        let payment = utxo(transaction.txid(), 0, 3_000);
+        credit_deposits(owner, &[change, payment]);
+
+        // Only outputs paying the wallet are excluded.
+        assert_eq!(balance(owner), 3_000);
+    }
+
+    #[test]
+    fn spent_deposits_are_credited() {
+        let owner = principal(8);
+        let wallet = address("1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2");
+        let credited = utxo(txid(3), 0, 1_000);
+        let unconfirmed = utxo(txid(3), 1, 2_000);
+        let unspent = utxo(txid(3), 2, 4_000);
+        credit_deposits(owner, std::slice::from_ref(&credited));
+
+        let transaction = transaction(&[&credited, &unconfirmed], vec![(&wallet, 2_500)]);
+        credit_spent_deposits(
+            owner,
+            &transaction,
+            &[credited.clone(), unconfirmed.clone(), unspent.clone()],
+        );
+        assert_eq!(balance(owner), 3_000);
+
+        // Deposits credited once spent aren't credited again when they show
+        // up with enough confirmations.
+        credit_deposits(owner, &[credited, unconfirmed, unspent]);
+        assert_eq!(balance(owner), 7_000);
+    }
+
+    #[test]
+    fn inscribed_and_rune_outputs_are_not_credited() {
+        let owner = principal(9);
+        let plain = utxo(txid(4), 0, 1_000);
+        let inscribed = utxo(txid(4), 1, 546);
+        let runic = utxo(txid(4), 2, 10_000);
+        inscription_ledger::insert(
+            to_outpoint(&inscribed),
+            InscribedUtxo {
+                address: String::new(),
+                inscriptions: vec![],
+            },
+        );
+        rune_ledger::insert(
+            to_outpoint(&runic),
+            RuneUtxo {
+                address: String::new(),
+                value: 10_000,
+                balances: vec![],
+            },
+        );
+
+        credit_deposits(owner, &[plain, inscribed.clone(), runic.clone()]);
+        assert_eq!(balance(owner), 1_000);
+
+        // The outputs stay seen once their inscriptions and runes move on.
+        inscription_ledger::remove(to_outpoint(&inscribed));
+        rune_ledger::remove(to_outpoint(&runic));
+        credit_deposits(owner, &[inscribed, runic]);
+        assert_eq!(balance(owner), 1_000);
+    }
+}
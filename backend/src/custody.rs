@@ -0,0 +1,113 @@
+use crate::icrc37;
+use crate::inscription_id::InscriptionId;
+use crate::types::Custody;
+use crate::{Memory, CUSTODY_MEMORY_ID, MEMORY_MANAGER};
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+
+thread_local! {
+    // The inscriptions held in custody, keyed by inscription ID.
+    static CUSTODY: RefCell<StableBTreeMap<String, Custody, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(CUSTODY_MEMORY_ID)),
+        ));
+}
+
+pub fn insert(inscription_id: InscriptionId, custody: Custody) {
+    CUSTODY.with(|c| c.borrow_mut().insert(inscription_id.to_string(), custody));
+}
+
+pub fn get(inscription_id: InscriptionId) -> Option<Custody> {
+    CUSTODY.with(|c| c.borrow().get(&inscription_id.to_string()))
+}
+
+/// Returns all inscriptions held in custody.
+pub fn all() -> Vec<(InscriptionId, Custody)> {
+    CUSTODY.with(|c| {
+        c.borrow()
+            .iter()
+            .map(|(inscription_id, custody)| {
+                (
+                    inscription_id
+                        .parse()
+                        .expect("inscription ID should be valid"),
+                    custody,
+                )
+            })
+            .collect()
+    })
+}
+
+pub fn remove(inscription_id: InscriptionId) {
+    CUSTODY.with(|c| c.borrow_mut().remove(&inscription_id.to_string()));
+}
+
+/// Returns the custody of an inscription if it is held for `owner`.
+pub fn get_owned(inscription_id: InscriptionId, owner: Principal) -> Result<Custody, String> {
+    get(inscription_id)
+        .filter(|custody| custody.owner == owner)
+        .ok_or_else(|| {
+            format!(
+                "Inscription {} is not held in custody for the caller",
+                inscription_id
+            )
+        })
+}
+
+/// Takes an inscription held for its owner out of custody while it is being
+/// withdrawn, so that it can't change hands while the withdrawal awaits the
+/// signatures and the broadcast.
+///
+/// The inscription is put back into custody when the guard is dropped unless
+/// the withdrawal has been completed. The CDK drops the futures of calls that
+/// trap, so this also happens if the withdrawal traps.
+pub struct Withdrawal {
+    inscription_id: InscriptionId,
+    custody: Option<Custody>,
+}
+
+impl Withdrawal {
+    pub fn start(inscription_id: InscriptionId, owner: Principal) -> Result<Self, String> {
+        let custody = get_owned(inscription_id, owner)?;
+        remove(inscription_id);
+        Ok(Self {
+            inscription_id,
+            custody: Some(custody),
+        })
+    }
+
+    pub fn custody(&self) -> &Custody {
+        self.custody
+            .as_ref()
+            .expect("custody should be held until the withdrawal completes")
+    }
+
+    /// Completes the withdrawal once the inscription has left custody,
+    /// revoking the approvals of its token.
+    pub fn complete(mut self) {
+        self.custody = None;
+        icrc37::clear_token_approvals(self.inscription_id);
+    }
+}
+
+impl Drop for Withdrawal {
+    fn drop(&mut self) {
+        if let Some(custody) = self.custody.take() {
+            insert(self.inscription_id, custody);
+        }
+    }
+}
+
+/// Moves the ownership of an inscription from one principal to another.
+pub fn transfer(
+    inscription_id: InscriptionId,
+    from: Principal,
+    to: Principal,
+) -> Result<(), String> {
+    let mut custody = get_owned(inscription_id, from)?;
+    custody.owner = to;
+    insert(inscription_id, custody);
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use crate::{
+    envelope::Envelope,
+    inscription_id::InscriptionId,
+    runes::{Artifact, Rune, Runestone, SpacedRune},
+    types::{
+        DecodedEdict, DecodedEtching, DecodedInscription, DecodedRunestone, DecodedTransaction,
+        InscriptionField, RuneTerms,
+    },
+};
+use bitcoin::{consensus::deserialize, Transaction};
+use hex::ToHex;
+use sha2::Digest;
+
+/// Decodes the inscription envelopes and the runestone of a raw transaction.
+pub fn decode(raw_tx: &[u8]) -> Result<DecodedTransaction, String> {
+    let transaction: Transaction =
+        deserialize(raw_tx).map_err(|e| format!("Invalid transaction: {}", e))?;
+
+    Ok(DecodedTransaction {
+        inscriptions: Envelope::from_transaction(&transaction)
+            .into_iter()
+            .map(decode_envelope)
+            .collect(),
+        runestone: Runestone::decipher(&transaction).map(decode_artifact),
+    })
+}
+
+fn decode_envelope(envelope: Envelope) -> DecodedInscription {
+    let inscription = &envelope.payload;
+    let text = |value: &Option<Vec<u8>>| {
+        value
+            .as_ref()
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    };
+    let inscription_id = |value: &Option<Vec<u8>>| {
+        value
+            .as_deref()
+            .and_then(InscriptionId::from_value)
+            .map(|id| id.to_string())
+    };
+
+    DecodedInscription {
+        input: envelope.input,
+        offset: envelope.offset,
+        fields: envelope
+            .fields
+            .iter()
+            .map(|(tag, value)| InscriptionField {
+                tag: tag.clone(),
+                value: value.clone(),
+            })
+            .collect(),
+        content_type: text(&inscription.content_type),
+        content_encoding: text(&inscription.content_encoding),
+        metaprotocol: text(&inscription.metaprotocol),
+        parent: inscription_id(&inscription.parent),
+        delegate: inscription_id(&inscription.delegate),
+        pointer: inscription.pointer(),
+        rune: inscription
+            .rune
+            .as_deref()
+            .and_then(rune_from_commitment)
+            .map(|rune| rune.to_string()),
+        body_length: inscription.body.as_ref().map(|body| body.len() as u64),
+        body_hash: inscription
+            .body
+            .as_ref()
+            .map(|body| sha2::Sha256::digest(body).encode_hex()),
+        duplicate_field: inscription.duplicate_field,
+        incomplete_field: inscription.incomplete_field,
+        unrecognized_even_field: inscription.unrecognized_even_field,
+        pushnum: envelope.pushnum,
+        stutter: envelope.stutter,
+    }
+}
+
+fn decode_artifact(artifact: Artifact) -> DecodedRunestone {
+    match artifact {
+        Artifact::Runestone(runestone) => DecodedRunestone {
+            flaw: None,
+            edicts: runestone
+                .edicts
+                .into_iter()
+                .map(|edict| DecodedEdict {
+                    rune_id: edict.id.to_string(),
+                    amount: edict.amount,
+                    output: edict.output,
+                })
+                .collect(),
+            etching: runestone.etching.map(|etching| DecodedEtching {
+                rune: etching.rune.map(|rune| {
+                    SpacedRune {
+                        rune,
+                        spacers: etching.spacers.unwrap_or_default(),
+                    }
+                    .to_string()
+                }),
+                divisibility: etching.divisibility,
+                symbol: etching.symbol.map(String::from),
+                premine: etching.premine,
+                terms: etching.terms.map(|terms| RuneTerms {
+                    amount: terms.amount,
+                    cap: terms.cap,
+                    height_start: terms.height.0,
+                    height_end: terms.height.1,
+                    offset_start: terms.offset.0,
+                    offset_end: terms.offset.1,
+                }),
+                turbo: etching.turbo,
+            }),
+            mint: runestone.mint.map(|id| id.to_string()),
+            pointer: runestone.pointer,
+        },
+        Artifact::Cenotaph(cenotaph) => DecodedRunestone {
+            flaw: Some(cenotaph.flaw.to_string()),
+            edicts: vec![],
+            etching: cenotaph.etching.map(|rune| DecodedEtching {
+                rune: Some(rune.to_string()),
+                divisibility: None,
+                symbol: None,
+                premine: None,
+                terms: None,
+                turbo: false,
+            }),
+            mint: cenotaph.mint.map(|id| id.to_string()),
+            pointer: None,
+        },
+    }
+}
+
+// Recovers the rune name from the commitment of an etching's reveal input.
+fn rune_from_commitment(commitment: &[u8]) -> Option<Rune> {
+    if commitment.len() > 16 {
+        return None;
+    }
+
+    let mut bytes = [0; 16];
+    bytes[..commitment.len()].copy_from_slice(commitment);
+
+    Some(Rune(u128::from_le_bytes(bytes)))
+}
@@ -0,0 +1,510 @@
+// Adapted from https://github.com/ordinals/ord/blob/master/src/inscriptions/envelope.rs
+
+use crate::inscription::{Inscription, Tag, BODY_TAG, PROTOCOL_ID};
+use bitcoin::{
+    blockdata::{opcodes, script::Instruction},
+    Script, Transaction,
+};
+
+use std::{collections::BTreeMap, iter::Peekable};
+
+type Instructions<'a> = Peekable<bitcoin::blockdata::script::Instructions<'a>>;
+
+/// The tag and value pairs preceding the body of an envelope, in script order.
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// An inscription envelope found in the tapscript of a transaction input,
+/// together with the properties that curse it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Envelope {
+    /// The index of the input whose tapscript holds the envelope.
+    pub input: u32,
+    /// The index of the envelope among the envelopes of the input.
+    pub offset: u32,
+    pub fields: Fields,
+    pub payload: Inscription,
+    /// Whether the envelope contains `OP_PUSHNUM` opcodes.
+    pub pushnum: bool,
+    /// Whether the envelope follows an `OP_FALSE` that didn't start an envelope.
+    pub stutter: bool,
+}
+
+impl Envelope {
+    /// Returns the envelopes of all inputs of the transaction.
+    pub fn from_transaction(transaction: &Transaction) -> Vec<Self> {
+        let mut envelopes = Vec::new();
+
+        for (i, input) in transaction.input.iter().enumerate() {
+            if let Some(tapscript) = input.witness.tapscript() {
+                // Like ord, we ignore the envelopes of unparseable tapscripts.
+                if let Ok(input_envelopes) = Self::from_tapscript(tapscript, i as u32) {
+                    envelopes.extend(input_envelopes);
+                }
+            }
+        }
+
+        envelopes
+    }
+
+    fn from_tapscript(tapscript: &Script, input: u32) -> Result<Vec<Self>, bitcoin::script::Error> {
+        let mut envelopes = Vec::new();
+
+        let mut instructions = tapscript.instructions().peekable();
+
+        let mut stuttered = false;
+        while let Some(instruction) = instructions.next().transpose()? {
+            if instruction == Instruction::PushBytes((&BODY_TAG).into()) {
+                let (stutter, envelope) = Self::from_instructions(
+                    &mut instructions,
+                    input,
+                    envelopes.len() as u32,
+                    stuttered,
+                )?;
+                if let Some(envelope) = envelope {
+                    envelopes.push(envelope);
+                } else {
+                    stuttered = stutter;
+                }
+            }
+        }
+
+        Ok(envelopes)
+    }
+
+    fn accept(
+        instructions: &mut Instructions,
+        instruction: Instruction,
+    ) -> Result<bool, bitcoin::script::Error> {
+        if instructions.peek() == Some(&Ok(instruction)) {
+            instructions.next().transpose()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn from_instructions(
+        instructions: &mut Instructions,
+        input: u32,
+        offset: u32,
+        stutter: bool,
+    ) -> Result<(bool, Option<Self>), bitcoin::script::Error> {
+        if !Self::accept(instructions, Instruction::Op(opcodes::all::OP_IF))? {
+            let stutter = instructions.peek() == Some(&Ok(Instruction::PushBytes((&[]).into())));
+            return Ok((stutter, None));
+        }
+
+        if !Self::accept(instructions, Instruction::PushBytes((&PROTOCOL_ID).into()))? {
+            let stutter = instructions.peek() == Some(&Ok(Instruction::PushBytes((&[]).into())));
+            return Ok((stutter, None));
+        }
+
+        let mut pushnum = false;
+
+        let mut payload = Vec::new();
+
+        loop {
+            let push = match instructions.next().transpose()? {
+                None => return Ok((false, None)),
+                Some(Instruction::Op(opcodes::all::OP_ENDIF)) => {
+                    let (fields, payload) = parse_payload(&payload);
+                    return Ok((
+                        false,
+                        Some(Self {
+                            input,
+                            offset,
+                            fields,
+                            payload,
+                            pushnum,
+                            stutter,
+                        }),
+                    ));
+                }
+                Some(Instruction::Op(opcodes::all::OP_PUSHNUM_NEG1)) => vec![0x81],
+                Some(Instruction::Op(op))
+                    if (opcodes::all::OP_PUSHNUM_1.to_u8()
+                        ..=opcodes::all::OP_PUSHNUM_16.to_u8())
+                        .contains(&op.to_u8()) =>
+                {
+                    vec![op.to_u8() - opcodes::all::OP_PUSHNUM_1.to_u8() + 1]
+                }
+                Some(Instruction::PushBytes(push)) => {
+                    payload.push(push.as_bytes().to_vec());
+                    continue;
+                }
+                Some(_) => return Ok((false, None)),
+            };
+
+            pushnum = true;
+            payload.push(push);
+        }
+    }
+}
+
+// Splits the payload of an envelope into its fields and body.
+fn parse_payload(payload: &[Vec<u8>]) -> (Fields, Inscription) {
+    let body = payload
+        .iter()
+        .enumerate()
+        .position(|(i, push)| i % 2 == 0 && push.is_empty());
+
+    let mut fields: BTreeMap<&[u8], Vec<&[u8]>> = BTreeMap::new();
+
+    let mut incomplete_field = false;
+
+    let mut field_list = Vec::new();
+
+    for item in payload[..body.unwrap_or(payload.len())].chunks(2) {
+        match item {
+            [key, value] => {
+                fields.entry(key).or_default().push(value);
+                field_list.push((key.clone(), value.clone()));
+            }
+            _ => incomplete_field = true,
+        }
+    }
+
+    let duplicate_field = fields.iter().any(|(_key, values)| values.len() > 1);
+
+    let content_encoding = Tag::ContentEncoding.take(&mut fields);
+    let content_type = Tag::ContentType.take(&mut fields);
+    let delegate = Tag::Delegate.take(&mut fields);
+    let metadata = Tag::Metadata.take(&mut fields);
+    let metaprotocol = Tag::Metaprotocol.take(&mut fields);
+    let parent = Tag::Parent.take(&mut fields);
+    let pointer = Tag::Pointer.take(&mut fields);
+    let rune = Tag::Rune.take(&mut fields);
+
+    let unrecognized_even_field = fields
+        .keys()
+        .any(|tag| tag.first().map(|lsb| lsb % 2 == 0).unwrap_or_default());
+
+    let inscription = Inscription {
+        body: body.map(|i| payload[i + 1..].iter().flatten().cloned().collect()),
+        content_encoding,
+        content_type,
+        delegate,
+        duplicate_field,
+        incomplete_field,
+        metadata,
+        metaprotocol,
+        parent,
+        pointer,
+        rune,
+        unrecognized_even_field,
+    };
+
+    (field_list, inscription)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        absolute::LockTime,
+        blockdata::script::{Builder, PushBytesBuf},
+        transaction::Version,
+        OutPoint, ScriptBuf, Sequence, TxIn, Witness,
+    };
+
+    fn parse(witnesses: &[Witness]) -> Vec<Envelope> {
+        Envelope::from_transaction(&Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: witnesses
+                .iter()
+                .map(|witness| TxIn {
+                    previous_output: OutPoint::null(),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: witness.clone(),
+                })
+                .collect(),
+            output: Vec::new(),
+        })
+    }
+
+    // Returns the witness of a script path spend whose tapscript holds an
+    // envelope with the given pushes.
+    fn envelope(payload: &[&[u8]]) -> Witness {
+        let mut builder = Builder::new()
+            .push_opcode(opcodes::OP_FALSE)
+            .push_opcode(opcodes::all::OP_IF);
+
+        for data in payload {
+            let mut buf = PushBytesBuf::new();
+            buf.extend_from_slice(data).unwrap();
+            builder = builder.push_slice(buf);
+        }
+
+        witness(builder.push_opcode(opcodes::all::OP_ENDIF))
+    }
+
+    fn witness(builder: Builder) -> Witness {
+        Witness::from_slice(&[builder.into_script().into_bytes(), Vec::new()])
+    }
+
+    fn payload(witnesses: &[Witness]) -> Inscription {
+        let envelopes = parse(witnesses);
+        assert_eq!(envelopes.len(), 1);
+        envelopes[0].payload.clone()
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse(&[Witness::new()]), Vec::new());
+    }
+
+    #[test]
+    fn ignore_unparsable_scripts() {
+        let mut script = Builder::new()
+            .push_opcode(opcodes::OP_FALSE)
+            .push_opcode(opcodes::all::OP_IF)
+            .push_slice(b"ord")
+            .push_opcode(opcodes::all::OP_ENDIF)
+            .into_script()
+            .into_bytes();
+        script.push(0x01);
+
+        assert_eq!(
+            parse(&[Witness::from_slice(&[script, Vec::new()])]),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn no_inscription() {
+        assert_eq!(
+            parse(&[Witness::from_slice(&[
+                ScriptBuf::new().into_bytes(),
+                Vec::new()
+            ])]),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(
+            payload(&[envelope(&[
+                b"ord",
+                &[1],
+                b"text/plain;charset=utf-8",
+                &[],
+                b"ord",
+            ])]),
+            Inscription::new(
+                Some(b"text/plain;charset=utf-8".to_vec()),
+                Some(b"ord".to_vec())
+            )
+        );
+    }
+
+    #[test]
+    fn valid_body_in_multiple_pushes() {
+        assert_eq!(
+            payload(&[envelope(&[
+                b"ord",
+                &[1],
+                b"text/plain",
+                &[],
+                b"foo",
+                b"bar"
+            ])]),
+            Inscription::new(Some(b"text/plain".to_vec()), Some(b"foobar".to_vec()))
+        );
+    }
+
+    #[test]
+    fn duplicate_field() {
+        assert_eq!(
+            payload(&[envelope(&[b"ord", &[255], &[], &[255], &[]])]),
+            Inscription {
+                duplicate_field: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn duplicate_field_keeps_first_value() {
+        assert_eq!(
+            payload(&[envelope(&[b"ord", &[1], b"a", &[1], b"b"])]),
+            Inscription {
+                content_type: Some(b"a".to_vec()),
+                duplicate_field: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn incomplete_field() {
+        assert_eq!(
+            payload(&[envelope(&[b"ord", &[99]])]),
+            Inscription {
+                incomplete_field: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn unrecognized_even_field() {
+        assert_eq!(
+            payload(&[envelope(&[b"ord", &[22], &[0], &[], b"ord"])]),
+            Inscription {
+                body: Some(b"ord".to_vec()),
+                unrecognized_even_field: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn unrecognized_odd_field_is_ignored() {
+        assert_eq!(
+            payload(&[envelope(&[b"ord", &[23], &[0], &[], b"ord"])]),
+            Inscription::new(None, Some(b"ord".to_vec()))
+        );
+    }
+
+    #[test]
+    fn metadata_is_parsed_correctly_from_chunks() {
+        // Like ord, chunks of metadata count as duplicate fields.
+        assert_eq!(
+            payload(&[envelope(&[b"ord", &[5], &[0], &[5], &[1]])]),
+            Inscription {
+                metadata: Some(vec![0, 1]),
+                duplicate_field: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn pushnum_opcodes_are_parsed_correctly() {
+        const PUSHNUMS: &[(opcodes::Opcode, u8)] = &[
+            (opcodes::all::OP_PUSHNUM_NEG1, 0x81),
+            (opcodes::all::OP_PUSHNUM_1, 1),
+            (opcodes::all::OP_PUSHNUM_2, 2),
+            (opcodes::all::OP_PUSHNUM_3, 3),
+            (opcodes::all::OP_PUSHNUM_4, 4),
+            (opcodes::all::OP_PUSHNUM_5, 5),
+            (opcodes::all::OP_PUSHNUM_6, 6),
+            (opcodes::all::OP_PUSHNUM_7, 7),
+            (opcodes::all::OP_PUSHNUM_8, 8),
+            (opcodes::all::OP_PUSHNUM_9, 9),
+            (opcodes::all::OP_PUSHNUM_10, 10),
+            (opcodes::all::OP_PUSHNUM_11, 11),
+            (opcodes::all::OP_PUSHNUM_12, 12),
+            (opcodes::all::OP_PUSHNUM_13, 13),
+            (opcodes::all::OP_PUSHNUM_14, 14),
+            (opcodes::all::OP_PUSHNUM_15, 15),
+            (opcodes::all::OP_PUSHNUM_16, 16),
+        ];
+
+        for &(op, value) in PUSHNUMS {
+            let envelopes = parse(&[witness(
+                Builder::new()
+                    .push_opcode(opcodes::OP_FALSE)
+                    .push_opcode(opcodes::all::OP_IF)
+                    .push_slice(b"ord")
+                    .push_slice([])
+                    .push_opcode(op)
+                    .push_opcode(opcodes::all::OP_ENDIF),
+            )]);
+
+            assert_eq!(envelopes.len(), 1);
+            assert_eq!(envelopes[0].payload.body, Some(vec![value]));
+            assert!(envelopes[0].pushnum);
+        }
+    }
+
+    #[test]
+    fn non_push_opcode_ends_envelope_without_inscription() {
+        assert_eq!(
+            parse(&[witness(
+                Builder::new()
+                    .push_opcode(opcodes::OP_FALSE)
+                    .push_opcode(opcodes::all::OP_IF)
+                    .push_slice(b"ord")
+                    .push_opcode(opcodes::all::OP_VERIFY)
+                    .push_opcode(opcodes::all::OP_ENDIF)
+            )]),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn stuttering() {
+        let stutter = |builder: Builder| {
+            let envelopes = parse(&[witness(
+                builder
+                    .push_opcode(opcodes::OP_FALSE)
+                    .push_opcode(opcodes::all::OP_IF)
+                    .push_slice(b"ord")
+                    .push_opcode(opcodes::all::OP_ENDIF),
+            )]);
+            assert_eq!(envelopes.len(), 1);
+            envelopes[0].stutter
+        };
+
+        assert!(!stutter(Builder::new()));
+        assert!(stutter(Builder::new().push_opcode(opcodes::OP_FALSE)));
+        assert!(stutter(
+            Builder::new()
+                .push_opcode(opcodes::OP_FALSE)
+                .push_opcode(opcodes::all::OP_IF)
+        ));
+        assert!(stutter(
+            Builder::new()
+                .push_opcode(opcodes::OP_FALSE)
+                .push_opcode(opcodes::all::OP_IF)
+                .push_opcode(opcodes::OP_FALSE)
+                .push_opcode(opcodes::all::OP_IF)
+        ));
+        assert!(!stutter(
+            Builder::new()
+                .push_opcode(opcodes::OP_FALSE)
+                .push_opcode(opcodes::all::OP_AND)
+        ));
+    }
+
+    #[test]
+    fn multiple_envelopes_in_one_input() {
+        let envelopes = parse(&[witness(
+            Builder::new()
+                .push_opcode(opcodes::OP_FALSE)
+                .push_opcode(opcodes::all::OP_IF)
+                .push_slice(b"ord")
+                .push_slice([])
+                .push_slice(b"foo")
+                .push_opcode(opcodes::all::OP_ENDIF)
+                .push_opcode(opcodes::OP_FALSE)
+                .push_opcode(opcodes::all::OP_IF)
+                .push_slice(b"ord")
+                .push_slice([])
+                .push_slice(b"bar")
+                .push_opcode(opcodes::all::OP_ENDIF),
+        )]);
+
+        assert_eq!(envelopes.len(), 2);
+        assert_eq!(envelopes[0].payload.body, Some(b"foo".to_vec()));
+        assert_eq!(envelopes[0].offset, 0);
+        assert_eq!(envelopes[1].payload.body, Some(b"bar".to_vec()));
+        assert_eq!(envelopes[1].offset, 1);
+    }
+
+    #[test]
+    fn envelopes_in_multiple_inputs() {
+        let envelopes = parse(&[
+            envelope(&[b"ord", &[], b"foo"]),
+            envelope(&[b"ord", &[], b"bar"]),
+        ]);
+
+        assert_eq!(envelopes.len(), 2);
+        assert_eq!(envelopes[0].input, 0);
+        assert_eq!(envelopes[1].input, 1);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::utxo_cache;
+use bitcoin::{OutPoint, Transaction};
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+thread_local! {
+    // The derivation paths of the wallets with an operation in progress.
+    static BUSY_WALLETS: RefCell<BTreeSet<Vec<Vec<u8>>>> =
+        const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Marks a wallet as busy for the duration of an operation, so that
+/// overlapping operations can't select the same UTXOs and broadcast
+/// conflicting transactions.
+///
+/// The UTXOs reserved during the operation are released when the guard is
+/// dropped, unless a transaction spending them has been sent. The CDK drops
+/// the futures of calls that trap, so this also happens if the operation
+/// traps.
+pub struct WalletGuard {
+    derivation_path: Vec<Vec<u8>>,
+    reserved: RefCell<Vec<OutPoint>>,
+}
+
+impl WalletGuard {
+    pub fn new(derivation_path: &[Vec<u8>]) -> Result<Self, String> {
+        let acquired = BUSY_WALLETS.with(|b| b.borrow_mut().insert(derivation_path.to_vec()));
+        if !acquired {
+            return Err("Busy: another operation of this wallet is in progress".to_string());
+        }
+
+        Ok(Self {
+            derivation_path: derivation_path.to_vec(),
+            reserved: RefCell::new(vec![]),
+        })
+    }
+
+    /// Reserves the UTXOs spent by a transaction, so that they aren't
+    /// selected again until the transaction confirms.
+    pub fn reserve(&self, transaction: &Transaction) {
+        let outpoints: Vec<OutPoint> = transaction
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        utxo_cache::reserve(&outpoints);
+        self.reserved.borrow_mut().extend(outpoints);
+    }
+}
+
+impl Drop for WalletGuard {
+    fn drop(&mut self) {
+        utxo_cache::release(&self.reserved.borrow());
+        BUSY_WALLETS.with(|b| b.borrow_mut().remove(&self.derivation_path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utxo_cache::tests::{cache, is_reserved};
+    use bitcoin::{absolute::LockTime, hashes::Hash, transaction::Version, TxIn, Txid};
+
+    fn spend(outpoints: &[OutPoint]) -> Transaction {
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: outpoints
+                .iter()
+                .map(|outpoint| TxIn {
+                    previous_output: *outpoint,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn wallets_are_busy_until_the_guard_is_dropped() {
+        let guard = WalletGuard::new(&[vec![1]]).unwrap();
+        assert!(WalletGuard::new(&[vec![1]]).is_err());
+        assert!(WalletGuard::new(&[vec![2]]).is_ok());
+
+        drop(guard);
+        assert!(WalletGuard::new(&[vec![1]]).is_ok());
+    }
+
+    #[test]
+    fn dropping_the_guard_releases_unsent_reservations() {
+        let sent = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 0,
+        };
+        let unsent = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 1,
+        };
+        cache(&[sent, unsent]);
+
+        let guard = WalletGuard::new(&[vec![1]]).unwrap();
+        guard.reserve(&spend(&[sent]));
+        guard.reserve(&spend(&[unsent]));
+        utxo_cache::mark_sent(&spend(&[sent]));
+        assert!(is_reserved(&sent));
+        assert!(is_reserved(&unsent));
+
+        drop(guard);
+        assert!(is_reserved(&sent));
+        assert!(!is_reserved(&unsent));
+    }
+}
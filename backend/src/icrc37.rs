@@ -0,0 +1,588 @@
+// Implements the approvals of https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-37/ICRC-37.md
+// for the tokens of `icrc7`.
+
+use crate::{
+    custody,
+    icrc7::{self, Account, TransferError},
+    inscription_id::InscriptionId,
+    types::impl_candid_storable,
+    Memory, COLLECTION_APPROVALS_MEMORY_ID, MEMORY_MANAGER, TOKEN_APPROVALS_MEMORY_ID,
+};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+
+pub const MAX_APPROVALS: usize = 10;
+pub const MAX_REVOKE_APPROVALS: usize = 20;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApprovalInfo {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: u64,
+}
+
+impl ApprovalInfo {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+// The approvals of a token or of all tokens of an owner.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct Approvals(Vec<ApprovalInfo>);
+
+impl_candid_storable!(Approvals);
+
+thread_local! {
+    // The approvals of single tokens, keyed by inscription ID.
+    static TOKEN_APPROVALS: RefCell<StableBTreeMap<String, Approvals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TOKEN_APPROVALS_MEMORY_ID)),
+        ));
+
+    // The approvals of all tokens of an owner, keyed by owner.
+    static COLLECTION_APPROVALS: RefCell<StableBTreeMap<Principal, Approvals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(COLLECTION_APPROVALS_MEMORY_ID)),
+        ));
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveTokenArg {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveTokenResult = Result<Nat, ApproveTokenError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveCollectionArg {
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveCollectionError {
+    InvalidSpender,
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveCollectionResult = Result<Nat, ApproveCollectionError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevokeTokenApprovalArg {
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeTokenApprovalResponse = Result<Nat, RevokeTokenApprovalError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevokeCollectionApprovalArg {
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeCollectionApprovalResult = Result<Nat, RevokeCollectionApprovalError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenApproval {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+pub type TransferFromResult = Result<Nat, TransferError>;
+
+pub fn approve_tokens(
+    caller: Principal,
+    args: Vec<ApproveTokenArg>,
+) -> Vec<Option<ApproveTokenResult>> {
+    if args.len() > icrc7::MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(ApproveTokenError::GenericBatchError {
+            error_code: Nat::from(0u8),
+            message: batch_size_error(),
+        }))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(approve_token(caller, arg)))
+        .collect()
+}
+
+fn approve_token(caller: Principal, arg: ApproveTokenArg) -> ApproveTokenResult {
+    let inscription_id =
+        icrc7::inscription_id(&arg.token_id).ok_or(ApproveTokenError::NonExistingTokenId)?;
+    let custody = custody::get(inscription_id).ok_or(ApproveTokenError::NonExistingTokenId)?;
+    if custody.owner != caller || !icrc7::is_default_subaccount(&arg.approval_info.from_subaccount)
+    {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+    if arg.approval_info.spender.owner == caller {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+
+    TOKEN_APPROVALS.with(|a| {
+        let mut approvals = a.borrow_mut();
+        let key = inscription_id.to_string();
+        let mut token_approvals = approvals.get(&key).unwrap_or_default();
+        add_approval(&mut token_approvals, arg.approval_info, ic_cdk::api::time()).map_err(
+            |message| ApproveTokenError::GenericError {
+                error_code: Nat::from(0u8),
+                message,
+            },
+        )?;
+        approvals.insert(key, token_approvals);
+        Ok(())
+    })?;
+
+    Ok(icrc7::next_tx_index())
+}
+
+pub fn approve_collection(
+    caller: Principal,
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<ApproveCollectionResult>> {
+    if args.len() > icrc7::MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(ApproveCollectionError::GenericBatchError {
+            error_code: Nat::from(0u8),
+            message: batch_size_error(),
+        }))];
+    }
+
+    args.into_iter()
+        .map(|arg| {
+            if arg.approval_info.spender.owner == caller
+                || !icrc7::is_default_subaccount(&arg.approval_info.from_subaccount)
+            {
+                return Some(Err(ApproveCollectionError::InvalidSpender));
+            }
+
+            let result = COLLECTION_APPROVALS.with(|a| {
+                let mut approvals = a.borrow_mut();
+                let mut owner_approvals = approvals.get(&caller).unwrap_or_default();
+                add_approval(&mut owner_approvals, arg.approval_info, ic_cdk::api::time())?;
+                approvals.insert(caller, owner_approvals);
+                Ok(())
+            });
+
+            Some(result.map(|()| icrc7::next_tx_index()).map_err(|message| {
+                ApproveCollectionError::GenericError {
+                    error_code: Nat::from(0u8),
+                    message,
+                }
+            }))
+        })
+        .collect()
+}
+
+pub fn revoke_token_approvals(
+    caller: Principal,
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<RevokeTokenApprovalResponse>> {
+    if args.len() > MAX_REVOKE_APPROVALS {
+        return vec![Some(Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code: Nat::from(0u8),
+            message: format!(
+                "At most {} approvals can be revoked per call",
+                MAX_REVOKE_APPROVALS
+            ),
+        }))];
+    }
+
+    args.into_iter()
+        .map(|arg| {
+            let Some(inscription_id) = icrc7::inscription_id(&arg.token_id) else {
+                return Some(Err(RevokeTokenApprovalError::NonExistingTokenId));
+            };
+            let Some(custody) = custody::get(inscription_id) else {
+                return Some(Err(RevokeTokenApprovalError::NonExistingTokenId));
+            };
+            if custody.owner != caller || !icrc7::is_default_subaccount(&arg.from_subaccount) {
+                return Some(Err(RevokeTokenApprovalError::Unauthorized));
+            }
+
+            let revoked = TOKEN_APPROVALS.with(|a| {
+                let mut approvals = a.borrow_mut();
+                let key = inscription_id.to_string();
+                let mut token_approvals = approvals.get(&key).unwrap_or_default();
+                let revoked = remove_approvals(&mut token_approvals, arg.spender.as_ref());
+                if token_approvals.0.is_empty() {
+                    approvals.remove(&key);
+                } else {
+                    approvals.insert(key, token_approvals);
+                }
+                revoked
+            });
+
+            Some(if revoked {
+                Ok(icrc7::next_tx_index())
+            } else {
+                Err(RevokeTokenApprovalError::ApprovalDoesNotExist)
+            })
+        })
+        .collect()
+}
+
+pub fn revoke_collection_approvals(
+    caller: Principal,
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    if args.len() > MAX_REVOKE_APPROVALS {
+        return vec![Some(Err(
+            RevokeCollectionApprovalError::GenericBatchError {
+                error_code: Nat::from(0u8),
+                message: format!(
+                    "At most {} approvals can be revoked per call",
+                    MAX_REVOKE_APPROVALS
+                ),
+            },
+        ))];
+    }
+
+    args.into_iter()
+        .map(|arg| {
+            let revoked = icrc7::is_default_subaccount(&arg.from_subaccount)
+                && COLLECTION_APPROVALS.with(|a| {
+                    let mut approvals = a.borrow_mut();
+                    let mut owner_approvals = approvals.get(&caller).unwrap_or_default();
+                    let revoked = remove_approvals(&mut owner_approvals, arg.spender.as_ref());
+                    if owner_approvals.0.is_empty() {
+                        approvals.remove(&caller);
+                    } else {
+                        approvals.insert(caller, owner_approvals);
+                    }
+                    revoked
+                });
+
+            Some(if revoked {
+                Ok(icrc7::next_tx_index())
+            } else {
+                Err(RevokeCollectionApprovalError::ApprovalDoesNotExist)
+            })
+        })
+        .collect()
+}
+
+pub fn is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    args.iter()
+        .take(icrc7::MAX_QUERY_BATCH_SIZE)
+        .map(|arg| {
+            icrc7::is_default_subaccount(&arg.from_subaccount)
+                && icrc7::inscription_id(&arg.token_id).is_some_and(|inscription_id| {
+                    is_spender(inscription_id, &arg.spender, ic_cdk::api::time())
+                })
+        })
+        .collect()
+}
+
+pub fn get_token_approvals(
+    token_id: Nat,
+    prev: Option<TokenApproval>,
+    take: Option<Nat>,
+) -> Vec<TokenApproval> {
+    let Some(inscription_id) = icrc7::inscription_id(&token_id) else {
+        return vec![];
+    };
+
+    let approvals = TOKEN_APPROVALS
+        .with(|a| a.borrow().get(&inscription_id.to_string()))
+        .unwrap_or_default();
+
+    after(approvals.0, prev.map(|prev| prev.approval_info))
+        .take(icrc7::take_value(take))
+        .map(|approval_info| TokenApproval {
+            token_id: token_id.clone(),
+            approval_info,
+        })
+        .collect()
+}
+
+pub fn get_collection_approvals(
+    owner: Account,
+    prev: Option<ApprovalInfo>,
+    take: Option<Nat>,
+) -> Vec<ApprovalInfo> {
+    if !owner.has_default_subaccount() {
+        return vec![];
+    }
+
+    let approvals = COLLECTION_APPROVALS
+        .with(|a| a.borrow().get(&owner.owner))
+        .unwrap_or_default();
+
+    after(approvals.0, prev)
+        .take(icrc7::take_value(take))
+        .collect()
+}
+
+/// Transfers tokens on behalf of their owners.
+pub fn transfer_from(
+    caller: Principal,
+    args: Vec<TransferFromArg>,
+) -> Vec<Option<TransferFromResult>> {
+    if args.len() > icrc7::MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(0u8),
+            message: batch_size_error(),
+        }))];
+    }
+
+    args.into_iter()
+        .map(|arg| Some(transfer_token_from(caller, arg)))
+        .collect()
+}
+
+fn transfer_token_from(caller: Principal, arg: TransferFromArg) -> TransferFromResult {
+    let inscription_id =
+        icrc7::inscription_id(&arg.token_id).ok_or(TransferError::NonExistingTokenId)?;
+    let custody = custody::get(inscription_id).ok_or(TransferError::NonExistingTokenId)?;
+
+    let spender = Account {
+        owner: caller,
+        subaccount: arg.spender_subaccount,
+    };
+    if custody.owner != arg.from.owner
+        || !arg.from.has_default_subaccount()
+        || !is_spender(inscription_id, &spender, ic_cdk::api::time())
+    {
+        return Err(TransferError::Unauthorized);
+    }
+
+    // Burning withdraws to a bitcoin address with the fee paid by the owner,
+    // so only the owner can burn.
+    if arg.to.owner == ic_cdk::id()
+        || arg.to.owner == Principal::anonymous()
+        || !arg.to.has_default_subaccount()
+        || arg.to.owner == custody.owner
+    {
+        return Err(TransferError::InvalidRecipient);
+    }
+
+    icrc7::move_token(inscription_id, custody.owner, arg.to.owner);
+
+    Ok(icrc7::next_tx_index())
+}
+
+/// Revokes all approvals of a token, which happens whenever it is transferred.
+pub fn clear_token_approvals(inscription_id: InscriptionId) {
+    TOKEN_APPROVALS.with(|a| a.borrow_mut().remove(&inscription_id.to_string()));
+}
+
+// Returns whether `spender` may transfer the token, either through an
+// approval of the token or of all tokens of its owner that is active at `now`.
+fn is_spender(inscription_id: InscriptionId, spender: &Account, now: u64) -> bool {
+    let Some(custody) = custody::get(inscription_id) else {
+        return false;
+    };
+
+    let approves = |approvals: Option<Approvals>| {
+        approvals
+            .unwrap_or_default()
+            .0
+            .iter()
+            .any(|approval| approval.spender == *spender && approval.is_active(now))
+    };
+
+    approves(TOKEN_APPROVALS.with(|a| a.borrow().get(&inscription_id.to_string())))
+        || approves(COLLECTION_APPROVALS.with(|a| a.borrow().get(&custody.owner)))
+}
+
+// Adds an approval, replacing an existing approval of the same spender and
+// dropping the approvals that have expired at `now`.
+fn add_approval(
+    approvals: &mut Approvals,
+    approval_info: ApprovalInfo,
+    now: u64,
+) -> Result<(), String> {
+    approvals
+        .0
+        .retain(|approval| approval.spender != approval_info.spender && approval.is_active(now));
+
+    if approvals.0.len() >= MAX_APPROVALS {
+        return Err(format!("At most {} approvals are allowed", MAX_APPROVALS));
+    }
+
+    approvals.0.push(approval_info);
+    Ok(())
+}
+
+// Removes the approvals of `spender`, or all approvals if no spender is
+// given, and returns whether any approval was removed.
+fn remove_approvals(approvals: &mut Approvals, spender: Option<&Account>) -> bool {
+    let count = approvals.0.len();
+    approvals
+        .0
+        .retain(|approval| spender.is_some_and(|spender| approval.spender != *spender));
+    approvals.0.len() < count
+}
+
+// Returns the approvals following the approval of the same spender as `prev`.
+fn after(
+    approvals: Vec<ApprovalInfo>,
+    prev: Option<ApprovalInfo>,
+) -> impl Iterator<Item = ApprovalInfo> {
+    let skip = prev
+        .and_then(|prev| {
+            approvals
+                .iter()
+                .position(|approval| approval.spender == prev.spender)
+                .map(|position| position + 1)
+        })
+        .unwrap_or(0);
+
+    approvals.into_iter().skip(skip)
+}
+
+fn batch_size_error() -> String {
+    format!(
+        "At most {} operations are allowed per call",
+        icrc7::MAX_UPDATE_BATCH_SIZE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Custody;
+    use candid::Encode;
+    use ic_stable_structures::Storable;
+    use std::str::FromStr;
+
+    fn account(id: u8) -> Account {
+        Account::of(Principal::from_slice(&[id]))
+    }
+
+    fn approval(spender: Account, expires_at: Option<u64>) -> ApprovalInfo {
+        ApprovalInfo {
+            spender,
+            from_subaccount: None,
+            expires_at,
+            memo: None,
+            created_at_time: 0,
+        }
+    }
+
+    #[test]
+    fn approvals_expire() {
+        assert!(approval(account(1), None).is_active(u64::MAX));
+        assert!(approval(account(1), Some(100)).is_active(99));
+        assert!(!approval(account(1), Some(100)).is_active(100));
+    }
+
+    #[test]
+    fn adding_an_approval_drops_expired_approvals() {
+        let mut approvals = Approvals::default();
+        add_approval(&mut approvals, approval(account(1), Some(100)), 0).unwrap();
+        add_approval(&mut approvals, approval(account(2), None), 0).unwrap();
+        add_approval(&mut approvals, approval(account(2), Some(200)), 0).unwrap();
+        assert_eq!(approvals.0.len(), 2);
+
+        add_approval(&mut approvals, approval(account(3), None), 100).unwrap();
+
+        let spenders: Vec<Account> = approvals.0.into_iter().map(|a| a.spender).collect();
+        assert_eq!(spenders, vec![account(2), account(3)]);
+    }
+
+    #[test]
+    fn expired_approvals_are_not_counted() {
+        let mut approvals = Approvals::default();
+        for id in 0..MAX_APPROVALS as u8 {
+            add_approval(&mut approvals, approval(account(id), Some(100)), 0).unwrap();
+        }
+        assert!(add_approval(&mut approvals, approval(account(100), None), 99).is_err());
+        assert_eq!(
+            add_approval(&mut approvals, approval(account(100), None), 100),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn expired_approvals_do_not_make_spenders() {
+        let inscription_id = InscriptionId::from_str(&format!("{}i0", "ab".repeat(32))).unwrap();
+        let owner = account(1);
+        custody::insert(
+            inscription_id,
+            Custody {
+                owner: owner.owner,
+                outpoint: format!("{}:0", "cd".repeat(32)),
+                value: 546,
+            },
+        );
+        TOKEN_APPROVALS.with(|a| {
+            a.borrow_mut().insert(
+                inscription_id.to_string(),
+                Approvals(vec![approval(account(2), Some(100))]),
+            )
+        });
+        COLLECTION_APPROVALS.with(|a| {
+            a.borrow_mut().insert(
+                owner.owner,
+                Approvals(vec![approval(account(3), Some(200))]),
+            )
+        });
+
+        assert!(is_spender(inscription_id, &account(2), 99));
+        assert!(!is_spender(inscription_id, &account(2), 100));
+        assert!(is_spender(inscription_id, &account(3), 199));
+        assert!(!is_spender(inscription_id, &account(3), 200));
+        assert!(!is_spender(inscription_id, &account(4), 0));
+    }
+
+    #[test]
+    fn approvals_are_stored_candid_encoded() {
+        let approvals = Approvals(vec![approval(account(1), Some(100))]);
+        let bytes = approvals.to_bytes();
+
+        assert_eq!(bytes.as_ref(), Encode!(&approvals).unwrap().as_slice());
+        assert_eq!(Approvals::from_bytes(bytes).0[0].spender, account(1));
+    }
+}
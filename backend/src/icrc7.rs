@@ -0,0 +1,431 @@
+// Implements https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7/ICRC-7.md over the
+// inscriptions held in custody. Each token ID encodes the inscription ID, so
+// tokens need no state beyond the custody ledger.
+
+use crate::{
+    bitcoin_wallet, catalog, custody, icrc37, inscription_id::InscriptionId, Memory,
+    ICRC7_TX_INDEX_MEMORY_ID, MEMORY_MANAGER, NETWORK,
+};
+use bitcoin::{hashes::Hash, Txid};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_stable_structures::StableCell;
+
+use std::cell::RefCell;
+
+pub const SYMBOL: &str = "ORD";
+pub const NAME: &str = "Ordinal Inscriptions";
+pub const DESCRIPTION: &str =
+    "Ordinal inscriptions held in custody by the canister. Burning a token withdraws its inscription to the bitcoin address in the memo.";
+
+pub const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub const MAX_UPDATE_BATCH_SIZE: usize = 20;
+pub const DEFAULT_TAKE_VALUE: usize = 100;
+pub const MAX_TAKE_VALUE: usize = 1_000;
+pub const MAX_MEMO_SIZE: usize = 128;
+
+/// The fee rate in sat/vB of the withdrawal triggered by burning a token.
+const BURN_FEE_RATE: u64 = 10;
+
+thread_local! {
+    // The index of the next transaction of the ledger.
+    static TX_INDEX: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ICRC7_TX_INDEX_MEMORY_ID)),
+            0,
+        )
+        .expect("initializing the transaction index should work"),
+    );
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    /// Custody is tracked per principal, so only default subaccounts hold tokens.
+    pub fn has_default_subaccount(&self) -> bool {
+        is_default_subaccount(&self.subaccount)
+    }
+
+    pub fn of(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+impl PartialEq for Account {
+    fn eq(&self, other: &Self) -> bool {
+        self.owner == other.owner
+            && self.subaccount.as_deref().unwrap_or(&[0; 32])
+                == other.subaccount.as_deref().unwrap_or(&[0; 32])
+    }
+}
+
+/// The subset of the ICRC-3 value type used in metadata.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type TransferResult = Result<Nat, TransferError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+/// Returns the token ID of an inscription: the big-endian integer of the
+/// txid as displayed followed by the index.
+pub fn token_id(inscription_id: InscriptionId) -> Nat {
+    let mut bytes = inscription_id.txid.to_byte_array();
+    bytes.reverse();
+
+    bytes
+        .into_iter()
+        .chain(inscription_id.index.to_be_bytes())
+        .fold(Nat::from(0u8), |n, byte| {
+            n * Nat::from(256u32) + Nat::from(byte)
+        })
+}
+
+/// Returns the inscription ID encoded by a token ID.
+pub fn inscription_id(token_id: &Nat) -> Option<InscriptionId> {
+    let mut bytes = [0u8; 36];
+    let mut n = token_id.clone();
+
+    for byte in bytes.iter_mut().rev() {
+        let remainder = n.clone() % Nat::from(256u32);
+        *byte = u8::try_from(&remainder.0).unwrap();
+        n /= Nat::from(256u32);
+    }
+
+    if n != 0u8 {
+        return None;
+    }
+
+    let (txid, index) = bytes.split_at(32);
+    let mut txid: [u8; 32] = txid.try_into().unwrap();
+    txid.reverse();
+
+    Some(InscriptionId {
+        txid: Txid::from_byte_array(txid),
+        index: u32::from_be_bytes(index.try_into().unwrap()),
+    })
+}
+
+/// Returns the owner of a token if it is held in custody.
+pub fn owner_of(token_id: &Nat) -> Option<Principal> {
+    custody::get(inscription_id(token_id)?).map(|custody| custody.owner)
+}
+
+/// Returns the index of the next ledger transaction and advances it.
+pub fn next_tx_index() -> Nat {
+    TX_INDEX.with(|t| {
+        let mut tx_index = t.borrow_mut();
+        let index = *tx_index.get();
+        tx_index
+            .set(index + 1)
+            .expect("setting the transaction index should work");
+        Nat::from(index)
+    })
+}
+
+pub fn total_supply() -> Nat {
+    Nat::from(custody::all().len())
+}
+
+pub fn collection_metadata() -> Vec<(String, Value)> {
+    let nat = |n: usize| Value::Nat(Nat::from(n));
+
+    vec![
+        ("icrc7:symbol".to_string(), Value::Text(SYMBOL.to_string())),
+        ("icrc7:name".to_string(), Value::Text(NAME.to_string())),
+        (
+            "icrc7:description".to_string(),
+            Value::Text(DESCRIPTION.to_string()),
+        ),
+        ("icrc7:total_supply".to_string(), Value::Nat(total_supply())),
+        (
+            "icrc7:max_query_batch_size".to_string(),
+            nat(MAX_QUERY_BATCH_SIZE),
+        ),
+        (
+            "icrc7:max_update_batch_size".to_string(),
+            nat(MAX_UPDATE_BATCH_SIZE),
+        ),
+        (
+            "icrc7:default_take_value".to_string(),
+            nat(DEFAULT_TAKE_VALUE),
+        ),
+        ("icrc7:max_take_value".to_string(), nat(MAX_TAKE_VALUE)),
+        ("icrc7:max_memo_size".to_string(), nat(MAX_MEMO_SIZE)),
+        (
+            "icrc37:max_approvals_per_token_or_collection".to_string(),
+            nat(icrc37::MAX_APPROVALS),
+        ),
+        (
+            "icrc37:max_revoke_approvals".to_string(),
+            nat(icrc37::MAX_REVOKE_APPROVALS),
+        ),
+    ]
+}
+
+/// Returns the metadata of the tokens, taken from the inscription ID and the
+/// content type and metadata of inscriptions revealed by the canister.
+pub fn token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|token_id| {
+            let inscription_id = inscription_id(token_id)?;
+            custody::get(inscription_id)?;
+
+            let mut metadata = vec![(
+                "ord:inscription_id".to_string(),
+                Value::Text(inscription_id.to_string()),
+            )];
+
+            if let Some(info) = catalog::get(inscription_id) {
+                if let Some(content_type) = info.content_type {
+                    metadata.push(("ord:content_type".to_string(), Value::Text(content_type)));
+                }
+                if let Some(inscription_metadata) = info.metadata {
+                    metadata.push((
+                        "ord:metadata".to_string(),
+                        Value::Blob(inscription_metadata),
+                    ));
+                }
+            }
+
+            Some(metadata)
+        })
+        .collect()
+}
+
+pub fn owners_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    token_ids
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|token_id| owner_of(token_id).map(Account::of))
+        .collect()
+}
+
+pub fn balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    let custodies = custody::all();
+
+    accounts
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|account| {
+            if !account.has_default_subaccount() {
+                return Nat::from(0u8);
+            }
+            Nat::from(
+                custodies
+                    .iter()
+                    .filter(|(_, custody)| custody.owner == account.owner)
+                    .count(),
+            )
+        })
+        .collect()
+}
+
+/// Returns the token IDs in ascending order, optionally only those of `owner`.
+pub fn tokens(owner: Option<&Account>, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    if owner.is_some_and(|owner| !owner.has_default_subaccount()) {
+        return vec![];
+    }
+
+    let mut token_ids: Vec<Nat> = custody::all()
+        .into_iter()
+        .filter(|(_, custody)| owner.is_none_or(|owner| custody.owner == owner.owner))
+        .map(|(inscription_id, _)| token_id(inscription_id))
+        .filter(|token_id| prev.as_ref().is_none_or(|prev| token_id > prev))
+        .collect();
+    token_ids.sort();
+    token_ids.truncate(take_value(take));
+
+    token_ids
+}
+
+/// Transfers tokens of the caller. Transferring a token to the canister
+/// burns it and withdraws its inscription to the bitcoin address given as
+/// the UTF-8 encoded memo, with the fee paid from the caller's wallet.
+pub async fn transfer(
+    caller: Principal,
+    derivation_path: Vec<Vec<u8>>,
+    args: Vec<TransferArg>,
+) -> Vec<Option<TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(0u8),
+            message: format!(
+                "At most {} transfers are allowed per call",
+                MAX_UPDATE_BATCH_SIZE
+            ),
+        }))];
+    }
+
+    let mut results = Vec::with_capacity(args.len());
+    for arg in args {
+        results.push(Some(
+            transfer_token(caller, derivation_path.clone(), arg).await,
+        ));
+    }
+    results
+}
+
+async fn transfer_token(
+    caller: Principal,
+    derivation_path: Vec<Vec<u8>>,
+    arg: TransferArg,
+) -> TransferResult {
+    if arg
+        .memo
+        .as_ref()
+        .is_some_and(|memo| memo.len() > MAX_MEMO_SIZE)
+    {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(0u8),
+            message: format!("Memo must not exceed {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+
+    let inscription_id = inscription_id(&arg.token_id).ok_or(TransferError::NonExistingTokenId)?;
+    let custody = custody::get(inscription_id).ok_or(TransferError::NonExistingTokenId)?;
+    if custody.owner != caller || !is_default_subaccount(&arg.from_subaccount) {
+        return Err(TransferError::Unauthorized);
+    }
+
+    if arg.to.owner == ic_cdk::id() {
+        let btc_address = arg
+            .memo
+            .and_then(|memo| String::from_utf8(memo).ok())
+            .ok_or(TransferError::InvalidRecipient)?;
+        let network = NETWORK.with(|n| n.get());
+
+        bitcoin_wallet::withdraw_inscription(
+            network,
+            derivation_path,
+            caller,
+            inscription_id.to_string(),
+            btc_address,
+            BURN_FEE_RATE,
+        )
+        .await
+        .map_err(|message| TransferError::GenericError {
+            error_code: Nat::from(0u8),
+            message,
+        })?;
+
+        return Ok(next_tx_index());
+    }
+
+    if !arg.to.has_default_subaccount()
+        || arg.to.owner == caller
+        || arg.to.owner == Principal::anonymous()
+    {
+        return Err(TransferError::InvalidRecipient);
+    }
+
+    move_token(inscription_id, caller, arg.to.owner);
+
+    Ok(next_tx_index())
+}
+
+/// Moves a token held in custody to a new owner, revoking its approvals.
+pub fn move_token(inscription_id: InscriptionId, from: Principal, to: Principal) {
+    custody::transfer(inscription_id, from, to).expect("token should be owned by the sender");
+    icrc37::clear_token_approvals(inscription_id);
+}
+
+pub fn supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-37".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-37".to_string(),
+        },
+    ]
+}
+
+/// Returns the number of entries to return for the given take value.
+pub fn take_value(take: Option<Nat>) -> usize {
+    take.map_or(DEFAULT_TAKE_VALUE, |take| {
+        usize::try_from(&take.0).map_or(MAX_TAKE_VALUE, |take| take.min(MAX_TAKE_VALUE))
+    })
+}
+
+pub fn is_default_subaccount(subaccount: &Option<Vec<u8>>) -> bool {
+    subaccount
+        .as_ref()
+        .is_none_or(|subaccount| subaccount.iter().all(|byte| *byte == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn token_ids_round_trip() {
+        for id in [
+            format!("{}i0", "00".repeat(32)),
+            format!("{}i1", "ab".repeat(32)),
+            format!("{}i4294967295", "ff".repeat(32)),
+        ] {
+            let id = InscriptionId::from_str(&id).unwrap();
+            assert_eq!(inscription_id(&token_id(id)), Some(id));
+        }
+    }
+
+    #[test]
+    fn token_ids_follow_the_displayed_txid() {
+        let txid = format!("{}01", "00".repeat(31));
+
+        assert_eq!(
+            token_id(InscriptionId::from_str(&format!("{}i2", txid)).unwrap()),
+            Nat::from((1u64 << 32) + 2)
+        );
+    }
+
+    #[test]
+    fn token_ids_beyond_36_bytes_are_rejected() {
+        let max =
+            token_id(InscriptionId::from_str(&format!("{}i4294967295", "ff".repeat(32))).unwrap());
+
+        assert_eq!(inscription_id(&(max + Nat::from(1u8))), None);
+    }
+}
@@ -6,12 +6,12 @@ use bitcoin::blockdata::{
     script::{self, PushBytesBuf},
 };
 
-use std::{convert::TryInto, mem};
+use std::{collections::BTreeMap, convert::TryInto, mem};
 
 use serde::{Deserialize, Serialize};
 
-const PROTOCOL_ID: [u8; 3] = *b"ord";
-const BODY_TAG: [u8; 0] = [];
+pub(crate) const PROTOCOL_ID: [u8; 3] = *b"ord";
+pub(crate) const BODY_TAG: [u8; 0] = [];
 /// The maximum allowed script size.
 pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
 
@@ -27,6 +27,7 @@ pub(crate) enum Tag {
     Metaprotocol,
     ContentEncoding,
     Delegate,
+    Rune,
     #[allow(unused)]
     Nop,
 }
@@ -47,10 +48,40 @@ impl Tag {
             Self::Metaprotocol => &[7],
             Self::ContentEncoding => &[9],
             Self::Delegate => &[11],
+            Self::Rune => &[13],
             Self::Nop => &[255],
         }
     }
 
+    /// Removes the value of the tag from the fields of an envelope. Values of
+    /// duplicate fields other than the first are left in place, except for
+    /// chunked tags whose values are concatenated.
+    pub(crate) fn take(self, fields: &mut BTreeMap<&[u8], Vec<&[u8]>>) -> Option<Vec<u8>> {
+        if self.is_chunked() {
+            let value = fields.remove(self.bytes())?;
+
+            if value.is_empty() {
+                None
+            } else {
+                Some(value.into_iter().flatten().cloned().collect())
+            }
+        } else {
+            let values = fields.get_mut(self.bytes())?;
+
+            if values.is_empty() {
+                None
+            } else {
+                let first = values.remove(0).to_vec();
+
+                if values.is_empty() {
+                    fields.remove(self.bytes());
+                }
+
+                Some(first)
+            }
+        }
+    }
+
     pub(crate) fn encode(self, builder: &mut script::Builder, value: &Option<Vec<u8>>) {
         if let Some(value) = value {
             let mut tmp = script::Builder::new();
@@ -85,6 +116,7 @@ pub struct Inscription {
     pub metaprotocol: Option<Vec<u8>>,
     pub parent: Option<Vec<u8>>,
     pub pointer: Option<Vec<u8>>,
+    pub rune: Option<Vec<u8>>,
     pub unrecognized_even_field: bool,
 }
 
@@ -97,6 +129,32 @@ impl Inscription {
         }
     }
 
+    pub(crate) fn pointer_value(pointer: u64) -> Vec<u8> {
+        let mut bytes = pointer.to_le_bytes().to_vec();
+
+        while bytes.last().copied() == Some(0) {
+            bytes.pop();
+        }
+
+        bytes
+    }
+
+    /// Decodes the pointer, ignoring pointers that don't fit into a `u64`.
+    pub(crate) fn pointer(&self) -> Option<u64> {
+        let value = self.pointer.as_ref()?;
+
+        if value.iter().skip(8).any(|byte| *byte != 0) {
+            return None;
+        }
+
+        let mut pointer = [0; 8];
+        for (i, byte) in value.iter().take(8).enumerate() {
+            pointer[i] = *byte;
+        }
+
+        Some(u64::from_le_bytes(pointer))
+    }
+
     pub(crate) fn append_reveal_script_to_builder(
         &self,
         mut builder: script::Builder,
@@ -113,6 +171,7 @@ impl Inscription {
         Tag::Delegate.encode(&mut builder, &self.delegate);
         Tag::Pointer.encode(&mut builder, &self.pointer);
         Tag::Metadata.encode(&mut builder, &self.metadata);
+        Tag::Rune.encode(&mut builder, &self.rune);
 
         if let Some(body) = &self.body {
             builder = builder.push_slice(BODY_TAG);
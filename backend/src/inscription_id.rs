@@ -0,0 +1,61 @@
+// Adapted from https://github.com/ordinals/ord/blob/master/src/inscriptions/inscription_id.rs
+
+use bitcoin::{hashes::Hash, Txid};
+
+use std::{fmt, str::FromStr};
+
+/// Identifies an inscription by the reveal transaction and its index within it.
+#[derive(Debug, PartialEq, Copy, Clone, Eq, PartialOrd, Ord)]
+pub struct InscriptionId {
+    pub txid: Txid,
+    pub index: u32,
+}
+
+impl InscriptionId {
+    /// Decodes the value of a parent or delegate tag, which holds the txid
+    /// followed by the index in little endian with trailing zeros removed.
+    pub fn from_value(value: &[u8]) -> Option<Self> {
+        if value.len() < Txid::LEN || value.len() > Txid::LEN + 4 {
+            return None;
+        }
+
+        let (txid, index) = value.split_at(Txid::LEN);
+
+        if index.last() == Some(&0) {
+            return None;
+        }
+
+        let mut bytes = [0; 4];
+        bytes[..index.len()].copy_from_slice(index);
+
+        Some(Self {
+            txid: Txid::from_slice(txid).ok()?,
+            index: u32::from_le_bytes(bytes),
+        })
+    }
+}
+
+impl fmt::Display for InscriptionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}i{}", self.txid, self.index)
+    }
+}
+
+impl FromStr for InscriptionId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (txid, index) = s
+            .split_once('i')
+            .ok_or_else(|| format!("Invalid inscription ID: {}", s))?;
+
+        Ok(Self {
+            txid: txid
+                .parse()
+                .map_err(|e| format!("Invalid inscription ID {}: {}", s, e))?,
+            index: index
+                .parse()
+                .map_err(|e| format!("Invalid inscription ID {}: {}", s, e))?,
+        })
+    }
+}
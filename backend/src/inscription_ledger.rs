@@ -0,0 +1,103 @@
+use crate::inscription_id::InscriptionId;
+use crate::types::{InscribedSat, InscribedUtxo};
+use crate::{Memory, INSCRIPTION_LEDGER_MEMORY_ID, MEMORY_MANAGER};
+use bitcoin::{OutPoint, Txid};
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+use std::str::FromStr;
+
+thread_local! {
+    // The inscriptions carried by the wallets' outputs, keyed by outpoint.
+    //
+    // Only inscriptions revealed or moved by the canister itself are
+    // recorded, so outputs that received inscriptions from elsewhere are not
+    // protected from being spent as plain bitcoin.
+    static INSCRIPTION_LEDGER: RefCell<StableBTreeMap<String, InscribedUtxo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(INSCRIPTION_LEDGER_MEMORY_ID)),
+        ));
+}
+
+pub fn insert(outpoint: OutPoint, inscribed_utxo: InscribedUtxo) {
+    INSCRIPTION_LEDGER.with(|l| l.borrow_mut().insert(outpoint.to_string(), inscribed_utxo));
+}
+
+pub fn remove(outpoint: OutPoint) -> Option<InscribedUtxo> {
+    INSCRIPTION_LEDGER.with(|l| l.borrow_mut().remove(&outpoint.to_string()))
+}
+
+pub fn get(outpoint: OutPoint) -> Option<InscribedUtxo> {
+    INSCRIPTION_LEDGER.with(|l| l.borrow().get(&outpoint.to_string()))
+}
+
+/// Returns whether the output carries inscriptions and must not be spent as
+/// plain bitcoin.
+pub fn contains(outpoint: OutPoint) -> bool {
+    INSCRIPTION_LEDGER.with(|l| l.borrow().contains_key(&outpoint.to_string()))
+}
+
+/// Returns the output carrying an inscription and the inscribed sat, if the
+/// canister has moved the inscription there.
+pub fn locate(inscription_id: InscriptionId) -> Option<(OutPoint, InscribedSat)> {
+    let inscription_id = inscription_id.to_string();
+    INSCRIPTION_LEDGER.with(|l| {
+        l.borrow().iter().find_map(|(outpoint, inscribed_utxo)| {
+            let inscribed_sat = inscribed_utxo
+                .inscriptions
+                .into_iter()
+                .find(|inscribed_sat| inscribed_sat.inscription_id == inscription_id)?;
+            Some((
+                outpoint.parse().expect("outpoint should be valid"),
+                inscribed_sat,
+            ))
+        })
+    })
+}
+
+/// Moves the outputs of a transaction to the transaction replacing it, which
+/// has the same outputs. The inscriptions revealed by the transaction are
+/// revealed by its replacement instead.
+pub fn replace_transaction(old_txid: Txid, new_txid: Txid) {
+    for (outpoint, mut inscribed_utxo) in remove_transaction(old_txid) {
+        for inscribed_sat in inscribed_utxo.inscriptions.iter_mut() {
+            let mut inscription_id = InscriptionId::from_str(&inscribed_sat.inscription_id)
+                .expect("inscription ID should be valid");
+            if inscription_id.txid == old_txid {
+                inscription_id.txid = new_txid;
+                inscribed_sat.inscription_id = inscription_id.to_string();
+            }
+        }
+        insert(
+            OutPoint {
+                txid: new_txid,
+                vout: outpoint.vout,
+            },
+            inscribed_utxo,
+        );
+    }
+}
+
+/// Forgets the outputs of a transaction, e.g. because it has been cancelled,
+/// and returns them.
+pub fn remove_transaction(txid: Txid) -> Vec<(OutPoint, InscribedUtxo)> {
+    INSCRIPTION_LEDGER.with(|l| {
+        let mut ledger = l.borrow_mut();
+        let outputs: Vec<(OutPoint, InscribedUtxo)> = ledger
+            .iter()
+            .map(|(outpoint, inscribed_utxo)| {
+                (
+                    outpoint
+                        .parse::<OutPoint>()
+                        .expect("outpoint should be valid"),
+                    inscribed_utxo,
+                )
+            })
+            .filter(|(outpoint, _)| outpoint.txid == txid)
+            .collect();
+        for (outpoint, _) in &outputs {
+            ledger.remove(&outpoint.to_string());
+        }
+        outputs
+    })
+}
@@ -0,0 +1,288 @@
+use crate::types::{
+    FailedReveal, Job, JobCommit, PendingReveal, PreparedReveal, Reveal, RevealStatus,
+};
+use crate::{
+    Memory, FAILED_REVEALS_MEMORY_ID, JOBS_MEMORY_ID, JOB_COMMITS_MEMORY_ID, MEMORY_MANAGER,
+    PENDING_REVEALS_MEMORY_ID, PREPARED_REVEALS_COUNT_MEMORY_ID, PREPARED_REVEALS_MEMORY_ID,
+};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+
+use std::cell::RefCell;
+
+thread_local! {
+    // The inscription jobs, keyed by job ID.
+    static JOBS: RefCell<StableBTreeMap<u64, Job, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(JOBS_MEMORY_ID)),
+    ));
+
+    // The commit transactions of the jobs that can still be replaced, keyed by
+    // job ID.
+    static JOB_COMMITS: RefCell<StableBTreeMap<u64, JobCommit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(JOB_COMMITS_MEMORY_ID)),
+        ));
+
+    // The reveal transactions waiting for their commit transaction to confirm,
+    // keyed by job ID.
+    static PENDING_REVEALS: RefCell<StableBTreeMap<u64, PendingReveal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_REVEALS_MEMORY_ID)),
+        ));
+
+    // The reveal transactions that failed to be sent, keyed by job ID and the
+    // index of the reveal within the job.
+    static FAILED_REVEALS: RefCell<StableBTreeMap<(u64, u32), FailedReveal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FAILED_REVEALS_MEMORY_ID)),
+        ));
+
+    // The inscriptions funded by their owners, keyed by ID.
+    static PREPARED_REVEALS: RefCell<StableBTreeMap<u64, PreparedReveal, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PREPARED_REVEALS_MEMORY_ID)),
+        ));
+
+    // The number of IDs handed out for prepared inscriptions.
+    static PREPARED_REVEALS_COUNT: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PREPARED_REVEALS_COUNT_MEMORY_ID)),
+            0,
+        )
+        .expect("initializing the prepared reveals count should work"),
+    );
+
+    // The ID of the last prepared inscription whose commit address was
+    // checked for a payment, so that the next round of checks continues after
+    // it.
+    static LAST_CHECKED_PREPARED_REVEAL: RefCell<Option<u64>> = const { RefCell::new(None) };
+}
+
+/// Stores a new job and returns its ID.
+pub fn insert(job: Job) -> u64 {
+    JOBS.with(|j| {
+        let mut jobs = j.borrow_mut();
+        let job_id = jobs.last_key_value().map_or(0, |(id, _)| id + 1);
+        jobs.insert(job_id, job);
+        job_id
+    })
+}
+
+pub fn get(job_id: u64) -> Option<Job> {
+    JOBS.with(|j| j.borrow().get(&job_id))
+}
+
+/// Replaces a stored job.
+pub fn update(job_id: u64, job: Job) {
+    JOBS.with(|j| j.borrow_mut().insert(job_id, job));
+}
+
+/// Updates the status of the reveal transaction at `index` of the job.
+pub fn set_reveal_status(job_id: u64, index: usize, status: RevealStatus) {
+    JOBS.with(|j| {
+        let mut jobs = j.borrow_mut();
+        let mut job = jobs.get(&job_id).expect("job should exist");
+        job.reveals[index].status = status;
+        jobs.insert(job_id, job);
+    });
+}
+
+pub fn insert_commit(job_id: u64, job_commit: JobCommit) {
+    JOB_COMMITS.with(|c| c.borrow_mut().insert(job_id, job_commit));
+}
+
+pub fn get_commit(job_id: u64) -> Option<JobCommit> {
+    JOB_COMMITS.with(|c| c.borrow().get(&job_id))
+}
+
+pub fn remove_commit(job_id: u64) {
+    JOB_COMMITS.with(|c| c.borrow_mut().remove(&job_id));
+}
+
+/// Defers sending the reveal transaction of a job with a single reveal.
+pub fn insert_pending_reveal(job_id: u64, pending_reveal: PendingReveal) {
+    PENDING_REVEALS.with(|p| p.borrow_mut().insert(job_id, pending_reveal));
+}
+
+pub fn pending_reveals() -> Vec<(u64, PendingReveal)> {
+    PENDING_REVEALS.with(|p| p.borrow().iter().collect())
+}
+
+pub fn get_pending_reveal(job_id: u64) -> Option<PendingReveal> {
+    PENDING_REVEALS.with(|p| p.borrow().get(&job_id))
+}
+
+pub fn remove_pending_reveal(job_id: u64) {
+    PENDING_REVEALS.with(|p| p.borrow_mut().remove(&job_id));
+}
+
+/// Keeps a reveal transaction that failed to be sent, so that it is sent again.
+pub fn insert_failed_reveal(job_id: u64, index: usize, failed_reveal: FailedReveal) {
+    FAILED_REVEALS.with(|f| f.borrow_mut().insert((job_id, index as u32), failed_reveal));
+}
+
+pub fn failed_reveals() -> Vec<((u64, u32), FailedReveal)> {
+    FAILED_REVEALS.with(|f| f.borrow().iter().collect())
+}
+
+pub fn get_failed_reveal(job_id: u64, index: usize) -> Option<FailedReveal> {
+    FAILED_REVEALS.with(|f| f.borrow().get(&(job_id, index as u32)))
+}
+
+pub fn remove_failed_reveal(job_id: u64, index: usize) {
+    FAILED_REVEALS.with(|f| f.borrow_mut().remove(&(job_id, index as u32)));
+}
+
+/// Forgets the failed reveal transactions of a job, e.g. because they have
+/// been replaced.
+pub fn remove_failed_reveals(job_id: u64) {
+    FAILED_REVEALS.with(|f| {
+        let mut failed_reveals = f.borrow_mut();
+        let keys: Vec<(u64, u32)> = failed_reveals
+            .range((job_id, 0)..=(job_id, u32::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            failed_reveals.remove(&key);
+        }
+    });
+}
+
+/// Reserves the ID of a prepared inscription. The ID is handed out before
+/// the inscription is stored, so that its keys can be derived from it.
+pub fn next_prepared_id() -> u64 {
+    PREPARED_REVEALS_COUNT.with(|c| {
+        let mut count = c.borrow_mut();
+        let id = *count.get();
+        count
+            .set(id + 1)
+            .expect("setting the prepared reveals count should work");
+        id
+    })
+}
+
+pub fn insert_prepared_reveal(id: u64, prepared_reveal: PreparedReveal) {
+    PREPARED_REVEALS.with(|p| p.borrow_mut().insert(id, prepared_reveal));
+}
+
+pub fn get_prepared_reveal(id: u64) -> Option<PreparedReveal> {
+    PREPARED_REVEALS.with(|p| p.borrow().get(&id))
+}
+
+/// Returns the prepared inscriptions that haven't expired at `now` and whose
+/// reveal transaction hasn't been sent, either because the commit address
+/// hasn't been funded yet or because sending it failed.
+pub fn unsent_reveals(now: u64) -> Vec<(u64, PreparedReveal)> {
+    PREPARED_REVEALS.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, prepared_reveal)| {
+                let inscription = &prepared_reveal.inscription;
+                inscription.expires_at > now
+                    && inscription
+                        .reveal
+                        .as_ref()
+                        .is_none_or(|reveal| matches!(reveal.status, RevealStatus::Failed(_)))
+            })
+            .collect()
+    })
+}
+
+/// Returns at most `count` of the unsent prepared inscriptions at `now`,
+/// continuing after the ones returned by the previous call and wrapping around,
+/// so that every prepared inscription is checked in turn.
+pub fn next_unsent_reveals(now: u64, count: usize) -> Vec<(u64, PreparedReveal)> {
+    let unsent_reveals = unsent_reveals(now);
+    let last_checked = LAST_CHECKED_PREPARED_REVEAL.with(|l| *l.borrow());
+    let start = last_checked.map_or(0, |last_checked| {
+        unsent_reveals.partition_point(|(id, _)| *id <= last_checked)
+    });
+
+    let next: Vec<_> = unsent_reveals[start..]
+        .iter()
+        .chain(&unsent_reveals[..start])
+        .take(count)
+        .cloned()
+        .collect();
+    if let Some((id, _)) = next.last() {
+        LAST_CHECKED_PREPARED_REVEAL.with(|l| *l.borrow_mut() = Some(*id));
+    }
+    next
+}
+
+/// Records the reveal transaction of a prepared inscription.
+pub fn set_prepared_reveal(id: u64, reveal: Reveal) {
+    PREPARED_REVEALS.with(|p| {
+        let mut prepared_reveals = p.borrow_mut();
+        let mut prepared_reveal = prepared_reveals
+            .get(&id)
+            .expect("prepared reveal should exist");
+        prepared_reveal.inscription.reveal = Some(reveal);
+        prepared_reveals.insert(id, prepared_reveal);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PreparedInscription;
+    use candid::Principal;
+
+    fn prepared_reveal(id: u64, expires_at: u64) -> PreparedReveal {
+        PreparedReveal {
+            inscription: PreparedInscription {
+                id,
+                commit_address: String::new(),
+                amount: 0,
+                expires_at,
+                reveal: None,
+            },
+            owner: Principal::anonymous(),
+            derivation_path: vec![],
+            reveal_script: vec![],
+            control_block: vec![],
+            reveal_outputs: vec![],
+            fee: 0,
+            refund: None,
+        }
+    }
+
+    fn ids(prepared_reveals: Vec<(u64, PreparedReveal)>) -> Vec<u64> {
+        prepared_reveals.into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn unsent_reveals_are_checked_in_turn() {
+        for id in 0..5 {
+            insert_prepared_reveal(id, prepared_reveal(id, 10));
+        }
+        insert_prepared_reveal(5, prepared_reveal(5, 1));
+
+        assert_eq!(ids(next_unsent_reveals(2, 2)), vec![0, 1]);
+        assert_eq!(ids(next_unsent_reveals(2, 2)), vec![2, 3]);
+        assert_eq!(ids(next_unsent_reveals(2, 2)), vec![4, 0]);
+        assert_eq!(ids(next_unsent_reveals(2, 10)), vec![1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn sent_reveals_are_not_checked() {
+        insert_prepared_reveal(0, prepared_reveal(0, 10));
+        insert_prepared_reveal(1, prepared_reveal(1, 10));
+        set_prepared_reveal(
+            0,
+            Reveal {
+                txid: String::new(),
+                status: RevealStatus::Sent,
+            },
+        );
+
+        assert_eq!(ids(next_unsent_reveals(0, 10)), vec![1]);
+        set_prepared_reveal(
+            0,
+            Reveal {
+                txid: String::new(),
+                status: RevealStatus::Failed("rejected".to_string()),
+            },
+        );
+        assert_eq!(ids(next_unsent_reveals(0, 10)), vec![0, 1]);
+    }
+}
@@ -1,13 +1,88 @@
 mod bitcoin_api;
 mod bitcoin_wallet;
+mod brc20;
+mod broadcasts;
+mod catalog;
+mod credits;
+mod custody;
+mod decoder;
 mod ecdsa_api;
+mod envelope;
+mod guard;
+mod icrc37;
+mod icrc7;
 mod inscription;
+mod inscription_id;
+mod inscription_ledger;
+mod jobs;
+mod metaprotocol;
+mod ord_api;
+mod rune_ledger;
+mod runes;
+mod sat_point;
 mod schnorr_api;
 mod types;
+mod utxo_cache;
 
+use candid::{Nat, Principal};
 use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl,
+};
+use icrc37::{
+    ApprovalInfo, ApproveCollectionArg, ApproveCollectionResult, ApproveTokenArg,
+    ApproveTokenResult, IsApprovedArg, RevokeCollectionApprovalArg, RevokeCollectionApprovalResult,
+    RevokeTokenApprovalArg, RevokeTokenApprovalResponse, TokenApproval, TransferFromArg,
+    TransferFromResult,
+};
+use icrc7::{Account, StandardRecord, TransferArg, TransferResult, Value};
+use inscription_id::InscriptionId;
+use types::{
+    DecodedTransaction, EtchingRequest, InscriptionRequest, Job, PreparedInscription, RefundInfo,
+};
 
 use std::cell::{Cell, RefCell};
+use std::str::FromStr;
+use std::time::Duration;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// The stable memory regions of the canister's stable structures.
+const JOBS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const PENDING_REVEALS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const RUNE_LEDGER_MEMORY_ID: MemoryId = MemoryId::new(2);
+const CUSTODY_MEMORY_ID: MemoryId = MemoryId::new(3);
+const CATALOG_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ICRC7_TX_INDEX_MEMORY_ID: MemoryId = MemoryId::new(5);
+const TOKEN_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(6);
+const COLLECTION_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const CREDITS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const SEEN_OUTPOINTS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const PREPARED_REVEALS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const PREPARED_REVEALS_COUNT_MEMORY_ID: MemoryId = MemoryId::new(11);
+const JOB_COMMITS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const BROADCASTS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const UTXO_CACHE_MEMORY_ID: MemoryId = MemoryId::new(14);
+const UTXO_TIPS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const UNCONFIRMED_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const BRC20_DEPLOYMENTS_MEMORY_ID: MemoryId = MemoryId::new(17);
+const INSCRIPTION_LEDGER_MEMORY_ID: MemoryId = MemoryId::new(18);
+const FAILED_REVEALS_MEMORY_ID: MemoryId = MemoryId::new(19);
+const PENDING_PREMINES_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+// How often to check whether pending reveal transactions can be sent and
+// whether prepared inscriptions have been funded, to retry the reveal
+// transactions that failed to be sent, and to record the premines of
+// confirmed etchings.
+const PENDING_REVEALS_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often to rebroadcast the transactions that haven't confirmed.
+const REBROADCAST_INTERVAL: Duration = Duration::from_secs(600);
+
+// How many times a transaction is rebroadcast unless configured otherwise.
+const DEFAULT_MAX_REBROADCAST_ATTEMPTS: u32 = 6;
 
 thread_local! {
     // The bitcoin network to connect to.
@@ -16,18 +91,63 @@ thread_local! {
     // When deploying to the IC this should be `Testnet` or 'Mainnet'.
     static NETWORK: Cell<BitcoinNetwork> = Cell::new(BitcoinNetwork::Regtest);
 
-    // The derivation path to use for ECDSA secp256k1.
-    static DERIVATION_PATH: Vec<Vec<u8>> = vec![];
-
     // The ECDSA and Schnor key name.
     static KEY_NAME: RefCell<String> = RefCell::new(String::from(""));
 
+    // The URL of the ord indexer that confirms deposited inscriptions, locates
+    // the inscriptions held by the wallets and looks up runes.
+    static ORD_URL: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    // How many times a transaction may be rebroadcast.
+    static MAX_REBROADCAST_ATTEMPTS: Cell<u32> =
+        const { Cell::new(DEFAULT_MAX_REBROADCAST_ATTEMPTS) };
+
+    // The memory manager that splits stable memory between the stable structures.
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+}
+
+// Returns the caller, trapping for the anonymous principal, which anyone can
+// call as and so must not own a wallet or inscriptions.
+fn authenticated_caller() -> Principal {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        ic_cdk::trap("the anonymous principal has no wallet");
+    }
+    caller
+}
+
+// Returns the derivation path of the caller's keys, so that every principal
+// has its own wallet with its own address, UTXOs and inscriptions.
+fn caller_derivation_path() -> Vec<Vec<u8>> {
+    vec![authenticated_caller().as_slice().to_vec()]
 }
 
 #[ic_cdk::init]
-pub fn init(network: BitcoinNetwork) {
+pub fn init(
+    network: BitcoinNetwork,
+    ord_url: Option<String>,
+    max_rebroadcast_attempts: Option<u32>,
+) {
     NETWORK.with(|n| n.set(network));
 
+    // The registry lives on the heap, so the built-in metaprotocols are
+    // registered again after every upgrade.
+    metaprotocol::register(Box::new(brc20::Brc20));
+
+    if let Some(max_rebroadcast_attempts) = max_rebroadcast_attempts {
+        MAX_REBROADCAST_ATTEMPTS.with(|m| m.set(max_rebroadcast_attempts));
+    }
+
+    ORD_URL.with(|url| {
+        url.replace(match network {
+            // For local development, we use the ord server of the docker setup.
+            BitcoinNetwork::Regtest => ord_url.or(Some(String::from("http://localhost:8080"))),
+            BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => ord_url,
+        })
+    });
+
     KEY_NAME.with(|key_name| {
         key_name.replace(String::from(match network {
             // For local development, we use a special test key with dfx.
@@ -36,6 +156,28 @@ pub fn init(network: BitcoinNetwork) {
             BitcoinNetwork::Mainnet | BitcoinNetwork::Testnet => "key_1",
         }))
     });
+
+    ic_cdk_timers::set_timer_interval(PENDING_REVEALS_INTERVAL, move || {
+        ic_cdk::spawn(bitcoin_wallet::send_pending_reveals(network));
+        ic_cdk::spawn(bitcoin_wallet::send_funded_reveals(network));
+        ic_cdk::spawn(bitcoin_wallet::retry_failed_reveals(network));
+        if let Some(ord_url) = ORD_URL.with(|url| url.borrow().clone()) {
+            ic_cdk::spawn(bitcoin_wallet::record_premines(ord_url));
+        }
+    });
+
+    ic_cdk_timers::set_timer_interval(REBROADCAST_INTERVAL, move || {
+        ic_cdk::spawn(broadcasts::rebroadcast_unconfirmed(network));
+    });
+}
+
+#[ic_cdk::post_upgrade]
+pub fn post_upgrade(
+    network: BitcoinNetwork,
+    ord_url: Option<String>,
+    max_rebroadcast_attempts: Option<u32>,
+) {
+    init(network, ord_url, max_rebroadcast_attempts);
 }
 
 /// Returns the balance of the given bitcoin address.
@@ -45,29 +187,643 @@ pub async fn get_balance(address: String) -> u64 {
     bitcoin_api::get_balance(network, address).await
 }
 
+/// Inscribes an inscription with a commit and a reveal transaction, and
+/// returns their IDs together with the ID of the job tracking them.
+///
+/// If a hex-encoded 32-byte `nums_tweak` is given, the internal key of the
+/// commit output is the BIP-341 NUMS point tweaked by it, so that the commit
+/// output can only be spent through the reveal script.
 #[ic_cdk::update]
+#[allow(clippy::too_many_arguments)]
 pub async fn inscribe(
     content_type: String,
     body: String,
     recipient: Option<String>,
     fee_rate: Option<u64>,
-) -> (String, String) {
+    metaprotocol: Option<String>,
+    pointer: Option<u64>,
+    payments: Option<Vec<(String, u64)>>,
+    sat_point: Option<String>,
+    nums_tweak: Option<String>,
+) -> Result<(String, String, u64), String> {
     let network = NETWORK.with(|n| n.get());
     let content_type = Some(content_type.as_bytes().to_vec());
     let body = Some(body.as_bytes().to_vec());
     bitcoin_wallet::inscribe(
         network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
         content_type,
         body,
         recipient,
         fee_rate.unwrap_or(10),
+        metaprotocol,
+        pointer,
+        payments.unwrap_or_default(),
+        sat_point,
+        nums_tweak,
+    )
+    .await
+}
+
+/// Inscribes onto the sat of an inscription held by the caller's wallet. The
+/// inscription is located with the configured ord indexer unless the canister
+/// has moved it itself.
+#[ic_cdk::update]
+pub async fn reinscribe(
+    inscription_id: String,
+    content_type: String,
+    body: String,
+    recipient: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<(String, String, u64), String> {
+    let network = NETWORK.with(|n| n.get());
+    let ord_url = ORD_URL.with(|url| url.borrow().clone());
+    bitcoin_wallet::reinscribe(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        ord_url,
+        inscription_id,
+        Some(content_type.into_bytes()),
+        Some(body.into_bytes()),
+        recipient,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Inscribes several inscriptions with a single commit and reveal transaction.
+#[ic_cdk::update]
+pub async fn inscribe_batch(
+    requests: Vec<InscriptionRequest>,
+    fee_rate: Option<u64>,
+) -> Result<(String, String, u64), String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::inscribe_batch(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        requests,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Inscribes each inscription with its own reveal transaction, all funded by
+/// a single commit transaction, and returns the ID of the job tracking them.
+#[ic_cdk::update]
+pub async fn inscribe_fan_out(
+    requests: Vec<InscriptionRequest>,
+    fee_rate: Option<u64>,
+) -> Result<u64, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::inscribe_fan_out(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        requests,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Returns the caller's credits in sats after crediting the deposits to the
+/// caller's address that have enough confirmations. The transactions of the
+/// caller's wallet are paid from these credits.
+#[ic_cdk::update]
+pub async fn get_my_credits() -> u64 {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::get_credits(network, caller_derivation_path(), ic_cdk::caller()).await
+}
+
+/// Prepares an inscription that the caller funds from their own wallet by
+/// paying the returned amount to the returned commit address. The reveal
+/// transaction is signed and sent once the payment has been confirmed, as
+/// long as the payment arrives before the prepared inscription expires. Each
+/// caller may have a limited number of prepared inscriptions waiting.
+///
+/// If a hex-encoded refund public key is given, the key can reclaim the
+/// payment if the reveal transaction is never sent, see `get_refund_info`.
+#[ic_cdk::update]
+pub async fn prepare_inscription(
+    request: InscriptionRequest,
+    refund_public_key: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<PreparedInscription, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::prepare_inscription(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        request,
+        refund_public_key,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Returns the prepared inscription with the given ID if the caller prepared
+/// it.
+#[ic_cdk::query]
+pub fn get_prepared_inscription(id: u64) -> Option<PreparedInscription> {
+    jobs::get_prepared_reveal(id)
+        .filter(|prepared_reveal| prepared_reveal.owner == ic_cdk::caller())
+        .map(|prepared_reveal| prepared_reveal.inscription)
+}
+
+/// Returns the data needed to build a transaction that reclaims the payment
+/// of a prepared inscription through its refund leaf if the caller prepared
+/// it.
+#[ic_cdk::query]
+pub fn get_refund_info(id: u64) -> Option<RefundInfo> {
+    jobs::get_prepared_reveal(id)
+        .filter(|prepared_reveal| prepared_reveal.owner == ic_cdk::caller())
+        .and_then(|prepared_reveal| prepared_reveal.refund)
+}
+
+/// Returns the job with the given ID.
+#[ic_cdk::query]
+pub fn get_job(job_id: u64) -> Option<Job> {
+    jobs::get(job_id)
+}
+
+/// Replaces the commit transaction of a job with one paying the given fee
+/// rate, signs and sends its reveal transactions again, and returns the ID of
+/// the new commit transaction.
+#[ic_cdk::update]
+pub async fn bump_commit_fee(job_id: u64, fee_rate: u64) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::bump_commit_fee(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        job_id,
+        fee_rate,
+    )
+    .await
+}
+
+/// Accelerates the commit transaction of a job by spending its change output
+/// with a fee that brings both transactions together to the given fee rate,
+/// and returns the ID of the child transaction.
+#[ic_cdk::update]
+pub async fn accelerate(job_id: u64, fee_rate: u64) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::accelerate(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        job_id,
+        fee_rate,
+    )
+    .await
+}
+
+/// Sends a transaction that the canister sent before once more, e.g. after
+/// the bitcoin adapter dropped it. Only the caller that sent the transaction
+/// and the controllers may rebroadcast it.
+#[ic_cdk::update]
+pub async fn rebroadcast(txid: String) -> Result<(), String> {
+    let network = NETWORK.with(|n| n.get());
+    broadcasts::rebroadcast(network, ic_cdk::caller(), txid).await
+}
+
+/// Cancels a job whose inscriptions haven't been revealed, sending its funds
+/// back to the wallet, and returns the ID of the cancelling transaction.
+#[ic_cdk::update]
+pub async fn cancel_inscription(job_id: u64, fee_rate: Option<u64>) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::cancel_inscription(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        job_id,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Etches a rune and returns the ID of the job tracking the etching. The
+/// reveal transaction is sent once the commit transaction has enough
+/// confirmations, and sent again on every check until it succeeds. The rune
+/// name must be unlocked at the current block height.
+#[ic_cdk::update]
+pub async fn etch_rune(
+    request: EtchingRequest,
+    recipient: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<u64, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::etch_rune(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        request,
+        recipient,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Mints a rune and returns the ID of the transaction. Runes minted to the
+/// caller's wallet are recorded in the canister's rune ledger with the amount
+/// that the configured ord indexer reports for the rune's terms.
+#[ic_cdk::update]
+pub async fn mint_rune(
+    rune_id: String,
+    destination: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    let ord_url = ORD_URL.with(|url| url.borrow().clone());
+    bitcoin_wallet::mint_rune(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        ord_url,
+        rune_id,
+        destination,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Transfers runes held by the canister and returns the ID of the transaction.
+#[ic_cdk::update]
+pub async fn transfer_runes(
+    rune_id: String,
+    amount: u128,
+    destination: String,
+    fee_rate: Option<u64>,
+) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::transfer_runes(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        rune_id,
+        amount,
+        destination,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Returns the balance of a rune held by the given wallet address according
+/// to the canister's rune ledger.
+#[ic_cdk::query]
+pub fn get_rune_balance(address: String, rune_id: String) -> u128 {
+    rune_ledger::balance(&address, &rune_id)
+}
+
+/// Moves an inscription from the caller's wallet into the canister's custody
+/// and returns the ID of the transaction.
+#[ic_cdk::update]
+pub async fn deposit_inscription(
+    inscription_id: String,
+    fee_rate: Option<u64>,
+) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    let ord_url = ORD_URL.with(|url| url.borrow().clone());
+    bitcoin_wallet::deposit_inscription(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        ord_url,
+        inscription_id,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Credits an inscription sent to the caller's address, as returned by
+/// `get_p2pkh_address`, to the caller by moving it into the canister's
+/// custody, and returns the ID of the transaction. The output must carry the
+/// inscription according to the configured ord indexer.
+#[ic_cdk::update]
+pub async fn claim_deposit(
+    txid: String,
+    vout: u32,
+    inscription_id: String,
+    fee_rate: Option<u64>,
+) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    let ord_url = ORD_URL
+        .with(|url| url.borrow().clone())
+        .ok_or("No ord indexer is configured")?;
+    bitcoin_wallet::claim_deposit(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        ord_url,
+        txid,
+        vout,
+        inscription_id,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Strips the headers from the responses of the ord indexer.
+#[ic_cdk::query]
+fn transform_ord_response(args: TransformArgs) -> HttpResponse {
+    ord_api::transform(args)
+}
+
+/// Transfers the ownership of an inscription held in custody for the caller
+/// to another principal and revokes the ICRC-37 approvals of its token. No
+/// bitcoin transaction is involved.
+#[ic_cdk::update]
+pub fn transfer_custody(inscription_id: String, to_principal: Principal) -> Result<(), String> {
+    let inscription_id = InscriptionId::from_str(&inscription_id)?;
+    let caller = authenticated_caller();
+    if to_principal == Principal::anonymous() {
+        return Err("Inscriptions can't be held for the anonymous principal".to_string());
+    }
+    custody::get_owned(inscription_id, caller)?;
+    icrc7::move_token(inscription_id, caller, to_principal);
+    Ok(())
+}
+
+/// Sends an inscription held in custody for the caller to a bitcoin address
+/// and returns the ID of the transaction. The fee is paid from the caller's
+/// wallet.
+#[ic_cdk::update]
+pub async fn withdraw_inscription(
+    inscription_id: String,
+    btc_address: String,
+    fee_rate: Option<u64>,
+) -> Result<String, String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::withdraw_inscription(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        inscription_id,
+        btc_address,
+        fee_rate.unwrap_or(10),
+    )
+    .await
+}
+
+/// Returns the principal owning an inscription held in custody.
+#[ic_cdk::query]
+pub fn get_custody_owner(inscription_id: String) -> Option<Principal> {
+    let inscription_id = InscriptionId::from_str(&inscription_id).ok()?;
+    custody::get(inscription_id).map(|custody| custody.owner)
+}
+
+#[ic_cdk::query]
+pub fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    icrc7::collection_metadata()
+}
+
+#[ic_cdk::query]
+pub fn icrc7_symbol() -> String {
+    icrc7::SYMBOL.to_string()
+}
+
+#[ic_cdk::query]
+pub fn icrc7_name() -> String {
+    icrc7::NAME.to_string()
+}
+
+#[ic_cdk::query]
+pub fn icrc7_description() -> Option<String> {
+    Some(icrc7::DESCRIPTION.to_string())
+}
+
+#[ic_cdk::query]
+pub fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[ic_cdk::query]
+pub fn icrc7_total_supply() -> Nat {
+    icrc7::total_supply()
+}
+
+#[ic_cdk::query]
+pub fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+pub fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(icrc7::MAX_QUERY_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+pub fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(icrc7::MAX_UPDATE_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+pub fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(icrc7::DEFAULT_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+pub fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(icrc7::MAX_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+pub fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(icrc7::MAX_MEMO_SIZE))
+}
+
+#[ic_cdk::query]
+pub fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[ic_cdk::query]
+pub fn icrc7_tx_window() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+pub fn icrc7_permitted_drift() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+pub fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    icrc7::token_metadata(token_ids)
+}
+
+#[ic_cdk::query]
+pub fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    icrc7::owners_of(token_ids)
+}
+
+#[ic_cdk::query]
+pub fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    icrc7::balance_of(accounts)
+}
+
+#[ic_cdk::query]
+pub fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    icrc7::tokens(None, prev, take)
+}
+
+#[ic_cdk::query]
+pub fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    icrc7::tokens(Some(&account), prev, take)
+}
+
+/// Transfers tokens of the caller. Transferring a token to this canister
+/// burns it and withdraws its inscription to the bitcoin address given as the
+/// memo.
+#[ic_cdk::update]
+pub async fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    icrc7::transfer(ic_cdk::caller(), caller_derivation_path(), args).await
+}
+
+#[ic_cdk::query]
+pub fn icrc10_supported_standards() -> Vec<StandardRecord> {
+    icrc7::supported_standards()
+}
+
+#[ic_cdk::query]
+pub fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(Nat::from(icrc37::MAX_APPROVALS))
+}
+
+#[ic_cdk::query]
+pub fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(Nat::from(icrc37::MAX_REVOKE_APPROVALS))
+}
+
+#[ic_cdk::update]
+pub fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    icrc37::approve_tokens(ic_cdk::caller(), args)
+}
+
+#[ic_cdk::update]
+pub fn icrc37_approve_collection(
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<ApproveCollectionResult>> {
+    icrc37::approve_collection(ic_cdk::caller(), args)
+}
+
+#[ic_cdk::update]
+pub fn icrc37_revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<RevokeTokenApprovalResponse>> {
+    icrc37::revoke_token_approvals(ic_cdk::caller(), args)
+}
+
+#[ic_cdk::update]
+pub fn icrc37_revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    icrc37::revoke_collection_approvals(ic_cdk::caller(), args)
+}
+
+#[ic_cdk::query]
+pub fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    icrc37::is_approved(args)
+}
+
+#[ic_cdk::query]
+pub fn icrc37_get_token_approvals(
+    token_id: Nat,
+    prev: Option<TokenApproval>,
+    take: Option<Nat>,
+) -> Vec<TokenApproval> {
+    icrc37::get_token_approvals(token_id, prev, take)
+}
+
+#[ic_cdk::query]
+pub fn icrc37_get_collection_approvals(
+    owner: Account,
+    prev: Option<ApprovalInfo>,
+    take: Option<Nat>,
+) -> Vec<ApprovalInfo> {
+    icrc37::get_collection_approvals(owner, prev, take)
+}
+
+#[ic_cdk::update]
+pub fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+    icrc37::transfer_from(ic_cdk::caller(), args)
+}
+
+/// Decodes the inscription envelopes and the runestone of a raw transaction.
+#[ic_cdk::query]
+pub fn decode_transaction(raw_tx: Vec<u8>) -> Result<DecodedTransaction, String> {
+    decoder::decode(&raw_tx)
+}
+
+/// Inscribes a BRC-20 deploy operation of a ticker that hasn't been deployed
+/// through this canister before.
+#[ic_cdk::update]
+pub async fn brc20_deploy(
+    tick: String,
+    max: String,
+    lim: Option<String>,
+    dec: Option<u8>,
+    recipient: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<(String, String, u64), String> {
+    let payload = brc20::deploy(&tick, &max, lim.as_deref(), dec)?;
+    let txids = inscribe_brc20(payload, recipient, fee_rate).await?;
+    brc20::record_deployment(&tick, &max, lim.as_deref(), dec);
+    Ok(txids)
+}
+
+/// Inscribes a BRC-20 mint operation for a token deployed through this canister.
+#[ic_cdk::update]
+pub async fn brc20_mint(
+    tick: String,
+    amt: String,
+    recipient: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<(String, String, u64), String> {
+    let payload = brc20::mint(&tick, &amt)?;
+    inscribe_brc20(payload, recipient, fee_rate).await
+}
+
+/// Inscribes a BRC-20 transfer operation.
+#[ic_cdk::update]
+pub async fn brc20_transfer(
+    tick: String,
+    amt: String,
+    recipient: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<(String, String, u64), String> {
+    let payload = brc20::transfer(&tick, &amt)?;
+    inscribe_brc20(payload, recipient, fee_rate).await
+}
+
+async fn inscribe_brc20(
+    payload: String,
+    recipient: Option<String>,
+    fee_rate: Option<u64>,
+) -> Result<(String, String, u64), String> {
+    let network = NETWORK.with(|n| n.get());
+    bitcoin_wallet::inscribe(
+        network,
+        caller_derivation_path(),
+        ic_cdk::caller(),
+        Some(brc20::CONTENT_TYPE.as_bytes().to_vec()),
+        Some(payload.into_bytes()),
+        recipient,
+        fee_rate.unwrap_or(10),
+        None,
+        None,
+        vec![],
+        None,
+        None,
     )
     .await
 }
 
 #[ic_cdk::update]
 pub async fn get_p2pkh_address() -> String {
-    let derivation_path = DERIVATION_PATH.with(|d| d.clone());
+    let derivation_path = caller_derivation_path();
     let key_name = KEY_NAME.with(|kn| kn.borrow().to_string());
     let network = NETWORK.with(|n| n.get());
     bitcoin_wallet::get_p2pkh_address(network, key_name, derivation_path).await
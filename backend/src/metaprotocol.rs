@@ -0,0 +1,199 @@
+use crate::inscription::Inscription;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// A metaprotocol that inscriptions can declare through the metaprotocol tag.
+///
+/// Implementations check that an inscription conforms to the protocol before
+/// it is inscribed, so that malformed payloads are rejected by the canister
+/// rather than by an indexer after the fact.
+pub trait Metaprotocol {
+    /// The identifier of the metaprotocol, e.g. `cbrc-20`.
+    fn name(&self) -> &str;
+
+    /// Returns an error describing why the inscription does not conform to the
+    /// metaprotocol.
+    fn validate(
+        &self,
+        content_type: Option<&[u8]>,
+        body: Option<&[u8]>,
+        metadata: Option<&[u8]>,
+    ) -> Result<(), String>;
+
+    /// Records what an inscription that has been sent means for the
+    /// metaprotocol, e.g. the parameters of a deployed token. Does nothing by
+    /// default.
+    fn record(&self, _: Option<&[u8]>, _: Option<&[u8]>, _: Option<&[u8]>) {}
+}
+
+thread_local! {
+    // The registered metaprotocols, keyed by name.
+    static METAPROTOCOLS: RefCell<BTreeMap<String, Box<dyn Metaprotocol>>> =
+        RefCell::new(BTreeMap::new());
+}
+
+/// Registers a metaprotocol, replacing any metaprotocol with the same name.
+pub fn register(metaprotocol: Box<dyn Metaprotocol>) {
+    METAPROTOCOLS.with(|m| {
+        m.borrow_mut()
+            .insert(metaprotocol.name().to_string(), metaprotocol)
+    });
+}
+
+/// Validates the inscription against the metaprotocol matching `identifier`.
+///
+/// Metaprotocol identifiers may carry protocol specific parameters after a
+/// colon (e.g. `cbrc-20:mint:TICK=1`), so the validator is looked up by the
+/// part before the first colon.
+pub fn validate(identifier: &str, inscription: &Inscription) -> Result<(), String> {
+    let name = identifier.split(':').next().unwrap_or_default();
+
+    METAPROTOCOLS.with(|m| {
+        let metaprotocols = m.borrow();
+        let metaprotocol = metaprotocols
+            .get(name)
+            .ok_or_else(|| format!("Unknown metaprotocol: {}", name))?;
+
+        metaprotocol.validate(
+            inscription.content_type.as_deref(),
+            inscription.body.as_deref(),
+            inscription.metadata.as_deref(),
+        )
+    })
+}
+
+/// Lets the metaprotocol declared by an inscription that has been sent record
+/// it. Inscriptions without a known metaprotocol are ignored.
+pub fn record(inscription: &Inscription) {
+    let Some(identifier) = inscription
+        .metaprotocol
+        .as_deref()
+        .and_then(|identifier| std::str::from_utf8(identifier).ok())
+    else {
+        return;
+    };
+    let name = identifier.split(':').next().unwrap_or_default();
+
+    METAPROTOCOLS.with(|m| {
+        if let Some(metaprotocol) = m.borrow().get(name) {
+            metaprotocol.record(
+                inscription.content_type.as_deref(),
+                inscription.body.as_deref(),
+                inscription.metadata.as_deref(),
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Accepts inscriptions whose body is `ok`.
+    struct Test;
+
+    impl Metaprotocol for Test {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn validate(
+            &self,
+            _content_type: Option<&[u8]>,
+            body: Option<&[u8]>,
+            _metadata: Option<&[u8]>,
+        ) -> Result<(), String> {
+            match body {
+                Some(b"ok") => Ok(()),
+                _ => Err("not ok".to_string()),
+            }
+        }
+    }
+
+    fn inscription(body: &str) -> Inscription {
+        Inscription::new(None, Some(body.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn validates_by_name() {
+        register(Box::new(Test));
+
+        assert_eq!(validate("test", &inscription("ok")), Ok(()));
+        assert_eq!(
+            validate("test", &inscription("nok")),
+            Err("not ok".to_string())
+        );
+    }
+
+    #[test]
+    fn parameters_after_the_name_are_ignored() {
+        register(Box::new(Test));
+
+        assert_eq!(validate("test:mint:TICK=1", &inscription("ok")), Ok(()));
+        assert_eq!(
+            validate("test:", &inscription("nok")),
+            Err("not ok".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_metaprotocols_are_rejected() {
+        register(Box::new(Test));
+
+        assert_eq!(
+            validate("other", &inscription("ok")),
+            Err("Unknown metaprotocol: other".to_string())
+        );
+        assert_eq!(
+            validate("other:test", &inscription("ok")),
+            Err("Unknown metaprotocol: other".to_string())
+        );
+        assert_eq!(
+            validate("tes", &inscription("ok")),
+            Err("Unknown metaprotocol: tes".to_string())
+        );
+    }
+
+    #[test]
+    fn empty_metaprotocols_are_rejected() {
+        register(Box::new(Test));
+
+        assert_eq!(
+            validate("", &inscription("ok")),
+            Err("Unknown metaprotocol: ".to_string())
+        );
+        assert_eq!(
+            validate(":test", &inscription("ok")),
+            Err("Unknown metaprotocol: ".to_string())
+        );
+    }
+
+    #[test]
+    fn registering_replaces_metaprotocols_of_the_same_name() {
+        struct Strict;
+
+        impl Metaprotocol for Strict {
+            fn name(&self) -> &str {
+                "test"
+            }
+
+            fn validate(
+                &self,
+                _content_type: Option<&[u8]>,
+                _body: Option<&[u8]>,
+                _metadata: Option<&[u8]>,
+            ) -> Result<(), String> {
+                Err("strict".to_string())
+            }
+        }
+
+        register(Box::new(Test));
+        register(Box::new(Strict));
+
+        assert_eq!(
+            validate("test", &inscription("ok")),
+            Err("strict".to_string())
+        );
+    }
+}
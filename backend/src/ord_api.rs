@@ -0,0 +1,156 @@
+use crate::inscription_id::InscriptionId;
+use crate::runes::RuneId;
+use crate::sat_point::SatPoint;
+use bitcoin::{OutPoint, Txid};
+use candid::Deserialize;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use serde::de::DeserializeOwned;
+
+use std::str::FromStr;
+
+// The fee for an HTTP outcall with a response of at most
+// `MAX_RESPONSE_BYTES` bytes.
+const HTTP_REQUEST_COST_CYCLES: u128 = 1_000_000_000;
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+// The part of ord's JSON representation of an output that we need. ord
+// reports `null` inscriptions for outputs it hasn't indexed inscriptions of.
+#[derive(Deserialize)]
+struct Output {
+    #[serde(default)]
+    inscriptions: Option<Vec<String>>,
+    #[serde(default)]
+    spent: bool,
+}
+
+// The part of ord's JSON representation of an inscription that we need.
+#[derive(Deserialize)]
+struct Inscription {
+    satpoint: String,
+}
+
+/// Returns the inscriptions that the ord indexer at `ord_url` reports for
+/// the given output.
+///
+/// Relies on ord's `/output/<outpoint>` JSON endpoint and an HTTP outcall.
+/// See https://docs.ordinals.com/guides/api.html
+pub async fn get_output_inscriptions(
+    ord_url: &str,
+    outpoint: OutPoint,
+) -> Result<Vec<InscriptionId>, String> {
+    let output: Output = get_json(ord_url, &format!("output/{}", outpoint)).await?;
+    if output.spent {
+        return Err(format!("Output {} has been spent", outpoint));
+    }
+
+    output
+        .inscriptions
+        .unwrap_or_default()
+        .iter()
+        .map(|inscription_id| InscriptionId::from_str(inscription_id))
+        .collect()
+}
+
+/// Returns the sat that carries an inscription according to the ord indexer
+/// at `ord_url`.
+///
+/// Relies on ord's `/inscription/<inscription_id>` JSON endpoint.
+pub async fn get_inscription_sat_point(
+    ord_url: &str,
+    inscription_id: InscriptionId,
+) -> Result<SatPoint, String> {
+    let inscription: Inscription =
+        get_json(ord_url, &format!("inscription/{}", inscription_id)).await?;
+
+    SatPoint::from_str(&inscription.satpoint)
+}
+
+// The part of ord's JSON representation of a rune that we need.
+#[derive(Deserialize)]
+struct RuneResponse {
+    id: String,
+    entry: RuneEntry,
+    mintable: bool,
+}
+
+#[derive(Deserialize)]
+struct RuneEntry {
+    etching: String,
+    terms: Option<RuneTerms>,
+}
+
+#[derive(Deserialize)]
+struct RuneTerms {
+    amount: Option<u128>,
+}
+
+/// A rune as reported by the ord indexer.
+pub struct Rune {
+    pub id: RuneId,
+    /// The transaction that etched the rune.
+    pub etching: Txid,
+    /// The amount each mint yields, if the rune has mint terms.
+    pub amount: Option<u128>,
+    /// Whether the rune can be minted in the next block.
+    pub mintable: bool,
+}
+
+/// Returns the rune with the given name or ID according to the ord indexer
+/// at `ord_url`.
+///
+/// Relies on ord's `/rune/<rune>` JSON endpoint.
+pub async fn get_rune(ord_url: &str, rune: &str) -> Result<Rune, String> {
+    let response: RuneResponse = get_json(ord_url, &format!("rune/{}", rune)).await?;
+
+    Ok(Rune {
+        id: RuneId::from_str(&response.id)?,
+        etching: Txid::from_str(&response.entry.etching).map_err(|e| e.to_string())?,
+        amount: response.entry.terms.and_then(|terms| terms.amount),
+        mintable: response.mintable,
+    })
+}
+
+// Fetches and parses the JSON representation of an ord resource.
+async fn get_json<T: DeserializeOwned>(ord_url: &str, path: &str) -> Result<T, String> {
+    let request = CanisterHttpRequestArgument {
+        url: format!("{}/{}", ord_url.trim_end_matches('/'), path),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader {
+            name: "Accept".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_ord_response".to_string(),
+            vec![],
+        )),
+    };
+
+    let (response,) = http_request(request, HTTP_REQUEST_COST_CYCLES)
+        .await
+        .map_err(|(code, msg)| format!("Failed to query the ord indexer: {:?} {}", code, msg))?;
+
+    if response.status != 200u16 {
+        return Err(format!(
+            "The ord indexer responded with status {}",
+            response.status
+        ));
+    }
+
+    serde_json::from_slice(&response.body)
+        .map_err(|e| format!("Invalid response from the ord indexer: {}", e))
+}
+
+/// Strips the headers from a response of the ord indexer, so that all
+/// replicas agree on the response.
+pub fn transform(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
@@ -0,0 +1,110 @@
+use crate::types::{PendingPremine, RuneUtxo};
+use crate::{Memory, MEMORY_MANAGER, PENDING_PREMINES_MEMORY_ID, RUNE_LEDGER_MEMORY_ID};
+use bitcoin::OutPoint;
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+
+thread_local! {
+    // The rune balances of the wallets' outputs, keyed by outpoint.
+    //
+    // Only runes moved by the canister itself are recorded, so outputs that
+    // received runes from elsewhere are not protected from being spent as
+    // plain bitcoin.
+    static RUNE_LEDGER: RefCell<StableBTreeMap<String, RuneUtxo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RUNE_LEDGER_MEMORY_ID)),
+        ));
+
+    // The premines of etchings that haven't been recorded yet, keyed by the ID
+    // of the job etching the rune.
+    static PENDING_PREMINES: RefCell<StableBTreeMap<u64, PendingPremine, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(PENDING_PREMINES_MEMORY_ID)),
+        ));
+}
+
+pub fn insert(outpoint: OutPoint, rune_utxo: RuneUtxo) {
+    RUNE_LEDGER.with(|l| l.borrow_mut().insert(outpoint.to_string(), rune_utxo));
+}
+
+pub fn remove(outpoint: OutPoint) {
+    RUNE_LEDGER.with(|l| l.borrow_mut().remove(&outpoint.to_string()));
+}
+
+/// Returns whether the output holds runes and must not be spent as plain bitcoin.
+pub fn contains(outpoint: OutPoint) -> bool {
+    RUNE_LEDGER.with(|l| l.borrow().contains_key(&outpoint.to_string()))
+}
+
+/// Returns the total balance of a rune over the outputs of a wallet.
+pub fn balance(address: &str, rune_id: &str) -> u128 {
+    RUNE_LEDGER.with(|l| {
+        l.borrow()
+            .iter()
+            .filter(|(_, rune_utxo)| rune_utxo.address == address)
+            .flat_map(|(_, rune_utxo)| rune_utxo.balances)
+            .filter(|balance| balance.rune_id == rune_id)
+            .map(|balance| balance.amount)
+            .sum()
+    })
+}
+
+/// Selects outputs of a wallet holding at least `amount` of a rune.
+pub fn select(
+    address: &str,
+    rune_id: &str,
+    amount: u128,
+) -> Result<Vec<(OutPoint, RuneUtxo)>, String> {
+    let mut selected = vec![];
+    let mut total = 0u128;
+
+    RUNE_LEDGER.with(|l| {
+        for (outpoint, rune_utxo) in l.borrow().iter() {
+            if total >= amount {
+                break;
+            }
+
+            if rune_utxo.address != address {
+                continue;
+            }
+
+            let held = rune_utxo
+                .balances
+                .iter()
+                .filter(|balance| balance.rune_id == rune_id)
+                .map(|balance| balance.amount)
+                .sum::<u128>();
+            if held == 0 {
+                continue;
+            }
+
+            total += held;
+            selected.push((
+                outpoint.parse().expect("outpoint should be valid"),
+                rune_utxo,
+            ));
+        }
+    });
+
+    if total < amount {
+        return Err(format!(
+            "Insufficient balance of rune {}: {} available, {} required",
+            rune_id, total, amount
+        ));
+    }
+
+    Ok(selected)
+}
+
+pub fn insert_pending_premine(job_id: u64, pending_premine: PendingPremine) {
+    PENDING_PREMINES.with(|p| p.borrow_mut().insert(job_id, pending_premine));
+}
+
+pub fn pending_premines() -> Vec<(u64, PendingPremine)> {
+    PENDING_PREMINES.with(|p| p.borrow().iter().collect())
+}
+
+pub fn remove_pending_premine(job_id: u64) {
+    PENDING_PREMINES.with(|p| p.borrow_mut().remove(&job_id));
+}
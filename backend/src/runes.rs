@@ -0,0 +1,1183 @@
+// Adapted from https://github.com/ordinals/ord/tree/master/crates/ordinals/src/runestone.rs and
+// the other rune types of https://github.com/ordinals/ord/tree/master/crates/ordinals/src
+
+use crate::inscription::MAX_SCRIPT_ELEMENT_SIZE;
+use bitcoin::{
+    blockdata::{
+        opcodes,
+        script::{self, Instruction, ScriptBuf},
+    },
+    Network, Transaction,
+};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+/// The opcode following `OP_RETURN` that marks a runestone output.
+pub const MAGIC_NUMBER: opcodes::Opcode = opcodes::all::OP_PUSHNUM_13;
+
+/// The number of confirmations the commit transaction of an etching needs
+/// before the etching can be revealed.
+pub const COMMIT_CONFIRMATIONS: u32 = 6;
+
+/// The maximum size of a runestone output script that is still relayed.
+pub const MAX_RUNESTONE_SIZE: usize = 82;
+
+/// The maximum divisibility of a rune.
+pub const MAX_DIVISIBILITY: u8 = 38;
+
+/// The maximum number of spacers, one between each pair of letters.
+const MAX_SPACERS: u32 = 0b00000111_11111111_11111111_11111111;
+
+/// Rune names from this value onwards are reserved.
+const RESERVED: u128 = 6402364363415443603228541259936211926;
+
+/// The number of blocks between two halvings of the block subsidy.
+const SUBSIDY_HALVING_INTERVAL: u32 = 210_000;
+
+/// The length of the shortest rune names that can be etched before the
+/// shorter names start to unlock.
+const UNLOCKED_LENGTH: u32 = 12;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Tag {
+    Body = 0,
+    Flags = 2,
+    Rune = 4,
+    Premine = 6,
+    Cap = 8,
+    Amount = 10,
+    HeightStart = 12,
+    HeightEnd = 14,
+    OffsetStart = 16,
+    OffsetEnd = 18,
+    Mint = 20,
+    Pointer = 22,
+    Divisibility = 1,
+    Spacers = 3,
+    Symbol = 5,
+}
+
+impl Tag {
+    fn encode<const N: usize>(self, values: [u128; N], payload: &mut Vec<u8>) {
+        for value in values {
+            encode_varint(self as u128, payload);
+            encode_varint(value, payload);
+        }
+    }
+
+    fn encode_option<T: Into<u128>>(self, value: Option<T>, payload: &mut Vec<u8>) {
+        if let Some(value) = value {
+            self.encode([value.into()], payload)
+        }
+    }
+
+    // Removes the first N values of the tag from the fields if `with` accepts them.
+    fn take<const N: usize, T>(
+        self,
+        fields: &mut HashMap<u128, VecDeque<u128>>,
+        with: impl Fn([u128; N]) -> Option<T>,
+    ) -> Option<T> {
+        let field = fields.get_mut(&(self as u128))?;
+
+        let mut values: [u128; N] = [0; N];
+
+        for (i, v) in values.iter_mut().enumerate() {
+            *v = *field.get(i)?;
+        }
+
+        let value = with(values)?;
+
+        field.drain(0..N);
+
+        if field.is_empty() {
+            fields.remove(&(self as u128));
+        }
+
+        Some(value)
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Flag {
+    Etching = 0,
+    Terms = 1,
+    Turbo = 2,
+}
+
+impl Flag {
+    fn mask(self) -> u128 {
+        1 << self as u128
+    }
+
+    // Clears the flag and returns whether it was set.
+    fn take(self, flags: &mut u128) -> bool {
+        let mask = self.mask();
+        let set = *flags & mask != 0;
+        *flags &= !mask;
+        set
+    }
+}
+
+/// The reason a runestone is a cenotaph.
+#[derive(Debug, PartialEq, Copy, Clone, Eq)]
+pub enum Flaw {
+    EdictOutput,
+    EdictRuneId,
+    InvalidScript,
+    Opcode,
+    SupplyOverflow,
+    TrailingIntegers,
+    TruncatedField,
+    UnrecognizedEvenTag,
+    UnrecognizedFlag,
+    Varint,
+}
+
+impl fmt::Display for Flaw {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::EdictOutput => write!(f, "edict output greater than transaction output count"),
+            Self::EdictRuneId => write!(f, "invalid rune ID in edict"),
+            Self::InvalidScript => write!(f, "invalid script in OP_RETURN"),
+            Self::Opcode => write!(f, "non-pushdata opcode in OP_RETURN"),
+            Self::SupplyOverflow => write!(f, "supply overflows u128"),
+            Self::TrailingIntegers => write!(f, "trailing integers in body"),
+            Self::TruncatedField => write!(f, "field with missing value"),
+            Self::UnrecognizedEvenTag => write!(f, "unrecognized even tag"),
+            Self::UnrecognizedFlag => write!(f, "unrecognized field"),
+            Self::Varint => write!(f, "invalid varint"),
+        }
+    }
+}
+
+/// A malformed runestone. Cenotaphs burn the runes of the transaction's
+/// inputs, while their etching and mint still count.
+#[derive(Debug, PartialEq, Copy, Clone, Eq)]
+pub struct Cenotaph {
+    pub etching: Option<Rune>,
+    pub flaw: Flaw,
+    pub mint: Option<RuneId>,
+}
+
+/// The outcome of deciphering the runestone output of a transaction.
+#[derive(Debug, PartialEq, Clone, Eq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Artifact {
+    Cenotaph(Cenotaph),
+    Runestone(Runestone),
+}
+
+/// Appends the LEB128 encoding of `n` to `v`.
+pub fn encode_varint(mut n: u128, v: &mut Vec<u8>) {
+    while n >> 7 > 0 {
+        v.push(n.to_le_bytes()[0] | 0b1000_0000);
+        n >>= 7;
+    }
+    v.push(n.to_le_bytes()[0]);
+}
+
+/// Decodes a LEB128 integer from the start of `buffer`, returning it together
+/// with the number of bytes read.
+pub fn decode_varint(buffer: &[u8]) -> Option<(u128, usize)> {
+    let mut n = 0u128;
+
+    for (i, &byte) in buffer.iter().enumerate() {
+        // Longer encodings would overflow a u128.
+        if i > 18 {
+            return None;
+        }
+
+        let value = u128::from(byte) & 0b0111_1111;
+
+        if i == 18 && value & 0b0111_1100 != 0 {
+            return None;
+        }
+
+        n |= value << (7 * i);
+
+        if byte & 0b1000_0000 == 0 {
+            return Some((n, i + 1));
+        }
+    }
+
+    None
+}
+
+/// A rune name, encoded as a modified base-26 integer.
+#[derive(Debug, PartialEq, Copy, Clone, Eq, PartialOrd, Ord)]
+pub struct Rune(pub u128);
+
+impl Rune {
+    /// The bytes committed to in the tapscript of the etching's reveal input.
+    pub fn commitment(self) -> Vec<u8> {
+        let bytes = self.0.to_le_bytes();
+
+        let mut end = bytes.len();
+
+        while end > 0 && bytes[end - 1] == 0 {
+            end -= 1;
+        }
+
+        bytes[..end].into()
+    }
+
+    pub fn is_reserved(self) -> bool {
+        self.0 >= RESERVED
+    }
+
+    /// Returns the smallest rune that can be etched in the block at `height`.
+    ///
+    /// Only names of at least 13 letters can be etched before runes activate.
+    /// Afterwards the minimum decreases steadily, unlocking one letter less
+    /// every `SUBSIDY_HALVING_INTERVAL / 12` blocks, until all names are
+    /// unlocked after a full halving interval.
+    pub fn minimum_at_height(network: Network, height: u32) -> Self {
+        let interval = SUBSIDY_HALVING_INTERVAL / UNLOCKED_LENGTH;
+        let offset = height.saturating_add(1);
+        let start = first_rune_height(network);
+        let end = start + SUBSIDY_HALVING_INTERVAL;
+
+        if offset < start {
+            return Self(steps(UNLOCKED_LENGTH));
+        }
+        if offset >= end {
+            return Self(0);
+        }
+
+        let progress = offset - start;
+        let length = UNLOCKED_LENGTH - progress / interval;
+        let start = steps(length);
+        let end = steps(length - 1);
+        let remainder = u128::from(progress % interval);
+
+        Self(start - (start - end) * remainder / u128::from(interval))
+    }
+}
+
+// Returns the height at which runes activate.
+fn first_rune_height(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => SUBSIDY_HALVING_INTERVAL * 4,
+        Network::Testnet => SUBSIDY_HALVING_INTERVAL * 12,
+        _ => 0,
+    }
+}
+
+// Returns the first rune whose name has `length + 1` letters.
+fn steps(length: u32) -> u128 {
+    (1..=length).map(|exponent| 26u128.pow(exponent)).sum()
+}
+
+impl fmt::Display for Rune {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut n = self.0;
+        if n == u128::MAX {
+            return write!(f, "BCGDENLQRQWDSLRUGSNLBTMFIJAV");
+        }
+
+        n += 1;
+        let mut symbol = String::new();
+        while n > 0 {
+            symbol.push(
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZ"
+                    .chars()
+                    .nth(((n - 1) % 26) as usize)
+                    .unwrap(),
+            );
+            n = (n - 1) / 26;
+        }
+
+        for c in symbol.chars().rev() {
+            write!(f, "{c}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Rune {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut x = 0u128;
+        for (i, c) in s.chars().enumerate() {
+            if i > 0 {
+                x = x.checked_add(1).ok_or("Rune name out of range")?;
+            }
+            x = x.checked_mul(26).ok_or("Rune name out of range")?;
+            match c {
+                'A'..='Z' => {
+                    x = x
+                        .checked_add(c as u128 - 'A' as u128)
+                        .ok_or("Rune name out of range")?;
+                }
+                _ => return Err(format!("Invalid character in rune name: {}", c)),
+            }
+        }
+        Ok(Rune(x))
+    }
+}
+
+/// A rune name with spacers between its letters, e.g. `UNCOMMON•GOODS`.
+#[derive(Debug, PartialEq, Copy, Clone, Eq)]
+pub struct SpacedRune {
+    pub rune: Rune,
+    pub spacers: u32,
+}
+
+impl fmt::Display for SpacedRune {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rune = self.rune.to_string();
+
+        for (i, c) in rune.chars().enumerate() {
+            write!(f, "{c}")?;
+
+            if i < rune.len() - 1 && self.spacers & 1 << i != 0 {
+                write!(f, "•")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for SpacedRune {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rune = String::new();
+        let mut spacers = 0u32;
+
+        for c in s.chars() {
+            match c {
+                'A'..='Z' => rune.push(c),
+                '.' | '•' => {
+                    let flag = 1 << rune.len().checked_sub(1).ok_or("Leading spacer")?;
+                    if spacers & flag != 0 {
+                        return Err("Double spacer".to_string());
+                    }
+                    spacers |= flag;
+                }
+                _ => return Err(format!("Invalid character in rune name: {}", c)),
+            }
+        }
+
+        if 32 - spacers.leading_zeros() >= rune.len() as u32 {
+            return Err("Trailing spacer".to_string());
+        }
+
+        Ok(SpacedRune {
+            rune: rune.parse()?,
+            spacers,
+        })
+    }
+}
+
+/// Identifies a rune by the block and the transaction index of its etching.
+#[derive(Debug, PartialEq, Copy, Clone, Eq, PartialOrd, Ord, Default)]
+pub struct RuneId {
+    pub block: u64,
+    pub tx: u32,
+}
+
+impl RuneId {
+    fn delta(self, next: RuneId) -> Option<(u128, u128)> {
+        let block = next.block.checked_sub(self.block)?;
+
+        let tx = if block == 0 {
+            next.tx.checked_sub(self.tx)?
+        } else {
+            next.tx
+        };
+
+        Some((block.into(), tx.into()))
+    }
+
+    // Applies a delta as encoded in the edicts of a runestone.
+    fn next(self, block: u128, tx: u128) -> Option<RuneId> {
+        let block = u64::try_from(block).ok()?;
+        let tx = u32::try_from(tx).ok()?;
+
+        let next = RuneId {
+            block: self.block.checked_add(block)?,
+            tx: if block == 0 {
+                self.tx.checked_add(tx)?
+            } else {
+                tx
+            },
+        };
+
+        // Only the reserved ID 0:0 has a zero block height.
+        if next.block == 0 && next.tx > 0 {
+            return None;
+        }
+
+        Some(next)
+    }
+}
+
+impl fmt::Display for RuneId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.block, self.tx)
+    }
+}
+
+impl FromStr for RuneId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (block, tx) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid rune ID: {}", s))?;
+
+        Ok(Self {
+            block: block
+                .parse()
+                .map_err(|e| format!("Invalid rune ID {}: {}", s, e))?,
+            tx: tx
+                .parse()
+                .map_err(|e| format!("Invalid rune ID {}: {}", s, e))?,
+        })
+    }
+}
+
+/// The open mint terms of a rune.
+#[derive(Default, Debug, PartialEq, Copy, Clone, Eq)]
+pub struct Terms {
+    pub amount: Option<u128>,
+    pub cap: Option<u128>,
+    pub height: (Option<u64>, Option<u64>),
+    pub offset: (Option<u64>, Option<u64>),
+}
+
+#[derive(Default, Debug, PartialEq, Copy, Clone, Eq)]
+pub struct Etching {
+    pub divisibility: Option<u8>,
+    pub premine: Option<u128>,
+    pub rune: Option<Rune>,
+    pub spacers: Option<u32>,
+    pub symbol: Option<char>,
+    pub terms: Option<Terms>,
+    pub turbo: bool,
+}
+
+impl Etching {
+    /// Checks that the etching is valid and its supply does not overflow.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(rune) = self.rune {
+            if rune.is_reserved() {
+                return Err(format!("Rune {} is reserved", rune));
+            }
+        }
+
+        if self.divisibility.unwrap_or_default() > MAX_DIVISIBILITY {
+            return Err(format!("Divisibility must not exceed {}", MAX_DIVISIBILITY));
+        }
+
+        if self.spacers.unwrap_or_default() > MAX_SPACERS {
+            return Err("Too many spacers".to_string());
+        }
+
+        self.supply()
+            .ok_or_else(|| "Supply of the rune overflows".to_string())?;
+
+        Ok(())
+    }
+
+    /// Returns the maximum supply of the rune, or `None` if it overflows.
+    pub fn supply(&self) -> Option<u128> {
+        let premine = self.premine.unwrap_or_default();
+        let cap = self.terms.and_then(|terms| terms.cap).unwrap_or_default();
+        let amount = self
+            .terms
+            .and_then(|terms| terms.amount)
+            .unwrap_or_default();
+        premine.checked_add(cap.checked_mul(amount)?)
+    }
+}
+
+/// Assigns `amount` of rune `id` to output `output`.
+#[derive(Debug, PartialEq, Copy, Clone, Eq)]
+pub struct Edict {
+    pub id: RuneId,
+    pub amount: u128,
+    pub output: u32,
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Eq)]
+pub struct Runestone {
+    pub edicts: Vec<Edict>,
+    pub etching: Option<Etching>,
+    pub mint: Option<RuneId>,
+    pub pointer: Option<u32>,
+}
+
+impl Runestone {
+    /// Deciphers the first runestone output of a transaction.
+    pub fn decipher(transaction: &Transaction) -> Option<Artifact> {
+        let payload = match Self::payload(transaction)? {
+            Ok(payload) => payload,
+            Err(flaw) => {
+                return Some(Artifact::Cenotaph(Cenotaph {
+                    etching: None,
+                    flaw,
+                    mint: None,
+                }))
+            }
+        };
+
+        let mut integers = Vec::new();
+        let mut i = 0;
+        while i < payload.len() {
+            let Some((integer, length)) = decode_varint(&payload[i..]) else {
+                return Some(Artifact::Cenotaph(Cenotaph {
+                    etching: None,
+                    flaw: Flaw::Varint,
+                    mint: None,
+                }));
+            };
+            integers.push(integer);
+            i += length;
+        }
+
+        let mut flaw = None;
+        let mut edicts = Vec::new();
+        let mut fields = HashMap::<u128, VecDeque<u128>>::new();
+
+        for i in (0..integers.len()).step_by(2) {
+            let tag = integers[i];
+
+            if tag == Tag::Body as u128 {
+                let mut id = RuneId::default();
+                for chunk in integers[i + 1..].chunks(4) {
+                    if chunk.len() != 4 {
+                        flaw.get_or_insert(Flaw::TrailingIntegers);
+                        break;
+                    }
+
+                    let Some(next) = id.next(chunk[0], chunk[1]) else {
+                        flaw.get_or_insert(Flaw::EdictRuneId);
+                        break;
+                    };
+
+                    // An output equal to the output count splits the runes
+                    // between all non-OP_RETURN outputs.
+                    let Some(output) = u32::try_from(chunk[3])
+                        .ok()
+                        .filter(|output| *output as usize <= transaction.output.len())
+                    else {
+                        flaw.get_or_insert(Flaw::EdictOutput);
+                        break;
+                    };
+
+                    id = next;
+                    edicts.push(Edict {
+                        id,
+                        amount: chunk[2],
+                        output,
+                    });
+                }
+                break;
+            }
+
+            let Some(&value) = integers.get(i + 1) else {
+                flaw.get_or_insert(Flaw::TruncatedField);
+                break;
+            };
+
+            fields.entry(tag).or_default().push_back(value);
+        }
+
+        let mut flags = Tag::Flags
+            .take(&mut fields, |[flags]| Some(flags))
+            .unwrap_or_default();
+
+        let etching = Flag::Etching.take(&mut flags).then(|| Etching {
+            divisibility: Tag::Divisibility.take(&mut fields, |[divisibility]| {
+                let divisibility = u8::try_from(divisibility).ok()?;
+                (divisibility <= MAX_DIVISIBILITY).then_some(divisibility)
+            }),
+            premine: Tag::Premine.take(&mut fields, |[premine]| Some(premine)),
+            rune: Tag::Rune.take(&mut fields, |[rune]| Some(Rune(rune))),
+            spacers: Tag::Spacers.take(&mut fields, |[spacers]| {
+                let spacers = u32::try_from(spacers).ok()?;
+                (spacers <= MAX_SPACERS).then_some(spacers)
+            }),
+            symbol: Tag::Symbol.take(&mut fields, |[symbol]| {
+                char::from_u32(u32::try_from(symbol).ok()?)
+            }),
+            terms: Flag::Terms.take(&mut flags).then(|| Terms {
+                cap: Tag::Cap.take(&mut fields, |[cap]| Some(cap)),
+                height: (
+                    Tag::HeightStart.take(&mut fields, |[start]| u64::try_from(start).ok()),
+                    Tag::HeightEnd.take(&mut fields, |[end]| u64::try_from(end).ok()),
+                ),
+                amount: Tag::Amount.take(&mut fields, |[amount]| Some(amount)),
+                offset: (
+                    Tag::OffsetStart.take(&mut fields, |[start]| u64::try_from(start).ok()),
+                    Tag::OffsetEnd.take(&mut fields, |[end]| u64::try_from(end).ok()),
+                ),
+            }),
+            turbo: Flag::Turbo.take(&mut flags),
+        });
+
+        let mint = Tag::Mint.take(&mut fields, |[block, tx]| {
+            Some(RuneId {
+                block: block.try_into().ok()?,
+                tx: tx.try_into().ok()?,
+            })
+        });
+
+        let pointer = Tag::Pointer.take(&mut fields, |[pointer]| {
+            let pointer = u32::try_from(pointer).ok()?;
+            ((pointer as usize) < transaction.output.len()).then_some(pointer)
+        });
+
+        if etching
+            .map(|etching| etching.supply().is_none())
+            .unwrap_or_default()
+        {
+            flaw.get_or_insert(Flaw::SupplyOverflow);
+        }
+
+        if flags != 0 {
+            flaw.get_or_insert(Flaw::UnrecognizedFlag);
+        }
+
+        if fields.keys().any(|tag| tag % 2 == 0) {
+            flaw.get_or_insert(Flaw::UnrecognizedEvenTag);
+        }
+
+        if let Some(flaw) = flaw {
+            return Some(Artifact::Cenotaph(Cenotaph {
+                etching: etching.and_then(|etching| etching.rune),
+                flaw,
+                mint,
+            }));
+        }
+
+        Some(Artifact::Runestone(Self {
+            edicts,
+            etching,
+            mint,
+            pointer,
+        }))
+    }
+
+    // Returns the concatenated data pushes of the first runestone output.
+    fn payload(transaction: &Transaction) -> Option<Result<Vec<u8>, Flaw>> {
+        for output in &transaction.output {
+            let mut instructions = output.script_pubkey.instructions();
+
+            if instructions.next() != Some(Ok(Instruction::Op(opcodes::all::OP_RETURN))) {
+                continue;
+            }
+
+            if instructions.next() != Some(Ok(Instruction::Op(MAGIC_NUMBER))) {
+                continue;
+            }
+
+            let mut payload = Vec::new();
+
+            for result in instructions {
+                match result {
+                    Ok(Instruction::PushBytes(push)) => payload.extend_from_slice(push.as_bytes()),
+                    Ok(Instruction::Op(_)) => return Some(Err(Flaw::Opcode)),
+                    Err(_) => return Some(Err(Flaw::InvalidScript)),
+                }
+            }
+
+            return Some(Ok(payload));
+        }
+
+        None
+    }
+
+    /// Returns the `OP_RETURN` output script carrying the runestone.
+    pub fn encipher(&self) -> ScriptBuf {
+        let mut payload = Vec::new();
+
+        if let Some(etching) = self.etching {
+            let mut flags = Flag::Etching.mask();
+
+            if etching.terms.is_some() {
+                flags |= Flag::Terms.mask();
+            }
+
+            if etching.turbo {
+                flags |= Flag::Turbo.mask();
+            }
+
+            Tag::Flags.encode([flags], &mut payload);
+
+            Tag::Rune.encode_option(etching.rune.map(|rune| rune.0), &mut payload);
+            Tag::Divisibility.encode_option(etching.divisibility, &mut payload);
+            Tag::Spacers.encode_option(etching.spacers, &mut payload);
+            Tag::Symbol.encode_option(etching.symbol.map(u32::from), &mut payload);
+            Tag::Premine.encode_option(etching.premine, &mut payload);
+
+            if let Some(terms) = etching.terms {
+                Tag::Amount.encode_option(terms.amount, &mut payload);
+                Tag::Cap.encode_option(terms.cap, &mut payload);
+                Tag::HeightStart.encode_option(terms.height.0, &mut payload);
+                Tag::HeightEnd.encode_option(terms.height.1, &mut payload);
+                Tag::OffsetStart.encode_option(terms.offset.0, &mut payload);
+                Tag::OffsetEnd.encode_option(terms.offset.1, &mut payload);
+            }
+        }
+
+        if let Some(RuneId { block, tx }) = self.mint {
+            Tag::Mint.encode([block.into(), tx.into()], &mut payload);
+        }
+
+        Tag::Pointer.encode_option(self.pointer, &mut payload);
+
+        if !self.edicts.is_empty() {
+            encode_varint(Tag::Body as u128, &mut payload);
+
+            let mut edicts = self.edicts.clone();
+            edicts.sort_by_key(|edict| edict.id);
+
+            let mut previous = RuneId::default();
+            for edict in edicts {
+                let (block, tx) = previous.delta(edict.id).unwrap();
+                encode_varint(block, &mut payload);
+                encode_varint(tx, &mut payload);
+                encode_varint(edict.amount, &mut payload);
+                encode_varint(edict.output.into(), &mut payload);
+                previous = edict.id;
+            }
+        }
+
+        let mut builder = script::Builder::new()
+            .push_opcode(opcodes::all::OP_RETURN)
+            .push_opcode(MAGIC_NUMBER);
+
+        for chunk in payload.chunks(MAX_SCRIPT_ELEMENT_SIZE) {
+            let push: &script::PushBytes = chunk.try_into().unwrap();
+            builder = builder.push_slice(push);
+        }
+
+        builder.into_script()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{absolute::LockTime, transaction::Version, Amount, TxOut};
+
+    // Builds a transaction with the given output scripts followed by
+    // `outputs` plain outputs.
+    fn transaction(scripts: Vec<ScriptBuf>, outputs: usize) -> Transaction {
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: scripts
+                .into_iter()
+                .chain((0..outputs).map(|_| ScriptBuf::new()))
+                .map(|script_pubkey| TxOut {
+                    script_pubkey,
+                    value: Amount::ZERO,
+                })
+                .collect(),
+        }
+    }
+
+    fn payload(integers: &[u128]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for integer in integers {
+            encode_varint(*integer, &mut payload);
+        }
+        payload
+    }
+
+    fn decipher(integers: &[u128]) -> Artifact {
+        let payload = payload(integers);
+        let payload: &script::PushBytes = payload.as_slice().try_into().unwrap();
+
+        Runestone::decipher(&transaction(
+            vec![script::Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .push_opcode(MAGIC_NUMBER)
+                .push_slice(payload)
+                .into_script()],
+            0,
+        ))
+        .unwrap()
+    }
+
+    fn cenotaph(flaw: Flaw) -> Artifact {
+        Artifact::Cenotaph(Cenotaph {
+            etching: None,
+            flaw,
+            mint: None,
+        })
+    }
+
+    #[track_caller]
+    fn round_trip(runestone: Runestone) {
+        let transaction = transaction(vec![runestone.encipher()], 4);
+        assert_eq!(
+            Runestone::decipher(&transaction),
+            Some(Artifact::Runestone(runestone))
+        );
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for n in [0, 1, 127, 128, 255, 16384, u64::MAX.into(), u128::MAX] {
+            let mut encoded = Vec::new();
+            encode_varint(n, &mut encoded);
+            assert_eq!(decode_varint(&encoded), Some((n, encoded.len())));
+        }
+
+        for i in 0..128 {
+            let n = 1 << i;
+            let mut encoded = Vec::new();
+            encode_varint(n, &mut encoded);
+            assert_eq!(decode_varint(&encoded), Some((n, encoded.len())));
+        }
+    }
+
+    #[test]
+    fn truncated_varints_are_rejected() {
+        assert_eq!(decode_varint(&[]), None);
+        assert_eq!(decode_varint(&[128]), None);
+        assert_eq!(decode_varint(&[255, 255]), None);
+    }
+
+    #[test]
+    fn varints_may_not_be_longer_than_19_bytes() {
+        const VALID: [u8; 19] = [
+            128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128,
+            128, 0,
+        ];
+        const INVALID: [u8; 20] = [
+            128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128,
+            128, 128, 0,
+        ];
+
+        assert_eq!(decode_varint(&VALID), Some((0, 19)));
+        assert_eq!(decode_varint(&INVALID), None);
+    }
+
+    #[test]
+    fn varints_may_not_overflow_u128() {
+        let varint = |last: u8| {
+            let mut buffer = [128; 19];
+            buffer[18] = last;
+            decode_varint(&buffer)
+        };
+
+        assert_eq!(varint(64), None);
+        assert_eq!(varint(32), None);
+        assert_eq!(varint(16), None);
+        assert_eq!(varint(8), None);
+        assert_eq!(varint(4), None);
+        assert_eq!(varint(2), Some((1 << 127, 19)));
+        assert_eq!(varint(3), Some((3 << 126, 19)));
+    }
+
+    #[test]
+    fn round_trip_empty_runestone() {
+        round_trip(Runestone::default());
+    }
+
+    #[test]
+    fn round_trip_etching() {
+        round_trip(Runestone {
+            etching: Some(Etching {
+                divisibility: Some(MAX_DIVISIBILITY),
+                premine: Some(8),
+                rune: Some(Rune(9)),
+                spacers: Some(MAX_SPACERS),
+                symbol: Some('$'),
+                terms: Some(Terms {
+                    amount: Some(4),
+                    cap: Some(5),
+                    height: (Some(10), Some(11)),
+                    offset: (Some(12), Some(13)),
+                }),
+                turbo: true,
+            }),
+            ..Default::default()
+        });
+
+        round_trip(Runestone {
+            etching: Some(Etching::default()),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn round_trip_mint_pointer_and_edicts() {
+        round_trip(Runestone {
+            edicts: vec![
+                Edict {
+                    id: RuneId { block: 1, tx: 2 },
+                    amount: 3,
+                    output: 1,
+                },
+                Edict {
+                    id: RuneId { block: 1, tx: 5 },
+                    amount: u128::MAX,
+                    output: 4,
+                },
+                Edict {
+                    id: RuneId { block: 7, tx: 0 },
+                    amount: 0,
+                    output: 0,
+                },
+            ],
+            etching: None,
+            mint: Some(RuneId {
+                block: 840_000,
+                tx: 1,
+            }),
+            pointer: Some(2),
+        });
+    }
+
+    #[test]
+    fn edicts_are_sorted_when_enciphered() {
+        let first = Edict {
+            id: RuneId { block: 1, tx: 0 },
+            amount: 1,
+            output: 0,
+        };
+        let second = Edict {
+            id: RuneId { block: 2, tx: 0 },
+            amount: 2,
+            output: 0,
+        };
+        let transaction = transaction(
+            vec![Runestone {
+                edicts: vec![second, first],
+                ..Default::default()
+            }
+            .encipher()],
+            1,
+        );
+
+        assert_eq!(
+            Runestone::decipher(&transaction),
+            Some(Artifact::Runestone(Runestone {
+                edicts: vec![first, second],
+                ..Default::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn outputs_without_magic_number_are_ignored() {
+        let transaction = transaction(
+            vec![script::Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .into_script()],
+            0,
+        );
+
+        assert_eq!(Runestone::decipher(&transaction), None);
+    }
+
+    #[test]
+    fn non_push_opcodes_are_a_cenotaph() {
+        let transaction = transaction(
+            vec![script::Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .push_opcode(MAGIC_NUMBER)
+                .push_opcode(opcodes::all::OP_VERIFY)
+                .into_script()],
+            0,
+        );
+
+        assert_eq!(
+            Runestone::decipher(&transaction),
+            Some(cenotaph(Flaw::Opcode))
+        );
+    }
+
+    #[test]
+    fn invalid_scripts_are_a_cenotaph() {
+        let transaction = transaction(vec![ScriptBuf::from_bytes(vec![0x6a, 0x5d, 0x04])], 0);
+
+        assert_eq!(
+            Runestone::decipher(&transaction),
+            Some(cenotaph(Flaw::InvalidScript))
+        );
+    }
+
+    #[test]
+    fn invalid_varints_are_a_cenotaph() {
+        let transaction = transaction(
+            vec![script::Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .push_opcode(MAGIC_NUMBER)
+                .push_slice([128])
+                .into_script()],
+            0,
+        );
+
+        assert_eq!(
+            Runestone::decipher(&transaction),
+            Some(cenotaph(Flaw::Varint))
+        );
+    }
+
+    #[test]
+    fn truncated_fields_are_a_cenotaph() {
+        assert_eq!(
+            decipher(&[Tag::Flags as u128]),
+            cenotaph(Flaw::TruncatedField)
+        );
+    }
+
+    #[test]
+    fn unrecognized_even_tags_are_a_cenotaph() {
+        assert_eq!(decipher(&[126, 0]), cenotaph(Flaw::UnrecognizedEvenTag));
+    }
+
+    #[test]
+    fn unrecognized_odd_tags_are_ignored() {
+        assert_eq!(
+            decipher(&[127, 100]),
+            Artifact::Runestone(Runestone::default())
+        );
+    }
+
+    #[test]
+    fn unrecognized_flags_are_a_cenotaph() {
+        assert_eq!(
+            decipher(&[Tag::Flags as u128, 1 << 127]),
+            cenotaph(Flaw::UnrecognizedFlag)
+        );
+    }
+
+    #[test]
+    fn trailing_integers_are_a_cenotaph() {
+        assert_eq!(
+            decipher(&[Tag::Body as u128, 1, 1, 2, 0, 5]),
+            cenotaph(Flaw::TrailingIntegers)
+        );
+    }
+
+    #[test]
+    fn edict_outputs_beyond_the_outputs_are_a_cenotaph() {
+        assert_eq!(
+            decipher(&[Tag::Body as u128, 1, 1, 2, 2]),
+            cenotaph(Flaw::EdictOutput)
+        );
+    }
+
+    #[test]
+    fn edict_rune_ids_with_zero_block_are_a_cenotaph() {
+        assert_eq!(
+            decipher(&[Tag::Body as u128, 0, 1, 2, 0]),
+            cenotaph(Flaw::EdictRuneId)
+        );
+    }
+
+    #[test]
+    fn edict_rune_ids_overflowing_the_block_are_a_cenotaph() {
+        assert_eq!(
+            decipher(&[Tag::Body as u128, 1, 0, 0, 0, u64::MAX.into(), 0, 0, 0]),
+            cenotaph(Flaw::EdictRuneId)
+        );
+    }
+
+    #[test]
+    fn supply_overflow_is_a_cenotaph() {
+        assert_eq!(
+            decipher(&[
+                Tag::Flags as u128,
+                Flag::Etching.mask() | Flag::Terms.mask(),
+                Tag::Premine as u128,
+                1,
+                Tag::Cap as u128,
+                u128::MAX,
+                Tag::Amount as u128,
+                1,
+            ]),
+            cenotaph(Flaw::SupplyOverflow)
+        );
+    }
+
+    #[test]
+    fn cenotaphs_keep_etched_rune_and_mint() {
+        assert_eq!(
+            decipher(&[
+                Tag::Flags as u128,
+                Flag::Etching.mask(),
+                Tag::Rune as u128,
+                4,
+                Tag::Mint as u128,
+                1,
+                Tag::Mint as u128,
+                2,
+                126,
+                0,
+            ]),
+            Artifact::Cenotaph(Cenotaph {
+                etching: Some(Rune(4)),
+                flaw: Flaw::UnrecognizedEvenTag,
+                mint: Some(RuneId { block: 1, tx: 2 }),
+            })
+        );
+    }
+
+    #[test]
+    fn first_flaw_is_reported() {
+        assert_eq!(
+            decipher(&[Tag::Body as u128, 1, 1, 2, 0, 5, 126]),
+            cenotaph(Flaw::TrailingIntegers)
+        );
+    }
+
+    #[test]
+    fn minimum_at_height() {
+        #[track_caller]
+        fn case(height: u32, minimum: &str) {
+            assert_eq!(
+                Rune::minimum_at_height(Network::Bitcoin, height).to_string(),
+                minimum
+            );
+        }
+
+        const START: u32 = SUBSIDY_HALVING_INTERVAL * 4;
+        const END: u32 = START + SUBSIDY_HALVING_INTERVAL;
+        const INTERVAL: u32 = SUBSIDY_HALVING_INTERVAL / 12;
+
+        case(0, "AAAAAAAAAAAAA");
+        case(START / 2, "AAAAAAAAAAAAA");
+        case(START, "ZZYZXBRKWXVA");
+        case(START + 1, "ZZXZUDIVTVQA");
+        case(END - 1, "A");
+        case(END, "A");
+        case(END + 1, "A");
+        case(u32::MAX, "A");
+
+        case(START + INTERVAL - 1, "AAAAAAAAAAAA");
+        case(START + INTERVAL, "ZZYZXBRKWXV");
+        case(START + INTERVAL + 1, "ZZXZUDIVTVQ");
+        case(START + INTERVAL * 11 - 1, "AA");
+        case(START + INTERVAL * 12 - 1, "A");
+    }
+
+    #[test]
+    fn minimum_at_height_on_regtest() {
+        assert_eq!(
+            Rune::minimum_at_height(Network::Regtest, 0).to_string(),
+            "ZZYZXBRKWXVA"
+        );
+        assert_eq!(
+            Rune::minimum_at_height(Network::Regtest, SUBSIDY_HALVING_INTERVAL).to_string(),
+            "A"
+        );
+    }
+}
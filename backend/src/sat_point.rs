@@ -0,0 +1,152 @@
+// Adapted from https://github.com/ordinals/ord/blob/master/src/sat_point.rs
+
+use bitcoin::OutPoint;
+
+use std::{fmt, str::FromStr};
+
+/// A sat identified by the output holding it and its offset within that output.
+#[derive(Debug, PartialEq, Copy, Clone, Eq)]
+pub struct SatPoint {
+    pub outpoint: OutPoint,
+    pub offset: u64,
+}
+
+impl fmt::Display for SatPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.outpoint, self.offset)
+    }
+}
+
+impl FromStr for SatPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (outpoint, offset) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Invalid sat point: {}", s))?;
+
+        Ok(Self {
+            outpoint: outpoint
+                .parse()
+                .map_err(|e| format!("Invalid sat point {}: {}", s, e))?,
+            offset: offset
+                .parse()
+                .map_err(|e| format!("Invalid sat point {}: {}", s, e))?,
+        })
+    }
+}
+
+/// Follows a sat through a transaction.
+///
+/// Sats are assigned to the outputs in the order of the inputs, so the sat at
+/// `offset` across all inputs ends up at the same offset across all outputs.
+/// Returns the output and the offset within it, or `None` if the sat is spent
+/// as fee.
+pub fn flow(input_values: &[u64], output_values: &[u64], offset: u64) -> Option<(usize, u64)> {
+    if offset >= input_values.iter().sum::<u64>() {
+        return None;
+    }
+
+    let mut start = 0;
+    for (vout, value) in output_values.iter().enumerate() {
+        if offset < start + value {
+            return Some((vout, offset - start));
+        }
+        start += value;
+    }
+
+    None
+}
+
+/// Returns the offset across all inputs of the sat that an inscription lands
+/// on. Pointers beyond the outputs are ignored, like ord does, and the
+/// inscription lands on the first sat of the input revealing it, which starts
+/// at `input_offset`.
+pub fn inscription_offset(pointer: Option<u64>, input_offset: u64, output_values: &[u64]) -> u64 {
+    pointer
+        .filter(|pointer| *pointer < output_values.iter().sum::<u64>())
+        .unwrap_or(input_offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TXID: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+
+    #[test]
+    fn from_str() {
+        let sat_point = SatPoint::from_str(&format!("{}:1:5", TXID)).unwrap();
+
+        assert_eq!(sat_point.outpoint.txid.to_string(), TXID);
+        assert_eq!(sat_point.outpoint.vout, 1);
+        assert_eq!(sat_point.offset, 5);
+        assert_eq!(sat_point.to_string(), format!("{}:1:5", TXID));
+    }
+
+    #[test]
+    fn from_str_rejects_invalid_sat_points() {
+        assert!(SatPoint::from_str(TXID).is_err());
+        assert!(SatPoint::from_str(&format!("{}:1", TXID)).is_err());
+        assert!(SatPoint::from_str(&format!("{}:1:x", TXID)).is_err());
+        assert!(SatPoint::from_str(&format!("{}:x:5", TXID)).is_err());
+        assert!(SatPoint::from_str(&format!("{}:1:-1", TXID)).is_err());
+    }
+
+    #[test]
+    fn flow_into_first_output() {
+        assert_eq!(flow(&[10_000], &[9_000], 0), Some((0, 0)));
+        assert_eq!(flow(&[10_000], &[9_000], 8_999), Some((0, 8_999)));
+    }
+
+    #[test]
+    fn flow_pointer_into_later_output() {
+        let output_values = [5_000, 3_000, 1_000];
+        let offset = inscription_offset(Some(8_500), 0, &output_values);
+
+        assert_eq!(flow(&[10_000], &output_values, offset), Some((2, 500)));
+        assert_eq!(flow(&[10_000], &output_values, 5_000), Some((1, 0)));
+    }
+
+    #[test]
+    fn flow_pointer_past_end_falls_back_to_first_sat() {
+        let output_values = [5_000, 3_000];
+
+        assert_eq!(inscription_offset(Some(8_000), 0, &output_values), 0);
+        assert_eq!(inscription_offset(Some(u64::MAX), 0, &output_values), 0);
+        assert_eq!(inscription_offset(None, 0, &output_values), 0);
+        assert_eq!(
+            flow(
+                &[10_000],
+                &output_values,
+                inscription_offset(Some(8_000), 0, &output_values)
+            ),
+            Some((0, 0))
+        );
+    }
+
+    #[test]
+    fn flow_into_fee() {
+        assert_eq!(flow(&[10_000], &[9_000], 9_000), None);
+        assert_eq!(flow(&[10_000], &[9_000], 10_000), None);
+        assert_eq!(flow(&[10_000], &[], 0), None);
+    }
+
+    #[test]
+    fn flow_multiple_inputs() {
+        // An inscribed UTXO followed by a commit output, as spent by a
+        // reveal transaction that reinscribes.
+        let input_values = [546, 10_000];
+        let output_values = [10_000, 300];
+
+        assert_eq!(flow(&input_values, &output_values, 0), Some((0, 0)));
+        assert_eq!(flow(&input_values, &output_values, 546), Some((0, 546)));
+        assert_eq!(flow(&input_values, &output_values, 10_200), Some((1, 200)));
+        assert_eq!(flow(&input_values, &output_values, 10_300), None);
+
+        // Without a valid pointer an inscription revealed by the second input
+        // lands on its first sat.
+        let offset = inscription_offset(Some(20_000), 546, &output_values);
+        assert_eq!(flow(&input_values, &output_values, offset), Some((0, 546)));
+    }
+}
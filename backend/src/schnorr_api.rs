@@ -33,6 +33,18 @@ struct SignWithSchnorr {
     pub message: Vec<u8>,
     pub derivation_path: Vec<Vec<u8>>,
     pub key_id: SchnorrKeyId,
+    pub aux: Option<SignWithSchnorrAux>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+enum SignWithSchnorrAux {
+    #[serde(rename = "bip341")]
+    Bip341(SignWithBip341Aux),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Debug)]
+struct SignWithBip341Aux {
+    pub merkle_root_hash: Vec<u8>,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -65,6 +77,27 @@ pub async fn sign_with_schnorr(
     key_name: String,
     derivation_path: Vec<Vec<u8>>,
     message: Vec<u8>,
+) -> Vec<u8> {
+    sign(key_name, derivation_path, message, None).await
+}
+
+/// Signs with the key at the given derivation path tweaked by the taproot
+/// merkle root, as needed to spend a taproot output through the key path.
+pub async fn sign_with_schnorr_bip341(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    message: Vec<u8>,
+    merkle_root_hash: Vec<u8>,
+) -> Vec<u8> {
+    let aux = SignWithSchnorrAux::Bip341(SignWithBip341Aux { merkle_root_hash });
+    sign(key_name, derivation_path, message, Some(aux)).await
+}
+
+async fn sign(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+    message: Vec<u8>,
+    aux: Option<SignWithSchnorrAux>,
 ) -> Vec<u8> {
     let request = SignWithSchnorr {
         message,
@@ -73,6 +106,7 @@ pub async fn sign_with_schnorr(
             name: key_name,
             algorithm: SchnorrAlgorithm::Bip340Secp256k1,
         },
+        aux,
     };
 
     let res: Result<(SignWithSchnorrReply,), _> = ic_cdk::call(
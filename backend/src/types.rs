@@ -1,4 +1,5 @@
 use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::Utxo;
 use serde::Serialize;
 
 #[derive(CandidType, Deserialize)]
@@ -7,6 +8,378 @@ pub struct SendRequest {
     pub amount_in_satoshi: u64,
 }
 
+/// A single inscription of a batch.
+#[derive(CandidType, Deserialize)]
+pub struct InscriptionRequest {
+    pub content_type: String,
+    pub body: String,
+    pub metaprotocol: Option<String>,
+    pub recipient: Option<String>,
+}
+
+/// An inscription job consisting of a commit transaction and the reveal
+/// transactions spending its outputs.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub commit_txid: String,
+    pub reveals: Vec<Reveal>,
+    /// The transaction spending the change output of the commit transaction
+    /// to accelerate it, if any.
+    pub child_txid: Option<String>,
+}
+
+/// A reveal transaction of a job.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Reveal {
+    pub txid: String,
+    pub status: RevealStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RevealStatus {
+    Pending,
+    Sent,
+    Failed(String),
+    /// The job was cancelled by the transaction with the given ID.
+    Cancelled(String),
+}
+
+/// A signed reveal transaction that is sent once its commit output has
+/// enough confirmations.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingReveal {
+    pub commit_address: String,
+    pub min_confirmations: u32,
+    pub reveal_tx: Vec<u8>,
+}
+
+/// A signed reveal transaction of a job that failed to be sent and is sent
+/// again periodically.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct FailedReveal {
+    pub reveal_tx: Vec<u8>,
+    /// The number of times sending the transaction has been retried.
+    pub attempts: u32,
+}
+
+/// The commit transaction of a job together with what is needed to replace it
+/// with one paying a higher fee.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobCommit {
+    pub derivation_path: Vec<Vec<u8>>,
+    pub commit_tx: Vec<u8>,
+    /// The values of the outputs spent by the commit transaction.
+    pub input_values: Vec<u64>,
+    /// The reveal transactions of the job, each spending the commit output at
+    /// its index.
+    pub reveals: Vec<RevealTemplate>,
+    /// The transaction accelerating the commit transaction, if any.
+    pub child_tx: Option<Vec<u8>>,
+}
+
+/// What is needed to sign a reveal transaction again once the commit
+/// transaction it spends has changed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevealTemplate {
+    pub reveal_script: Vec<u8>,
+    pub control_block: Vec<u8>,
+    /// The consensus encoding of the reveal outputs.
+    pub outputs: Vec<u8>,
+    pub fee: u64,
+    /// The outputs of the wallet spent ahead of the commit output, with their
+    /// values, if any.
+    pub inputs: Option<Vec<(String, u64)>>,
+    /// The output of the commit transaction spent by the reveal transaction,
+    /// if it isn't the output at the index of the reveal within its job.
+    pub commit_vout: Option<u32>,
+}
+
+/// A signed transaction sent by the canister.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Broadcast {
+    pub transaction: Vec<u8>,
+    /// The principal whose call sent the transaction, which is the canister
+    /// itself for the transactions sent by timers.
+    pub sender: Principal,
+    /// The number of times the transaction has been sent again.
+    pub attempts: u32,
+    /// Whether the transaction has been seen confirmed.
+    pub confirmed: bool,
+}
+
+/// A UTXO of one of the canister's wallets.
+///
+/// The UTXO is either confirmed or the change output of a transaction sent by
+/// the canister that hasn't confirmed yet, in which case its height is the tip
+/// height at which the transaction was sent.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CachedUtxo {
+    pub address: String,
+    pub utxo: Utxo,
+    pub unconfirmed: bool,
+    /// The tip height at which the UTXO was reserved for a transaction, if
+    /// that transaction hasn't confirmed yet.
+    pub reserved_at: Option<u32>,
+    /// The ID of the sent transaction spending the UTXO, if any.
+    pub spent_by: Option<String>,
+}
+
+/// A transaction sent by the canister that hasn't been seen confirmed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UnconfirmedTransaction {
+    /// The IDs of the unconfirmed transactions it spends outputs of, directly
+    /// or indirectly.
+    pub ancestors: Vec<String>,
+    /// The number of unconfirmed transactions spending its outputs, directly
+    /// or indirectly.
+    pub descendants: u32,
+    /// The tip height at which it was sent.
+    pub sent_at: u32,
+}
+
+/// The chain tip at which the UTXOs of an address were fetched.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UtxoTip {
+    pub height: u32,
+    pub block_hash: Vec<u8>,
+}
+
+/// An inscription that its owner funds by paying `amount` sats to the commit
+/// address.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PreparedInscription {
+    pub id: u64,
+    pub commit_address: String,
+    pub amount: u64,
+    /// The time in nanoseconds since the epoch after which payments to the
+    /// commit address are no longer revealed.
+    pub expires_at: u64,
+    /// The reveal transaction, once the commit address has been funded.
+    pub reveal: Option<Reveal>,
+}
+
+/// A prepared inscription together with what is needed to sign its reveal
+/// transaction once the commit address has been funded.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PreparedReveal {
+    pub inscription: PreparedInscription,
+    pub owner: Principal,
+    pub derivation_path: Vec<Vec<u8>>,
+    pub reveal_script: Vec<u8>,
+    pub control_block: Vec<u8>,
+    /// The consensus encoding of the reveal outputs.
+    pub reveal_outputs: Vec<u8>,
+    pub fee: u64,
+    pub refund: Option<RefundInfo>,
+}
+
+/// What the funder of a prepared inscription needs to reclaim the commit
+/// output through the refund leaf, once the output is `csv_blocks` blocks old.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RefundInfo {
+    pub commit_address: String,
+    pub csv_blocks: u16,
+    /// The hex-encoded refund leaf script, `<csv_blocks> OP_CSV OP_DROP
+    /// <refund key> OP_CHECKSIG`.
+    pub refund_script: String,
+    /// The hex-encoded control block of the refund leaf.
+    pub control_block: String,
+}
+
+/// The open mint terms of a rune.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RuneTerms {
+    pub amount: Option<u128>,
+    pub cap: Option<u128>,
+    pub height_start: Option<u64>,
+    pub height_end: Option<u64>,
+    pub offset_start: Option<u64>,
+    pub offset_end: Option<u64>,
+}
+
+/// A rune to etch. The name may contain `•` or `.` spacers.
+#[derive(CandidType, Deserialize)]
+pub struct EtchingRequest {
+    pub rune: String,
+    pub divisibility: Option<u8>,
+    pub symbol: Option<String>,
+    pub premine: Option<u128>,
+    pub terms: Option<RuneTerms>,
+    pub turbo: Option<bool>,
+}
+
+/// The runes held by an output of one of the canister's wallets.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RuneUtxo {
+    /// The address of the wallet holding the output.
+    pub address: String,
+    pub value: u64,
+    pub balances: Vec<RuneBalance>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RuneBalance {
+    pub rune_id: String,
+    pub amount: u128,
+}
+
+/// The premine of a rune etched to one of the canister's wallets, which is
+/// recorded in the rune ledger once the etching has confirmed and the ID of
+/// the rune is known.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingPremine {
+    pub rune: String,
+    pub address: String,
+    pub amount: u128,
+}
+
+/// The parameters of a BRC-20 token deployed through this canister, with the
+/// mint limit as a fixed point number with 18 decimals.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Brc20Deployment {
+    pub lim: u128,
+    pub dec: u8,
+}
+
+/// The inscriptions carried by an output of one of the canister's wallets.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InscribedUtxo {
+    /// The address of the wallet holding the output.
+    pub address: String,
+    pub inscriptions: Vec<InscribedSat>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InscribedSat {
+    pub inscription_id: String,
+    /// The offset of the inscribed sat within the output.
+    pub offset: u64,
+}
+
+/// An inscription held by the canister's custody wallet on behalf of a
+/// principal.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Custody {
+    pub owner: Principal,
+    /// The output holding the inscription.
+    pub outpoint: String,
+    pub value: u64,
+}
+
+/// The properties of an inscription revealed by the canister.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InscriptionInfo {
+    pub content_type: Option<String>,
+    pub metadata: Option<Vec<u8>>,
+}
+
+/// The inscriptions and runestone found in a raw transaction.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DecodedTransaction {
+    pub inscriptions: Vec<DecodedInscription>,
+    pub runestone: Option<DecodedRunestone>,
+}
+
+/// An inscription envelope of a transaction input. The flags mirror the
+/// curses ord applies to the envelope.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DecodedInscription {
+    pub input: u32,
+    pub offset: u32,
+    pub fields: Vec<InscriptionField>,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub metaprotocol: Option<String>,
+    pub parent: Option<String>,
+    pub delegate: Option<String>,
+    pub pointer: Option<u64>,
+    pub rune: Option<String>,
+    pub body_length: Option<u64>,
+    /// The hex encoded SHA-256 hash of the body.
+    pub body_hash: Option<String>,
+    pub duplicate_field: bool,
+    pub incomplete_field: bool,
+    pub unrecognized_even_field: bool,
+    pub pushnum: bool,
+    pub stutter: bool,
+}
+
+/// A tag and value pair of an inscription envelope.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InscriptionField {
+    pub tag: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// A runestone, or a cenotaph if `flaw` is set. The etching of a cenotaph
+/// only carries the rune name.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DecodedRunestone {
+    pub flaw: Option<String>,
+    pub edicts: Vec<DecodedEdict>,
+    pub etching: Option<DecodedEtching>,
+    pub mint: Option<String>,
+    pub pointer: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DecodedEdict {
+    pub rune_id: String,
+    pub amount: u128,
+    pub output: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DecodedEtching {
+    pub rune: Option<String>,
+    pub divisibility: Option<u8>,
+    pub symbol: Option<String>,
+    pub premine: Option<u128>,
+    pub terms: Option<RuneTerms>,
+    pub turbo: bool,
+}
+
+// Implements `Storable` for types that are stored candid-encoded in stable
+// maps.
+macro_rules! impl_candid_storable {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl ::ic_stable_structures::Storable for $t {
+                fn to_bytes(&self) -> ::std::borrow::Cow<'_, [u8]> {
+                    ::std::borrow::Cow::Owned(::candid::encode_one(self).unwrap())
+                }
+
+                fn from_bytes(bytes: ::std::borrow::Cow<[u8]>) -> Self {
+                    ::candid::decode_one(&bytes).unwrap()
+                }
+
+                const BOUND: ::ic_stable_structures::storable::Bound =
+                    ::ic_stable_structures::storable::Bound::Unbounded;
+            }
+        )*
+    };
+}
+
+pub(crate) use impl_candid_storable;
+
+impl_candid_storable!(
+    Job,
+    PendingReveal,
+    JobCommit,
+    FailedReveal,
+    Broadcast,
+    CachedUtxo,
+    UnconfirmedTransaction,
+    UtxoTip,
+    PreparedReveal,
+    Custody,
+    InscriptionInfo,
+    PendingPremine,
+    Brc20Deployment,
+    InscribedUtxo,
+    RuneUtxo,
+);
+
 #[derive(CandidType, Serialize, Deserialize, Debug)]
 pub struct ECDSAPublicKeyReply {
     pub public_key: Vec<u8>,
@@ -42,4 +415,4 @@ pub struct SignWithECDSA {
     pub message_hash: Vec<u8>,
     pub derivation_path: Vec<Vec<u8>>,
     pub key_id: EcdsaKeyId,
-}
\ No newline at end of file
+}
@@ -0,0 +1,645 @@
+use crate::bitcoin_api;
+use crate::bitcoin_wallet::to_outpoint;
+use crate::types::{CachedUtxo, UnconfirmedTransaction, UtxoTip};
+use crate::{
+    Memory, MEMORY_MANAGER, UNCONFIRMED_TRANSACTIONS_MEMORY_ID, UTXO_CACHE_MEMORY_ID,
+    UTXO_TIPS_MEMORY_ID,
+};
+use bitcoin::{hashes::Hash, Address, OutPoint, Transaction, Txid};
+use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Outpoint, Utxo};
+use ic_stable_structures::StableBTreeMap;
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+/// The number of blocks after which a reserved UTXO that is still unspent,
+/// e.g. because the transaction spending it was dropped, can be spent again.
+/// Unconfirmed transactions and their change are forgotten after the same
+/// number of blocks.
+pub const RESERVATION_EXPIRY_BLOCKS: u32 = 144;
+
+/// Bitcoin Core's default limit on the number of unconfirmed ancestors and on
+/// the number of unconfirmed descendants of a transaction in the mempool, each
+/// counting the transaction itself.
+pub const MEMPOOL_CHAIN_LIMIT: usize = 25;
+
+thread_local! {
+    // The UTXOs of the canister's wallets, keyed by outpoint.
+    static UTXOS: RefCell<StableBTreeMap<String, CachedUtxo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(UTXO_CACHE_MEMORY_ID)),
+        ));
+
+    // The chain tip at which the UTXOs of an address were last fetched, keyed
+    // by address.
+    static TIPS: RefCell<StableBTreeMap<String, UtxoTip, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(UTXO_TIPS_MEMORY_ID)),
+        ));
+
+    // The transactions sent by the canister that haven't been seen confirmed,
+    // keyed by transaction ID.
+    static UNCONFIRMED_TRANSACTIONS: RefCell<StableBTreeMap<String, UnconfirmedTransaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(UNCONFIRMED_TRANSACTIONS_MEMORY_ID)),
+        ));
+}
+
+/// Returns the UTXOs of `address` that aren't reserved, newest first. The
+/// unconfirmed change of the canister's transactions comes first.
+///
+/// The first page of UTXOs is always fetched, as it tells the current chain
+/// tip. The remaining pages are only fetched if the tip has changed since the
+/// UTXOs were last fetched.
+pub async fn get_utxos(network: BitcoinNetwork, address: String) -> Vec<Utxo> {
+    let first_page = bitcoin_api::get_utxos_page(network, address.clone(), None).await;
+    let tip = UtxoTip {
+        height: first_page.tip_height,
+        block_hash: first_page.tip_block_hash.clone(),
+    };
+
+    if TIPS.with(|t| t.borrow().get(&address)).as_ref() != Some(&tip) {
+        let response = bitcoin_api::get_next_pages(network, address.clone(), first_page).await;
+        refresh(&address, response.utxos, tip);
+    }
+
+    let mut cached_utxos: Vec<CachedUtxo> = UTXOS.with(|u| {
+        u.borrow()
+            .iter()
+            .filter(|(_, cached_utxo)| {
+                cached_utxo.address == address && cached_utxo.reserved_at.is_none()
+            })
+            .map(|(_, cached_utxo)| cached_utxo)
+            .collect()
+    });
+    cached_utxos
+        .sort_by_key(|cached_utxo| Reverse((cached_utxo.unconfirmed, cached_utxo.utxo.height)));
+    cached_utxos
+        .into_iter()
+        .map(|cached_utxo| cached_utxo.utxo)
+        .collect()
+}
+
+/// Reserves the given UTXOs for a transaction that is about to be sent.
+pub fn reserve(outpoints: &[OutPoint]) {
+    update(outpoints, |cached_utxo, tip_height| {
+        cached_utxo.reserved_at = Some(tip_height);
+    });
+}
+
+/// Records a transaction sent by the canister.
+///
+/// The UTXOs it spends stay reserved until it confirms, while its change can
+/// fund the next transaction right away. A transaction spending UTXOs that
+/// were spent by another transaction before replaces that transaction.
+pub fn mark_sent(transaction: &Transaction) {
+    let txid = transaction.txid().to_string();
+    let outpoints: Vec<OutPoint> = transaction
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .collect();
+
+    for outpoint in &outpoints {
+        if let Some(spent_by) = spent_by(outpoint).filter(|spent_by| *spent_by != txid) {
+            evict(&spent_by);
+        }
+    }
+
+    let change_output = change_output(transaction);
+    update(&outpoints, |cached_utxo, tip_height| {
+        cached_utxo.reserved_at = Some(tip_height);
+        cached_utxo.spent_by = Some(txid.clone());
+    });
+
+    let tip_height = tip_height();
+    let ancestors = ancestors(&outpoints);
+    UNCONFIRMED_TRANSACTIONS.with(|u| {
+        let mut unconfirmed_transactions = u.borrow_mut();
+        for ancestor in &ancestors {
+            if let Some(mut unconfirmed_transaction) = unconfirmed_transactions.get(ancestor) {
+                unconfirmed_transaction.descendants += 1;
+                unconfirmed_transactions.insert(ancestor.clone(), unconfirmed_transaction);
+            }
+        }
+        unconfirmed_transactions.insert(
+            txid.clone(),
+            UnconfirmedTransaction {
+                ancestors,
+                descendants: 0,
+                sent_at: tip_height,
+            },
+        );
+    });
+
+    if let Some((address, vout)) = change_output {
+        let outpoint = OutPoint {
+            txid: transaction.txid(),
+            vout,
+        };
+        let cached_utxo = CachedUtxo {
+            address,
+            utxo: Utxo {
+                outpoint: Outpoint {
+                    txid: outpoint.txid.as_byte_array().to_vec(),
+                    vout,
+                },
+                value: transaction.output[vout as usize].value.to_sat(),
+                height: tip_height,
+            },
+            unconfirmed: true,
+            reserved_at: None,
+            spent_by: None,
+        };
+        UTXOS.with(|u| u.borrow_mut().insert(outpoint.to_string(), cached_utxo));
+    }
+}
+
+/// Releases the reservations of the given UTXOs that haven't been spent by a
+/// sent transaction.
+pub fn release(outpoints: &[OutPoint]) {
+    update(outpoints, |cached_utxo, _| {
+        if cached_utxo.spent_by.is_none() {
+            cached_utxo.reserved_at = None;
+        }
+    });
+}
+
+/// Returns the ID of the sent transaction spending the given UTXO.
+pub fn spent_by(outpoint: &OutPoint) -> Option<String> {
+    UTXOS
+        .with(|u| u.borrow().get(&outpoint.to_string()))
+        .and_then(|cached_utxo| cached_utxo.spent_by)
+}
+
+/// Returns whether a transaction sent by the canister hasn't confirmed yet,
+/// i.e. it is still tracked as unconfirmed and none of its outputs has been
+/// fetched as a confirmed UTXO.
+pub fn is_unconfirmed(txid: &Txid) -> bool {
+    let txid = txid.to_string();
+    let tracked = UNCONFIRMED_TRANSACTIONS.with(|u| u.borrow().contains_key(&txid));
+    let prefix = format!("{}:", txid);
+    let confirmed_output = UTXOS.with(|u| {
+        u.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(outpoint, _)| outpoint.starts_with(&prefix))
+            .any(|(_, cached_utxo)| !cached_utxo.unconfirmed)
+    });
+
+    tracked && !confirmed_output
+}
+
+/// Returns whether a transaction sent by the canister is known not to have
+/// confirmed: it hasn't been seen confirmed, and one of the outputs it spends
+/// was still unspent when the UTXOs of its address were last fetched, either as
+/// a confirmed UTXO or as the change of a transaction that hasn't confirmed
+/// either.
+pub fn is_pending(transaction: &Transaction) -> bool {
+    is_unconfirmed(&transaction.txid())
+        && transaction.input.iter().any(|input| {
+            UTXOS
+                .with(|u| u.borrow().get(&input.previous_output.to_string()))
+                .is_some_and(|cached_utxo| {
+                    !cached_utxo.unconfirmed || is_unconfirmed(&input.previous_output.txid)
+                })
+        })
+}
+
+/// Returns whether a transaction spending the given outputs stays within the
+/// mempool chain limits, together with `children` transactions spending its
+/// outputs.
+pub fn fits_chain_limits(outpoints: &[OutPoint], children: usize) -> bool {
+    let ancestors = ancestors(outpoints);
+    let depth = if children > 0 { 2 } else { 1 };
+    if ancestors.len() + depth > MEMPOOL_CHAIN_LIMIT {
+        return false;
+    }
+
+    UNCONFIRMED_TRANSACTIONS.with(|u| {
+        let unconfirmed_transactions = u.borrow();
+        ancestors.iter().all(|ancestor| {
+            unconfirmed_transactions
+                .get(ancestor)
+                .is_none_or(|unconfirmed_transaction| {
+                    1 + unconfirmed_transaction.descendants as usize + 1 + children
+                        <= MEMPOOL_CHAIN_LIMIT
+                })
+        })
+    })
+}
+
+// Returns the unconfirmed transactions that a transaction spending the given
+// outputs descends from.
+fn ancestors(outpoints: &[OutPoint]) -> Vec<String> {
+    UNCONFIRMED_TRANSACTIONS.with(|u| {
+        let unconfirmed_transactions = u.borrow();
+        let mut ancestors = BTreeSet::new();
+        for outpoint in outpoints {
+            let txid = outpoint.txid.to_string();
+            if let Some(parent) = unconfirmed_transactions.get(&txid) {
+                ancestors.extend(
+                    parent
+                        .ancestors
+                        .into_iter()
+                        .filter(|ancestor| unconfirmed_transactions.contains_key(ancestor)),
+                );
+                ancestors.insert(txid);
+            }
+        }
+        ancestors.into_iter().collect()
+    })
+}
+
+// Returns the address and index of the change output of a transaction, i.e.
+// its last output if it pays to the address of all of its inputs, which have
+// to be cached UTXOs.
+fn change_output(transaction: &Transaction) -> Option<(String, u32)> {
+    let addresses: BTreeSet<String> = transaction
+        .input
+        .iter()
+        .map(|input| {
+            UTXOS
+                .with(|u| u.borrow().get(&input.previous_output.to_string()))
+                .map(|cached_utxo| cached_utxo.address)
+        })
+        .collect::<Option<_>>()?;
+    let mut addresses = addresses.into_iter();
+    let (Some(address), None) = (addresses.next(), addresses.next()) else {
+        return None;
+    };
+
+    let script_pubkey = Address::from_str(&address)
+        .ok()?
+        .assume_checked()
+        .script_pubkey();
+    let vout = transaction.output.len().checked_sub(1)?;
+    (transaction.output[vout].script_pubkey == script_pubkey).then_some((address, vout as u32))
+}
+
+// Forgets a transaction that has been replaced, together with its
+// descendants and their change. The UTXOs they spent are released, unless the
+// replacement spends them, which reserves them again.
+fn evict(txid: &str) {
+    let evicted: Vec<String> = UNCONFIRMED_TRANSACTIONS.with(|u| {
+        u.borrow()
+            .iter()
+            .filter(|(id, unconfirmed_transaction)| {
+                id == txid || unconfirmed_transaction.ancestors.iter().any(|a| a == txid)
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+
+    for id in &evicted {
+        let unconfirmed_transaction = UNCONFIRMED_TRANSACTIONS.with(|u| u.borrow_mut().remove(id));
+        let Some(unconfirmed_transaction) = unconfirmed_transaction else {
+            continue;
+        };
+        for ancestor in unconfirmed_transaction.ancestors {
+            UNCONFIRMED_TRANSACTIONS.with(|u| {
+                let mut unconfirmed_transactions = u.borrow_mut();
+                if let Some(mut ancestor_transaction) = unconfirmed_transactions.get(&ancestor) {
+                    ancestor_transaction.descendants =
+                        ancestor_transaction.descendants.saturating_sub(1);
+                    unconfirmed_transactions.insert(ancestor, ancestor_transaction);
+                }
+            });
+        }
+    }
+
+    UTXOS.with(|u| {
+        let mut cached_utxos = u.borrow_mut();
+        let affected: Vec<(String, CachedUtxo)> = cached_utxos
+            .iter()
+            .filter(|(outpoint, cached_utxo)| {
+                evicted.contains(&created_by(outpoint))
+                    || cached_utxo
+                        .spent_by
+                        .as_ref()
+                        .is_some_and(|spent_by| evicted.contains(spent_by))
+            })
+            .collect();
+
+        for (outpoint, mut cached_utxo) in affected {
+            if cached_utxo.unconfirmed && evicted.contains(&created_by(&outpoint)) {
+                cached_utxos.remove(&outpoint);
+            } else {
+                cached_utxo.reserved_at = None;
+                cached_utxo.spent_by = None;
+                cached_utxos.insert(outpoint, cached_utxo);
+            }
+        }
+    });
+}
+
+// Replaces the cached UTXOs of `address`. The UTXOs that are still unspent
+// keep their reservations, and the unconfirmed change that hasn't confirmed
+// yet is kept, unless they have expired.
+fn refresh(address: &str, utxos: Vec<Utxo>, tip: UtxoTip) {
+    let expired = |height: u32| height + RESERVATION_EXPIRY_BLOCKS <= tip.height;
+    let confirmed_txids: BTreeSet<String> = utxos
+        .iter()
+        .map(|utxo| to_outpoint(utxo).txid.to_string())
+        .collect();
+
+    UTXOS.with(|u| {
+        let mut cached_utxos = u.borrow_mut();
+
+        let stale_utxos: Vec<(String, CachedUtxo)> = cached_utxos
+            .iter()
+            .filter(|(_, cached_utxo)| cached_utxo.address == address)
+            .collect();
+        let mut reservations = BTreeMap::new();
+        let mut unconfirmed_utxos = vec![];
+        for (outpoint, cached_utxo) in stale_utxos {
+            cached_utxos.remove(&outpoint);
+            if let Some(height) = cached_utxo.reserved_at.filter(|height| !expired(*height)) {
+                reservations.insert(outpoint.clone(), (height, cached_utxo.spent_by.clone()));
+            }
+            if cached_utxo.unconfirmed
+                && !expired(cached_utxo.utxo.height)
+                && !confirmed_txids.contains(&created_by(&outpoint))
+            {
+                unconfirmed_utxos.push((outpoint, cached_utxo));
+            }
+        }
+
+        for utxo in utxos {
+            let outpoint = to_outpoint(&utxo).to_string();
+            let (reserved_at, spent_by) = reservations
+                .remove(&outpoint)
+                .map_or((None, None), |(height, spent_by)| (Some(height), spent_by));
+            cached_utxos.insert(
+                outpoint,
+                CachedUtxo {
+                    address: address.to_string(),
+                    utxo,
+                    unconfirmed: false,
+                    reserved_at,
+                    spent_by,
+                },
+            );
+        }
+
+        for (outpoint, cached_utxo) in unconfirmed_utxos {
+            cached_utxos.insert(outpoint, cached_utxo);
+        }
+    });
+
+    // A confirmed transaction confirms its ancestors as well.
+    UNCONFIRMED_TRANSACTIONS.with(|u| {
+        let mut unconfirmed_transactions = u.borrow_mut();
+        for txid in &confirmed_txids {
+            if let Some(unconfirmed_transaction) = unconfirmed_transactions.remove(txid) {
+                for ancestor in unconfirmed_transaction.ancestors {
+                    unconfirmed_transactions.remove(&ancestor);
+                }
+            }
+        }
+
+        let expired_txids: Vec<String> = unconfirmed_transactions
+            .iter()
+            .filter(|(_, unconfirmed_transaction)| expired(unconfirmed_transaction.sent_at))
+            .map(|(txid, _)| txid)
+            .collect();
+        for txid in expired_txids {
+            unconfirmed_transactions.remove(&txid);
+        }
+    });
+
+    TIPS.with(|t| t.borrow_mut().insert(address.to_string(), tip));
+}
+
+// Applies `f` to the cached UTXOs at the given outpoints, passing the tip
+// height at which the UTXOs of their address were last fetched.
+fn update(outpoints: &[OutPoint], mut f: impl FnMut(&mut CachedUtxo, u32)) {
+    for outpoint in outpoints {
+        let outpoint = outpoint.to_string();
+        let Some(mut cached_utxo) = UTXOS.with(|u| u.borrow().get(&outpoint)) else {
+            continue;
+        };
+
+        let tip_height = TIPS
+            .with(|t| t.borrow().get(&cached_utxo.address))
+            .map_or(0, |tip| tip.height);
+        f(&mut cached_utxo, tip_height);
+        UTXOS.with(|u| u.borrow_mut().insert(outpoint, cached_utxo));
+    }
+}
+
+/// Returns the height of the latest block that the cached UTXOs have been
+/// fetched at.
+pub fn tip_height() -> u32 {
+    TIPS.with(|t| t.borrow().iter().map(|(_, tip)| tip.height).max())
+        .unwrap_or_default()
+}
+
+// Returns the ID of the transaction that created the output at `outpoint`.
+fn created_by(outpoint: &str) -> String {
+    OutPoint::from_str(outpoint)
+        .expect("outpoint should be valid")
+        .txid
+        .to_string()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, ScriptBuf, Sequence, TxIn, TxOut, Witness};
+
+    const ADDRESS: &str = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn";
+
+    fn utxo(outpoint: OutPoint, value: u64, height: u32) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: outpoint.txid.as_byte_array().to_vec(),
+                vout: outpoint.vout,
+            },
+            value,
+            height,
+        }
+    }
+
+    // Caches UTXOs of `ADDRESS` at the given outpoints, fetched at height 100.
+    pub(crate) fn cache(outpoints: &[OutPoint]) {
+        let utxos = outpoints
+            .iter()
+            .map(|outpoint| utxo(*outpoint, 10_000, 100))
+            .collect();
+        refresh(ADDRESS, utxos, tip(100));
+    }
+
+    pub(crate) fn is_reserved(outpoint: &OutPoint) -> bool {
+        UTXOS
+            .with(|u| u.borrow().get(&outpoint.to_string()))
+            .is_some_and(|cached_utxo| cached_utxo.reserved_at.is_some())
+    }
+
+    fn tip(height: u32) -> UtxoTip {
+        UtxoTip {
+            height,
+            block_hash: vec![height as u8],
+        }
+    }
+
+    // Returns a transaction spending `outpoint` to another address, with its
+    // change going back to `ADDRESS`.
+    fn spend(outpoint: OutPoint, change: u64) -> Transaction {
+        let address = Address::from_str(ADDRESS).unwrap().assume_checked();
+        Transaction {
+            version: Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ZERO,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(1_000),
+                    script_pubkey: ScriptBuf::new(),
+                },
+                TxOut {
+                    value: Amount::from_sat(change),
+                    script_pubkey: address.script_pubkey(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn transactions_are_pending_until_they_confirm() {
+        let outpoint = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 0,
+        };
+        refresh(ADDRESS, vec![utxo(outpoint, 10_000, 100)], tip(100));
+
+        let parent = spend(outpoint, 8_000);
+        mark_sent(&parent);
+        let child = spend(OutPoint::new(parent.txid(), 1), 6_000);
+        mark_sent(&child);
+        assert!(is_pending(&parent));
+        assert!(is_pending(&child));
+
+        // The parent confirms and its change becomes a confirmed UTXO.
+        refresh(
+            ADDRESS,
+            vec![utxo(OutPoint::new(parent.txid(), 1), 8_000, 101)],
+            tip(101),
+        );
+        assert!(!is_pending(&parent));
+        assert!(is_pending(&child));
+
+        refresh(
+            ADDRESS,
+            vec![utxo(OutPoint::new(child.txid(), 1), 6_000, 102)],
+            tip(102),
+        );
+        assert!(!is_pending(&child));
+    }
+
+    #[test]
+    fn transactions_whose_inputs_are_gone_are_not_pending() {
+        let outpoint = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 0,
+        };
+        refresh(ADDRESS, vec![utxo(outpoint, 10_000, 100)], tip(100));
+
+        let transaction = spend(outpoint, 8_000);
+        mark_sent(&transaction);
+
+        // The transaction confirmed and its change has been spent since.
+        refresh(ADDRESS, vec![], tip(101));
+        assert!(!is_pending(&transaction));
+    }
+
+    #[test]
+    fn chains_are_limited() {
+        let outpoint = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 0,
+        };
+        cache(&[outpoint]);
+
+        // Builds a chain of 23 unconfirmed transactions.
+        let mut tip_outpoint = outpoint;
+        for _ in 0..23 {
+            let transaction = spend(tip_outpoint, 8_000);
+            mark_sent(&transaction);
+            tip_outpoint = OutPoint::new(transaction.txid(), 1);
+        }
+        assert!(fits_chain_limits(&[tip_outpoint], 0));
+        assert!(fits_chain_limits(&[tip_outpoint], 1));
+        assert!(!fits_chain_limits(&[tip_outpoint], 2));
+
+        let transaction = spend(tip_outpoint, 8_000);
+        mark_sent(&transaction);
+        tip_outpoint = OutPoint::new(transaction.txid(), 1);
+        assert!(fits_chain_limits(&[tip_outpoint], 0));
+        assert!(!fits_chain_limits(&[tip_outpoint], 1));
+
+        let transaction = spend(tip_outpoint, 8_000);
+        mark_sent(&transaction);
+        assert!(!fits_chain_limits(
+            &[OutPoint::new(transaction.txid(), 1)],
+            0
+        ));
+
+        // Confirmed UTXOs are always spendable.
+        assert!(fits_chain_limits(
+            &[OutPoint {
+                txid: Txid::from_byte_array([2; 32]),
+                vout: 0,
+            }],
+            MEMPOOL_CHAIN_LIMIT - 2
+        ));
+    }
+
+    #[test]
+    fn released_utxos_are_spendable_unless_sent() {
+        let sent = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 0,
+        };
+        let unsent = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 1,
+        };
+        cache(&[sent, unsent]);
+
+        reserve(&[sent, unsent]);
+        mark_sent(&spend(sent, 8_000));
+        release(&[sent, unsent]);
+
+        assert!(is_reserved(&sent));
+        assert!(!is_reserved(&unsent));
+    }
+
+    #[test]
+    fn reservations_expire() {
+        let outpoint = OutPoint {
+            txid: Txid::from_byte_array([1; 32]),
+            vout: 0,
+        };
+        let unspent = vec![utxo(outpoint, 10_000, 100)];
+        refresh(ADDRESS, unspent.clone(), tip(100));
+        reserve(&[outpoint]);
+
+        refresh(
+            ADDRESS,
+            unspent.clone(),
+            tip(100 + RESERVATION_EXPIRY_BLOCKS - 1),
+        );
+        assert!(is_reserved(&outpoint));
+
+        refresh(ADDRESS, unspent, tip(100 + RESERVATION_EXPIRY_BLOCKS));
+        assert!(!is_reserved(&outpoint));
+    }
+}
@@ -154,7 +154,7 @@ function App() {
     console.log(content);
     try {
       setIsInscribing(true);
-      const result = await backend.inscribe(type, content, [], []);
+      const result = await backend.inscribe(type, content, [], [], [], [], [], [], []);
       console.log(result);
       setTransactions(transactions);
       await generateBlock();